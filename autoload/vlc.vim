@@ -79,6 +79,10 @@ function! vlc#do_complete(res) abort
   call complete(col('.'), a:res['words'])
 endfunction
 
+function! vlc#set_server_stopped(filetype) abort
+  let s:running[a:filetype] = v:false
+endfunction
+
 function! vlc#is_server_running(filetype)
   return get(s:running, a:filetype, v:false)
 endfunction
@@ -105,12 +109,17 @@ function! vlc#run() abort
     let l:config = expand(g:vlc#config)
   endif
 
+  let l:record = v:null
+  if exists('g:vlc#record')
+    let l:record = expand(g:vlc#record)
+  endif
+
   if executable(l:binpath) != 1
     echoerr 'binary ' . l:binpath . ' not found'
     return 0
   endif
 
-  call vlc#rpc#start(l:binpath, l:config)
+  call vlc#rpc#start(l:binpath, l:config, l:record)
   let s:started = v:true
 endfunction
 
@@ -152,77 +161,54 @@ function! vlc#apply_edit(changes) abort
   catch
   endtry
 
-  for change in a:changes['changes']
-    let l:start_line = change['start']['line']
-    let l:start_col = change['start']['column']
+  call vlc#apply_changes(a:changes['changes'])
+  execute ':w'
+endfunction
 
-    let l:end_line = change['end']['line']
-    let l:end_col = change['end']['column'] - 2
+" makes the changes to the current buffer. Every change is for the text as it
+" was before any of them was made, so they are made from the last one in the
+" buffer to the first.
+function! vlc#apply_changes(changes) abort
+  let l:changes = sort(copy(a:changes), function('s:compare_changes'))
+  for l:change in reverse(l:changes)
+    call s:apply_change(l:change)
+  endfor
+endfunction
 
-    let l:first_line = getline(l:start_line)
-    if len(l:first_line) ==# l:start_col - 1
-      let l:start_line += 1
-      let l:start_col = 0
-    endif
+function! s:compare_changes(a, b) abort
+  if a:a['start']['line'] != a:b['start']['line']
+    return a:a['start']['line'] - a:b['start']['line']
+  endif
+  return a:a['start']['column'] - a:b['start']['column']
+endfunction
+
+" replaces the text between the start and end of the change, which have
+" one-based lines and columns counted in bytes.
+function! s:apply_change(change) abort
+  let l:start = a:change['start']
+  let l:end = a:change['end']
+  let l:last = min([l:end['line'], line('$')])
+
+  let l:prefix = strpart(getline(l:start['line']), 0, l:start['column'] - 1)
+  let l:suffix = l:end['line'] > line('$') ? '' : strpart(getline(l:end['line']), l:end['column'] - 1)
+  let l:text = l:prefix . join(a:change['lines'], "\n") . l:suffix
+  let l:new = split(l:text, "\n", v:true)
+  " the newline at the end of the buffer is not a line of its own in vim.
+  if l:end['line'] > line('$') && len(l:new) > 1 && l:new[-1] ==# ''
+    call remove(l:new, -1)
+  endif
 
-    " if change['end'] !=# change['start']
-      let l:command = 'normal! ' . l:start_line . 'G0' . l:start_col
-      if l:start_col > 0
-        let l:command .= 'l'
-      endif
-      let l:command .= 'v'.  l:end_line . 'G0' . l:end_col
-      if l:end_col > 0
-        let l:command .= 'l'
-      endif
-      let l:command .= 'c'
-
-      echom l:command
-      call execute(l:command)
-      execute 'normal! lha' . join(change['lines'], '\n')
-    " else
-    "   call cursor(l:start_line, l:start_col)
-    "   execute 'normal! lha' . join(change['lines'], '\n')
-    " endif
-    " call append(line('.'), change['lines'])
-    " normal! c
-
-      " echom l:start_line
-      " echom l:end_line
-      " echom json_encode(change['lines'])
-    " if l:start_line < l:end_line
-      " " if the change happens in multiple lines
-      " " delete the first line from start_col to end
-      " " normal! D
-      " " delete all lines in between first and last
-      " " not sure why 2, but it works
-      " " execute l:start_line + 1 . ',' . l:end_line . 'd'
-      " " delete all characters from the start of the last line to end_col
-      " let cnum = l:start_col
-      " while cnum < l:end_col
-      "   norma! x
-      "   let cnum += 1
-      " endwhile
-    " elseif l:start_line == l:end_line
-      " " if the change happens in a single line
-      " " delete all characters between start_col and end_col
-      " let cnum = l:start_col
-      " while cnum < l:end_col
-      "   norma! x
-      "   let cnum += 1
-      " endwhile
-    " endif
-
-    " if l:start_line < l:end_line
-    "   " if the change happens in multiple lines
-    "   " append all lines after the cursor
-    "   call append(line('.'), change['lines'])
-    " else
-    "   " if change happens in a single line
-    "   " insert the change after the cursor
-    "   execute 'normal! ha' . change['lines'][0]
-    " endif
-  endfor
-  execute ':w'
+  let l:old = l:last - l:start['line'] + 1
+  if l:old <= 0
+    call append(line('$'), l:new)
+  elseif len(l:new) < l:old
+    execute (l:start['line'] + len(l:new)) . ',' . l:last . 'delete _'
+  elseif len(l:new) > l:old
+    call append(l:last, l:new[l:old :])
+  endif
+  if l:old > 0
+    call setline(l:start['line'], l:new[: l:old - 1])
+  endif
 endfunction
 
 function! vlc#set_virtual_texts(params) abort
@@ -242,7 +228,7 @@ function! vlc#set_virtual_texts(params) abort
   endfor
 endfunction
 
-function! vlc#set_quickfix(params) abort
+function! vlc#set_quickfix(params, open) abort
   if type(a:params) !=# type([])
     echoerr 'quickfix list is not a list'
   endif
@@ -253,6 +239,12 @@ function! vlc#set_quickfix(params) abort
   endfor
 
   call setqflist(l:params)
+  if a:open
+    " cwindow moves the cursor to the quickfix window when it opens it.
+    let l:win = win_getid()
+    cwindow
+    call win_gotoid(l:win)
+  endif
 endfunction
 
 function! vlc#show_message(params) abort
@@ -359,9 +351,11 @@ function! vlc#show_fzf(items, sink)
   call fzf#run(fzf#wrap({ 'source': a:items, 'sink': function(a:sink)}))
 endfunction
 
-function! vlc#show_locations(items, sink) abort
+function! vlc#show_locations(items, open) abort
   call setloclist(0, a:items)
-  :lopen
+  if a:open
+    :lopen
+  endif
 endfunction
 
 function! vlc#selection(items, sink) abort
@@ -411,5 +405,10 @@ function! s:resolve_code_action(selection) abort
 endfunction
 
 function! vlc#trigger_completion()
+  " ncm2 triggers completion by itself.
+  if vlc#ncm2#is_registered(&filetype)
+    return
+  endif
+
   call feedkeys("\<C-x>\<C-o>", "n")
 endfunction
@@ -15,6 +15,30 @@ function! vlc#lsp#did_save() abort
   call s:send_lifecycle_event('textDocument/didSave')
 endfunction
 
+" the language client gives up on servers that take longer than the timeout
+" for textDocument/willSaveWaitUntil, this only keeps vim from hanging if the
+" language client itself doesn't answer.
+let s:will_save_timeout = 5000
+
+" lets the servers know the buffer is about to be written, and makes the edits
+" they want made to it before it is.
+function! vlc#lsp#will_save() abort
+  if &buftype !=# '' || &filetype ==# '' || expand('%') ==# ''
+    return 0
+  endif
+
+  if !vlc#is_server_running(&filetype)
+    return 0
+  endif
+
+  let l:changes = vlc#rpc#call_sync('textDocument/willSave', s:text_document(), s:will_save_timeout)
+  if type(l:changes) ==# type([]) && !empty(l:changes)
+    call vlc#apply_changes(l:changes)
+    call vlc#lsp#did_change()
+  endif
+  return 1
+endfunction
+
 function! vlc#lsp#did_change() abort
   call s:send_lifecycle_event('textDocument/didChange')
 endfunction
@@ -32,6 +56,19 @@ function! s:send_lifecycle_event(event) abort
   return 1
 endfunction
 
+" lets the language client know when g:vlc#servers changed since the last time
+" it was checked, so that running servers get their new settings.
+function! vlc#lsp#did_change_configuration() abort
+  let l:servers = string(get(g:, 'vlc#servers', {}))
+  if !exists('s:servers') || l:servers ==# s:servers
+    let s:servers = l:servers
+    return
+  endif
+
+  let s:servers = l:servers
+  call vlc#rpc#notify('workspace/didChangeConfiguration', {})
+endfunction
+
 function! vlc#lsp#exit() abort
   call vlc#rpc#notify('exit', v:null)
 endfunction
@@ -152,16 +189,16 @@ function! s:selection_range(...) abort
     let [line_end, column_end] = getpos("'>")[1:2]
 
     return { 'range': {
-          \ 'start': { 'line': line_start - 1, 'column': column_start - 1},
-          \ 'end': { 'line': line_end - 1, 'column': column_end - 1},
+          \ 'start': { 'line': line_start, 'column': column_start},
+          \ 'end': { 'line': line_end, 'column': column_end},
           \ }}
   endif
 
   let l:line = line('.')
   let l:col = col('.')
   return { 'range': {
-        \ 'start': { 'line': l:line - 1, 'column': l:col - 1},
-        \ 'end': { 'line': l:line - 1, 'column': l:col - 1},
+        \ 'start': { 'line': l:line, 'column': l:col},
+        \ 'end': { 'line': l:line, 'column': l:col},
         \ }}
 endfunction
 
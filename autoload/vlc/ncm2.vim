@@ -1,3 +1,5 @@
+let s:registered = {}
+
 function! vlc#ncm2#register(params) abort
   let l:complete_pattern = a:params['complete_pattern']
   let l:cpp = []
@@ -14,6 +16,11 @@ function! vlc#ncm2#register(params) abort
       \ 'complete_pattern': l:cpp,
       \ 'on_complete': ['vlc#ncm2#completion'],
       \ })
+  let s:registered[a:params['language_id']] = v:true
+endfunction
+
+function! vlc#ncm2#is_registered(filetype) abort
+  return get(s:registered, a:filetype, v:false)
 endfunction
 
 " ncm2 completion func
@@ -9,7 +9,7 @@ function! s:get_id() abort
   return l:id
 endfunction
 
-function! vlc#rpc#start(binpath, config) abort
+function! vlc#rpc#start(binpath, config, record) abort
     let cmd = [a:binpath]
     if a:config !=# v:null
       let cmd = add(cmd, '--config')
@@ -17,6 +17,12 @@ function! vlc#rpc#start(binpath, config) abort
       echom 'INFO: Using VLC config in: ' . a:config
     endif
 
+    if a:record !=# v:null
+      let cmd = add(cmd, '--record')
+      let cmd = add(cmd, a:record)
+      echom 'INFO: Recording VLC session to: ' . a:record
+    endif
+
   if has('nvim')
     let s:job = jobstart(cmd, {
         \ 'on_stdout': function('vlc#rpc#read'),
@@ -33,7 +39,7 @@ function! vlc#rpc#start(binpath, config) abort
       return 1
     endif
   elseif has('job')
-    let s:job = job_start([a:binpath], {
+    let s:job = job_start(cmd, {
        \ 'out_cb': function('vlc#rpc#read'),
        \ 'err_cb': function('vlc#handle_error'),
        \ })
@@ -57,11 +63,18 @@ function! vlc#rpc#hande_error_vim(job, data) abort
     return vlc#rpc#handle_error(a:job, [a:data], 'stderr')
 endfunction
 
-" TODO: needs to be separated in replySuccess and replyError
+" JSON-RPC error codes used when replying to or receiving errors from the language client.
+let s:internal_error = -32603
+let s:request_cancelled = -32800
+
 function! vlc#rpc#reply(id, params) abort
   call s:do_send('success', a:params, a:id)
 endfunction
 
+function! vlc#rpc#reply_error(id, code, message) abort
+  call s:do_send('error', { 'code': a:code, 'message': a:message }, a:id)
+endfunction
+
 function! vlc#rpc#call_with_callback(method, params, callback) abort
   let l:id = vlc#rpc#call(a:method, a:params)
   let s:callbacks[l:id] = a:callback
@@ -73,6 +86,30 @@ function! vlc#rpc#call(method, params) abort
   return l:id
 endfunction
 
+" calls the method and waits for its result for up to timeout milliseconds,
+" returning v:null if it doesn't come by then or the call fails.
+function! vlc#rpc#call_sync(method, params, timeout) abort
+  let l:result = {}
+  let l:id = vlc#rpc#call(a:method, a:params)
+  let s:callbacks[l:id] = {res -> extend(l:result, { 'value': res })}
+
+  " replies are read in job callbacks, which are only run while waiting.
+  if exists('*wait')
+    call wait(a:timeout, {-> !has_key(s:callbacks, l:id)})
+  else
+    let l:waited = 0
+    while has_key(s:callbacks, l:id) && l:waited < a:timeout
+      sleep 10m
+      let l:waited += 10
+    endwhile
+  endif
+
+  if has_key(s:callbacks, l:id)
+    call remove(s:callbacks, l:id)
+  endif
+  return get(l:result, 'value', v:null)
+endfunction
+
 function! vlc#rpc#notify(method, params) abort
   call s:do_send(a:method, a:params)
 endfunction
@@ -165,35 +202,65 @@ function! vlc#rpc#read(job, lines, event) abort
       let l:message_id = l:message['id']
     endif
 
-    if has_key(l:message, 'result') || has_key(l:message, 'error')
-      let Callback = s:callbacks[l:message_id]
-      call Callback(l:message['result'])
+    if has_key(l:message, 'error')
+      call s:handle_error(l:message_id, l:message['error'])
+      continue
+    endif
+
+    if has_key(l:message, 'result')
+      if has_key(s:callbacks, l:message_id)
+        let Callback = remove(s:callbacks, l:message_id)
+        call Callback(l:message['result'])
+      endif
       continue
     endif
 
     let l:method = l:message['method']
     let l:params = l:message['params']
-    if l:method ==# 'vlc#show_fzf'
-      return vlc#show_fzf(l:params['items'], l:params['sink'])
-    " shows the vitual texts for the current buffer
-    elseif l:method ==# 'call'
-      call vlc#eval(l:params)
-    " evaluates a command, waits for the response and replies to the server
-    elseif l:method ==# 'eval'
-      let l:res = vlc#eval(l:params)
-      if has_key(l:message, 'id')
-        call vlc#rpc#reply(l:message_id, l:res)
-      endif
-    elseif l:method ==# 'execute'
-      let l:res = vlc#execute(l:params)
-      if has_key(l:message, 'id')
-        call vlc#rpc#reply(l:message_id, l:res)
+    try
+      if l:method ==# 'vlc#show_fzf'
+        return vlc#show_fzf(l:params['items'], l:params['sink'])
+      " shows the vitual texts for the current buffer
+      elseif l:method ==# 'call'
+        call vlc#eval(l:params)
+      " evaluates a command, waits for the response and replies to the server
+      elseif l:method ==# 'eval'
+        let l:res = vlc#eval(l:params)
+        if has_key(l:message, 'id')
+          call vlc#rpc#reply(l:message_id, l:res)
+        endif
+      elseif l:method ==# 'execute'
+        let l:res = vlc#execute(l:params)
+        if has_key(l:message, 'id')
+          call vlc#rpc#reply(l:message_id, l:res)
+        endif
+      else
+        let l:result = call(l:method, l:params)
+        if l:message_id isnot v:null
+          call vlc#rpc#reply(l:message_id, l:result)
+        endif
       endif
-    else
-      let l:result = call(l:method, l:params)
+    catch
+      " the language client blocks waiting for replies, so it must hear about failures too.
       if l:message_id isnot v:null
-        call vlc#rpc#reply(l:message_id, l:result)
+        call vlc#rpc#reply_error(l:message_id, s:internal_error, v:exception)
+      else
+        echoerr v:exception
       endif
-    endif
+    endtry
   endwhile
 endfunction
+
+" errors in reply to our requests are shown to the user, except for cancelled requests since the
+" user has already been warned about them by the language client.
+function! s:handle_error(id, error) abort
+  if has_key(s:callbacks, a:id)
+    call remove(s:callbacks, a:id)
+  endif
+
+  if get(a:error, 'code', 0) ==# s:request_cancelled
+    return
+  endif
+
+  call vlc#show_message({ 'level': 1, 'message': get(a:error, 'message', 'unknown error') })
+endfunction
@@ -50,6 +50,7 @@ function! s:configure()
   augroup vlc
       autocmd!
       autocmd TextChanged   <buffer> call vlc#lsp#did_change()
+      autocmd BufWritePre   <buffer> call vlc#lsp#will_save()
       autocmd BufWritePost  <buffer> call vlc#lsp#did_save()
       autocmd BufWinLeave   <buffer> call vlc#lsp#did_close()
       autocmd VimLeavePre   <buffer> call vlc#lsp#exit()
@@ -60,6 +61,7 @@ function! s:configure()
       autocmd InsertCharPre <buffer> call vlc#trigger_completion()
   augroup END
 
+  call vlc#lsp#did_change_configuration()
   call vlc#lsp#did_open()
 endfunction
 
@@ -0,0 +1,307 @@
+// didChange notifications waiting to be sent. Changes to a document are held back for a while after
+// the first of them, so that the ones made in quick succession, e.g. while typing, go out together
+// in a single notification. Changes are always sent before anything else is sent to the server,
+// so that it never answers a request for a document it hasn't caught up with.
+
+use crate::rpc::ServerID;
+use anyhow::Result;
+use crossbeam::channel::{Receiver, Sender};
+use lsp_types::VersionedTextDocumentIdentifier;
+use lsp_types::{DidChangeTextDocumentParams, TextDocumentContentChangeEvent, Url};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// the changes to a document waiting to be sent to a server.
+#[derive(Debug)]
+struct Pending {
+    // version of the document after every change in it.
+    version: i32,
+    changes: Vec<TextDocumentContentChangeEvent>,
+    deadline: Instant,
+}
+
+/// Handle to the changes waiting to be sent to each server.
+#[derive(Debug, Clone)]
+pub struct PendingChanges {
+    pending: Arc<Mutex<HashMap<(ServerID, Url), Pending>>>,
+    // lets the thread sending the changes know that there are new ones to wait for.
+    queued: Sender<()>,
+}
+
+impl PendingChanges {
+    /// returns the pending changes along with the channel that is signaled when changes are
+    /// queued.
+    pub fn new() -> (Self, Receiver<()>) {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let changes = Self {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            queued: tx,
+        };
+
+        (changes, rx)
+    }
+
+    /// queues the change to the document to be sent to the server once the delay has passed since
+    /// the first change that is still waiting.
+    pub fn push(
+        &self,
+        id: &ServerID,
+        uri: Url,
+        version: i32,
+        change: TextDocumentContentChangeEvent,
+        delay: Duration,
+    ) {
+        let mut pending = self.pending.lock();
+        let pending = pending.entry((id.clone(), uri)).or_insert_with(|| Pending {
+            version,
+            changes: vec![],
+            deadline: Instant::now() + delay,
+        });
+        // the whole text replaces everything that came before it.
+        if change.range.is_none() {
+            pending.changes.clear();
+        }
+        pending.version = version;
+        pending.changes.push(change);
+        let _ = self.queued.send(());
+    }
+
+    /// sends every change waiting for the server with the given function.
+    pub fn flush<F>(&self, id: &ServerID, send: F) -> Result<()>
+    where
+        F: FnMut(&ServerID, DidChangeTextDocumentParams) -> Result<()>,
+    {
+        // the lock is held while sending, so that nothing gets ahead of the changes being sent.
+        let mut pending = self.pending.lock();
+        let keys: Vec<_> = pending.keys().filter(|(i, _)| i == id).cloned().collect();
+        send_all(&mut pending, keys, send)
+    }
+
+    /// sends the changes whose delay has passed with the given function, returning when the next
+    /// ones are due.
+    pub fn flush_due<F>(&self, send: F) -> Option<Instant>
+    where
+        F: FnMut(&ServerID, DidChangeTextDocumentParams) -> Result<()>,
+    {
+        let now = Instant::now();
+        let mut pending = self.pending.lock();
+        let keys: Vec<_> = pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if let Err(err) = send_all(&mut pending, keys, send) {
+            log::error!("could not send document changes: {}", err);
+        }
+
+        pending.values().map(|p| p.deadline).min()
+    }
+
+    /// drops the changes waiting for a server that is gone.
+    pub fn forget(&self, id: &ServerID) {
+        self.pending.lock().retain(|(i, _), _| i != id);
+    }
+}
+
+fn send_all<F>(
+    pending: &mut HashMap<(ServerID, Url), Pending>,
+    keys: Vec<(ServerID, Url)>,
+    mut send: F,
+) -> Result<()>
+where
+    F: FnMut(&ServerID, DidChangeTextDocumentParams) -> Result<()>,
+{
+    for (id, uri) in keys {
+        let changes = match pending.remove(&(id.clone(), uri.clone())) {
+            Some(changes) => changes,
+            None => continue,
+        };
+
+        let params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri,
+                version: changes.version,
+            },
+            content_changes: changes.changes,
+        };
+        send(&id, params)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{Position, Range};
+
+    fn server(name: &str) -> ServerID {
+        ServerID::new("rust", name, "/root")
+    }
+
+    fn uri(filename: &str) -> Url {
+        Url::from_file_path(filename).unwrap()
+    }
+
+    fn full(text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: text.into(),
+        }
+    }
+
+    fn incremental(character: u32, text: &str) -> TextDocumentContentChangeEvent {
+        let position = Position::new(0, character);
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(position, position)),
+            range_length: None,
+            text: text.into(),
+        }
+    }
+
+    // returns what would be sent for each server, sorted by server and uri.
+    fn collect_sent<F>(flush: F) -> Vec<(ServerID, DidChangeTextDocumentParams)>
+    where
+        F: FnOnce(&mut dyn FnMut(&ServerID, DidChangeTextDocumentParams) -> Result<()>),
+    {
+        let mut sent = vec![];
+        flush(&mut |id, params| {
+            sent.push((id.clone(), params));
+            Ok(())
+        });
+        sent.sort_by(|(a, x), (b, y)| {
+            (&a.name, x.text_document.uri.as_str()).cmp(&(&b.name, y.text_document.uri.as_str()))
+        });
+        sent
+    }
+
+    #[test]
+    fn test_push_coalesces_incremental() {
+        let (changes, queued) = PendingChanges::new();
+        let delay = Duration::from_secs(60);
+        changes.push(&server("a"), uri("/a.rs"), 1, incremental(0, "x"), delay);
+        changes.push(&server("a"), uri("/a.rs"), 2, incremental(1, "y"), delay);
+        assert_eq!(queued.try_iter().count(), 2);
+
+        let sent = collect_sent(|send| changes.flush(&server("a"), send).unwrap());
+        assert_eq!(sent.len(), 1);
+        let (id, params) = &sent[0];
+        assert_eq!(id, &server("a"));
+        assert_eq!(params.text_document.uri, uri("/a.rs"));
+        assert_eq!(params.text_document.version, 2);
+        assert_eq!(
+            params.content_changes,
+            vec![incremental(0, "x"), incremental(1, "y")]
+        );
+
+        // nothing is sent twice.
+        assert!(collect_sent(|send| changes.flush(&server("a"), send).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_push_full_replaces_previous_changes() {
+        let (changes, _queued) = PendingChanges::new();
+        let delay = Duration::from_secs(60);
+        changes.push(&server("a"), uri("/a.rs"), 1, incremental(0, "x"), delay);
+        changes.push(&server("a"), uri("/a.rs"), 2, full("xy"), delay);
+        changes.push(&server("a"), uri("/a.rs"), 3, incremental(2, "z"), delay);
+        changes.push(&server("b"), uri("/a.rs"), 1, full("x"), delay);
+        changes.push(&server("b"), uri("/a.rs"), 2, full("xy"), delay);
+
+        let sent = collect_sent(|send| {
+            changes.flush(&server("a"), &mut *send).unwrap();
+            changes.flush(&server("b"), send).unwrap();
+        });
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].1.text_document.version, 3);
+        assert_eq!(
+            sent[0].1.content_changes,
+            vec![full("xy"), incremental(2, "z")]
+        );
+        assert_eq!(sent[1].1.text_document.version, 2);
+        assert_eq!(sent[1].1.content_changes, vec![full("xy")]);
+    }
+
+    #[test]
+    fn test_flush_only_sends_for_the_server() {
+        let (changes, _queued) = PendingChanges::new();
+        let delay = Duration::from_secs(60);
+        changes.push(&server("a"), uri("/a.rs"), 1, full("a"), delay);
+        changes.push(&server("a"), uri("/b.rs"), 1, full("b"), delay);
+        changes.push(&server("b"), uri("/a.rs"), 1, full("a"), delay);
+
+        let sent = collect_sent(|send| changes.flush(&server("a"), send).unwrap());
+        let uris: Vec<_> = sent
+            .iter()
+            .map(|(id, params)| (id.name.as_str(), params.text_document.uri.path()))
+            .collect();
+        assert_eq!(uris, vec![("a", "/a.rs"), ("a", "/b.rs")]);
+
+        let sent = collect_sent(|send| changes.flush(&server("b"), send).unwrap());
+        assert_eq!(sent.len(), 1);
+    }
+
+    #[test]
+    fn test_flush_due() {
+        let (changes, _queued) = PendingChanges::new();
+        assert_eq!(changes.flush_due(|_, _| Ok(())), None);
+
+        let start = Instant::now();
+        let delay = Duration::from_millis(200);
+        changes.push(&server("a"), uri("/a.rs"), 1, full("a"), delay);
+        changes.push(
+            &server("b"),
+            uri("/a.rs"),
+            1,
+            full("a"),
+            Duration::from_secs(60),
+        );
+
+        // changes are not sent before their deadline, which is counted from the first of them.
+        let mut next = None;
+        let sent = collect_sent(|send| next = changes.flush_due(send));
+        assert!(sent.is_empty());
+        let next = next.unwrap();
+        assert!(next >= start + delay && next <= Instant::now() + delay);
+
+        changes.push(&server("a"), uri("/a.rs"), 2, full("ab"), delay);
+        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+        let mut after = None;
+        let sent = collect_sent(|send| after = changes.flush_due(send));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, server("a"));
+        assert_eq!(sent[0].1.text_document.version, 2);
+
+        // the changes that are still waiting are due next.
+        assert!(after.unwrap() >= start + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_forget() {
+        let (changes, _queued) = PendingChanges::new();
+        changes.push(
+            &server("a"),
+            uri("/a.rs"),
+            1,
+            full("a"),
+            Duration::from_millis(0),
+        );
+        changes.push(
+            &server("b"),
+            uri("/a.rs"),
+            1,
+            full("a"),
+            Duration::from_millis(0),
+        );
+        changes.forget(&server("a"));
+
+        let sent = collect_sent(|send| {
+            changes.flush_due(send);
+        });
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, server("b"));
+    }
+}
@@ -1,23 +1,181 @@
-use crate::rpc::RPCClient;
+use crate::rpc::{RPCClient, Transport};
+use crate::vim::{LogLevel, Message};
 use anyhow::Result;
 use jsonrpc_core::Value;
-use serde::Deserialize;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Default, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ServerConfig {
     pub name: String,
+    // command that runs the server. It can be left empty for servers using a socket transport that
+    // are already running.
+    #[serde(default)]
     pub command: String,
     #[serde(default)]
     pub args: Vec<String>,
+    // environment variables set for the server command, on top of the ones vim runs with.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // working directory for the server command, defaults to the one vim runs in.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    // file the stderr of the server command is appended to, it is discarded if not set.
+    #[serde(default)]
+    pub stderr_log: Option<String>,
     #[serde(default)]
     pub initialization_options: Option<Value>,
+    // settings handed to the server when it asks for them with workspace/configuration, and
+    // pushed to it with workspace/didChangeConfiguration.
+    #[serde(default)]
+    pub settings: Value,
     #[serde(default)]
     pub features: FeatureSet,
+    // milliseconds changes to a document are held back for, so that the ones made in quick
+    // succession are sent to the server together.
+    #[serde(default = "default_change_delay")]
+    pub change_delay: u64,
+    // number of times the server is restarted after crashing before giving up on it, zero disables
+    // restarts.
+    #[serde(default = "default_restart_attempts")]
+    pub restart_attempts: u32,
+    #[serde(default)]
+    pub transport: Transport,
+    // file names or globs marking the root of a workspace, looked for from the directory of a
+    // document upwards. The defaults for the language id are used if none are set.
+    #[serde(default)]
+    pub root_patterns: Vec<String>,
+    // whether documents outside of any workspace are handled with their directory as the root,
+    // rather than not starting the server for them.
+    #[serde(default = "default_single_file_mode")]
+    pub single_file_mode: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl ServerConfig {
+    /// returns the settings under the given dotted section, e.g. `python.analysis`, or null if
+    /// there are none. An empty section returns all of them.
+    pub fn settings_section(&self, section: &str) -> Value {
+        settings_section(&self.settings, section).unwrap_or_default()
+    }
+}
+
+fn settings_section(settings: &Value, section: &str) -> Option<Value> {
+    if section.is_empty() {
+        return Some(settings.clone());
+    }
+
+    let settings = settings.as_object()?;
+    if let Some(value) = settings.get(section) {
+        return Some(value.clone());
+    }
+
+    // keys can have dots in them too, e.g. `{"python.analysis": {...}}`.
+    section
+        .match_indices('.')
+        .find_map(|(i, _)| settings_section(settings.get(&section[..i])?, &section[i + 1..]))
+}
+
+/// reads a config file, as TOML if it has a .toml extension and as JSON otherwise.
+pub fn read_file(file: &Path) -> Result<Value> {
+    let contents = std::fs::read_to_string(file)
+        .map_err(|err| anyhow::anyhow!("could not read {}: {}", file.display(), err))?;
+    let value = match file.extension() {
+        Some(ext) if ext == "toml" => toml::from_str(&contents)?,
+        _ => serde_json::from_str(&contents)?,
+    };
+
+    match value {
+        Value::Object(_) => Ok(value),
+        _ => Err(anyhow::anyhow!(
+            "{} does not contain a table",
+            file.display()
+        )),
+    }
+}
+
+// merges the config set in vim on top of the one read from a file. Every language id, log option
+// or timeout set in vim replaces the one in the file.
+fn merge(config: &mut Value, globals: Value) {
+    let globals = match globals {
+        Value::Object(globals) => globals,
+        _ => return,
+    };
+
+    for (key, value) in globals {
+        match (config.get_mut(&key), without_nulls(value)) {
+            (_, Value::Null) => {}
+            (Some(Value::Object(config)), Value::Object(value)) => config.extend(value),
+            (_, value) => config[&key] = value,
+        }
+    }
+}
+
+// removes the fields that are null from an object, the ones that are not set in vim.
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            Value::Object(map.into_iter().filter(|(_, v)| !v.is_null()).collect())
+        }
+        value => value,
+    }
+}
+
+/// returns the server config with the overrides merged deep into it. Keys that are not part of a
+/// server config are added to unknown.
+pub fn merge_server(
+    server_config: &ServerConfig,
+    overrides: Value,
+    unknown: &mut Vec<String>,
+) -> Result<ServerConfig> {
+    let mut value = serde_json::to_value(server_config)?;
+    deep_merge(&mut value, overrides);
+
+    let mut unknown_key = |path: serde_ignored::Path| unknown.push(path.to_string());
+    let deserializer = serde_ignored::Deserializer::new(value, &mut unknown_key);
+    let mut merged: ServerConfig = serde_path_to_error::deserialize(deserializer)?;
+    // the name is what identifies the server, so it can't be overridden.
+    merged.name = server_config.name.clone();
+    Ok(merged)
+}
+
+// merges the overrides into the value, going into objects that are in both and replacing anything
+// else.
+fn deep_merge(value: &mut Value, overrides: Value) {
+    match (value, overrides) {
+        (Value::Object(value), Value::Object(overrides)) => {
+            for (key, overrides) in overrides {
+                deep_merge(value.entry(key).or_insert(Value::Null), overrides);
+            }
+        }
+        (value, overrides) => *value = overrides,
+    }
+}
+
+pub fn show_message<C: RPCClient>(vim: &C, level: LogLevel, message: String) -> Result<()> {
+    vim.notify(
+        "vlc#show_message",
+        serde_json::json!([Message { level, message }]),
+    )
+}
+
+fn default_restart_attempts() -> u32 {
+    3
+}
+
+fn default_change_delay() -> u64 {
+    100
+}
+
+fn default_single_file_mode() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct FeatureSet {
     pub code_lenses: bool,
     pub code_actions: bool,
@@ -39,14 +197,142 @@ impl Default for FeatureSet {
 #[derive(Debug, Clone, Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
-    pub servers: HashMap<String, ServerConfig>,
+    // servers configured for each language id. The order in which they are listed is the priority
+    // used to pick which server answers requests that only need a single answer.
+    #[serde(default, deserialize_with = "deserialize_servers")]
+    pub servers: HashMap<String, Vec<ServerConfig>>,
     #[serde(default)]
     pub log: Log,
     #[serde(default)]
     pub hover: Hover,
+    #[serde(default)]
+    pub completion: Completion,
+    #[serde(default)]
+    pub locations: Locations,
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
+    // features turned off for every server, on top of the ones turned off in the config of each
+    // of them.
+    #[serde(default)]
+    pub features: FeatureFlags,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub file_watcher: FileWatcher,
+    // directories, or globs matching them, holding projects whose project config can be used.
+    #[serde(default)]
+    pub trusted_projects: Vec<String>,
+    // file the config was read from, on top of the one set in vim.
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
 }
 
+// a language id can be configured with either a single server or a list of them. This is not an
+// untagged enum so that errors in the config of a server point at the field they are in. Either
+// way they are kept as a list.
+struct OneOrMany(Vec<ServerConfig>);
+
+impl<'de> Deserialize<'de> for OneOrMany {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = OneOrMany;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a server or a list of servers")
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<OneOrMany, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(OneOrMany)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<OneOrMany, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                ServerConfig::deserialize(MapAccessDeserializer::new(map))
+                    .map(|server| OneOrMany(vec![server]))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+fn deserialize_servers<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, Vec<ServerConfig>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let servers: HashMap<String, OneOrMany> = HashMap::deserialize(deserializer)?;
+    Ok(servers
+        .into_iter()
+        .map(|(language_id, OneOrMany(servers))| (language_id, servers))
+        .collect())
+}
+
+// request timeouts in milliseconds, keyed by LSP method. Methods that are not present in the map
+// use the value under the "default" key.
 #[derive(Debug, Clone, Deserialize)]
+#[serde(from = "HashMap<String, u64>")]
+pub struct Timeouts(HashMap<String, u64>);
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        let timeouts = [
+            ("default", 10_000),
+            ("initialize", 60_000),
+            ("shutdown", 5_000),
+            ("textDocument/completion", 500),
+            ("completionItem/resolve", 500),
+            ("textDocument/references", 30_000),
+            ("textDocument/implementation", 30_000),
+            ("textDocument/rename", 30_000),
+            ("workspace/executeCommand", 30_000),
+            ("textDocument/willSaveWaitUntil", 1_000),
+        ];
+
+        Timeouts(
+            timeouts
+                .iter()
+                .map(|(method, ms)| (method.to_string(), *ms))
+                .collect(),
+        )
+    }
+}
+
+// user provided timeouts are merged on top of the defaults so that overriding a single method
+// keeps the rest of them.
+impl From<HashMap<String, u64>> for Timeouts {
+    fn from(f: HashMap<String, u64>) -> Self {
+        let mut timeouts = Timeouts::default();
+        timeouts.0.extend(f);
+        timeouts
+    }
+}
+
+impl Timeouts {
+    pub fn get(&self, method: &str) -> Duration {
+        let ms = self
+            .0
+            .get(method)
+            .or_else(|| self.0.get("default"))
+            .cloned()
+            .unwrap_or(10_000);
+        Duration::from_millis(ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Completion {
     pub enabled: bool,
     pub strategy: CompletionStrategy,
@@ -123,6 +409,7 @@ pub struct FeatureFlags {
     pub did_open: bool,
     pub did_change: bool,
     pub did_save: bool,
+    pub will_save: bool,
 }
 
 impl Default for FeatureFlags {
@@ -142,6 +429,7 @@ impl Default for FeatureFlags {
             did_open: true,
             did_change: true,
             did_save: true,
+            will_save: true,
         }
     }
 }
@@ -156,14 +444,16 @@ pub struct Log {
 impl Default for Log {
     fn default() -> Self {
         Log {
-            output: shellexpand::tilde("~/.vlc/vlc.log").into(),
+            output: "/tmp/vlc.log".into(),
             level: "error".into(),
         }
     }
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Locations {
+    // whether the location list is opened when there is more than one location to jump to.
     pub auto_open: bool,
 }
 
@@ -174,7 +464,10 @@ impl Default for Locations {
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Diagnostics {
+    // whether the quickfix list is opened when there are diagnostics, and closed when there are
+    // none left.
     pub auto_open: bool,
     pub show_signs: bool,
 }
@@ -182,41 +475,576 @@ pub struct Diagnostics {
 impl Default for Diagnostics {
     fn default() -> Self {
         Diagnostics {
-            auto_open: true,
-            show_signs: false,
+            auto_open: false,
+            show_signs: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FileWatcher {
+    // names of the directories that are not watched for changes to the files servers registered
+    // watchers for, on top of version control internals.
+    pub ignored_dirs: Vec<String>,
+}
+
+impl Default for FileWatcher {
+    fn default() -> Self {
+        FileWatcher {
+            ignored_dirs: [
+                "node_modules",
+                "target",
+                "vendor",
+                "build",
+                "dist",
+                "__pycache__",
+                ".venv",
+            ]
+            .iter()
+            .map(|dir| dir.to_string())
+            .collect(),
         }
     }
 }
 
 impl Config {
-    pub fn parse<C: RPCClient>(vim: &C) -> Result<Config> {
+    /// reads the config from the given file, if any, with the one set in vim on top of it. Errors
+    /// and unknown keys in it are shown in vim.
+    pub fn parse<C: RPCClient>(vim: &C, file: Option<&Path>) -> Result<Config> {
+        let mut unknown = vec![];
+        let config = Config::load(vim, file, &mut unknown);
+        for key in unknown {
+            show_message(
+                vim,
+                LogLevel::Warning,
+                format!("unknown config key {}", key),
+            )?;
+        }
+
+        if let Err(err) = &config {
+            show_message(vim, LogLevel::Error, format!("invalid config: {:#}", err))?;
+        }
+
+        config
+    }
+
+    fn load<C: RPCClient>(
+        vim: &C,
+        file: Option<&Path>,
+        unknown: &mut Vec<String>,
+    ) -> Result<Config> {
+        let mut value = match file {
+            Some(file) => read_file(file)?,
+            None => Value::Object(Default::default()),
+        };
+
+        // variables that are not set come back as null and leave the value in the file alone.
         let req = r#"{
-            "servers": get(g:, 'vlc#servers', {}),
+            "servers": get(g:, 'vlc#servers', v:null),
             "log": {
-                "level": get(g:, 'vlc#log#level', 'error'),
-                "output": get(g:, 'vlc#log#output', '/tmp/vlc.log'),
+                "level": get(g:, 'vlc#log#level', v:null),
+                "output": get(g:, 'vlc#log#output', v:null),
+            },
+            "completion": {
+                "enabled": get(g:, 'vlc#completion#enabled', v:null),
+                "strategy": get(g:, 'vlc#completion#strategy', v:null),
+            },
+            "locations": {
+                "auto_open": get(g:, 'vlc#locations#auto_open', v:null),
+            },
+            "diagnostics": {
+                "auto_open": get(g:, 'vlc#diagnostics#auto_open', v:null),
+                "show_signs": get(g:, 'vlc#diagnostics#show_signs', v:null),
+            },
+            "features": get(g:, 'vlc#features', v:null),
+            "timeouts": get(g:, 'vlc#timeouts', v:null),
+            "fileWatcher": {
+                "ignored_dirs": get(g:, 'vlc#file_watcher#ignored_dirs', v:null),
             },
+            "trustedProjects": get(g:, 'vlc#trusted_projects', v:null),
         }"#;
+        let globals: Value = vim.call("eval", [req.replace("\n", "")])?;
+        merge(&mut value, globals);
 
-        let config: Config = vim.call("eval", [req.replace("\n", "")])?;
+        let mut unknown_key = |path: serde_ignored::Path| unknown.push(path.to_string());
+        let deserializer = serde_ignored::Deserializer::new(value, &mut unknown_key);
+        let mut config: Config = serde_path_to_error::deserialize(deserializer)?;
+        config.file = file.map(Path::to_owned);
         Ok(config)
     }
 
-    pub fn server(&self, language_id: &str) -> Result<&ServerConfig> {
-        let command = self.servers.get(language_id).ok_or(anyhow::anyhow!(
-            "no server command found for filetype {}",
-            language_id
-        ))?;
+    /// returns the servers configured for the given language id, in priority order.
+    pub fn servers(&self, language_id: &str) -> Result<&[ServerConfig]> {
+        match self.servers.get(language_id) {
+            Some(servers) if !servers.is_empty() => Ok(servers),
+            _ => Err(anyhow::anyhow!(
+                "no server command found for filetype {}",
+                language_id
+            )),
+        }
+    }
+
+    pub fn server(&self, language_id: &str, name: &str) -> Result<&ServerConfig> {
+        self.servers(language_id)?
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "no server named {} found for filetype {}",
+                    name,
+                    language_id
+                )
+            })
+    }
+
+    /// returns whether the project at the given root is trusted to have its own config.
+    pub fn is_trusted(&self, root: &str) -> bool {
+        self.trusted_projects.iter().any(|trusted| {
+            let trusted = shellexpand::tilde(trusted);
+            let trusted = trusted.trim_end_matches('/');
+            Path::new(root).starts_with(trusted) || crate::glob::matches(trusted, root)
+        })
+    }
+
+    pub fn timeout(&self, method: &str) -> Duration {
+        self.timeouts.get(method)
+    }
+
+    /// returns the features enabled for the given language id, a feature being enabled if it is
+    /// for any of its servers and has not been turned off for all of them.
+    pub fn features(&self, language_id: &str) -> Result<FeatureSet> {
+        let servers = self.servers(language_id)?;
+        let flags = &self.features;
+        Ok(FeatureSet {
+            code_lenses: flags.code_lens && servers.iter().any(|s| s.features.code_lenses),
+            code_actions: flags.code_action && servers.iter().any(|s| s.features.code_actions),
+            completion: self.completion.enabled
+                && flags.completion
+                && servers.iter().any(|s| s.features.completion),
+            diagnostics: flags.diagnostics && servers.iter().any(|s| s.features.diagnostics),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::FakeClient;
+    use serde_json::json;
+
+    fn server_config(settings: Value) -> ServerConfig {
+        ServerConfig {
+            name: "test".into(),
+            settings,
+            ..Default::default()
+        }
+    }
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("vlc-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    // returns a vim that has the given globals set, the ones that aren't being null.
+    fn vim(globals: Value) -> FakeClient {
+        FakeClient::with_response("eval", globals)
+    }
+
+    fn messages(vim: &FakeClient) -> Vec<Message> {
+        vim.notified("vlc#show_message")
+            .into_iter()
+            .flat_map(|params| serde_json::from_value::<Vec<Message>>(params).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_settings_section() {
+        let config = server_config(json!({
+            "python": {"analysis": {"typeCheckingMode": "strict"}},
+            "rust-analyzer.cargo": {"features": ["all"]},
+            "gopls": {"ui.completion": {"usePlaceholders": true}},
+        }));
+
+        assert_eq!(
+            config.settings_section("python"),
+            json!({"analysis": {"typeCheckingMode": "strict"}})
+        );
+        assert_eq!(
+            config.settings_section("python.analysis"),
+            json!({"typeCheckingMode": "strict"})
+        );
+        assert_eq!(
+            config.settings_section("python.analysis.typeCheckingMode"),
+            json!("strict")
+        );
+        // keys with dots in them, at the top and further down.
+        assert_eq!(
+            config.settings_section("rust-analyzer.cargo.features"),
+            json!(["all"])
+        );
+        assert_eq!(
+            config.settings_section("gopls.ui.completion.usePlaceholders"),
+            json!(true)
+        );
+    }
+
+    #[test]
+    fn test_settings_section_missing() {
+        let config = server_config(json!({"python": {"analysis": {}}}));
+        assert_eq!(config.settings_section("go"), Value::Null);
+        assert_eq!(config.settings_section("python.linting"), Value::Null);
+        assert_eq!(config.settings_section("python.analysis.mode"), Value::Null);
+
+        // a section can't be looked up inside a value that is not an object.
+        let config = server_config(json!({"python": "strict"}));
+        assert_eq!(config.settings_section("python.analysis"), Value::Null);
+        assert_eq!(
+            server_config(Value::Null).settings_section("python"),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn test_settings_section_empty() {
+        let settings = json!({"python": {"analysis": {}}});
+        assert_eq!(
+            server_config(settings.clone()).settings_section(""),
+            settings
+        );
+        assert_eq!(server_config(Value::Null).settings_section(""), Value::Null);
+        assert_eq!(
+            server_config(settings).settings_section("python.analysis"),
+            json!({})
+        );
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let file = config_file(
+            "parse.toml",
+            r#"
+            trustedProjects = ["~/src"]
+
+            [servers.go]
+            name = "gopls"
+            command = "gopls"
+
+            [[servers.python]]
+            name = "pyright"
+            command = "pyright-langserver"
+            args = ["--stdio"]
+            changeDelay = 0
+
+            [[servers.python]]
+            name = "ruff"
+            command = "ruff"
+            restartAttempts = 0
+
+            [servers.python.settings]
+            lineLength = 100
+
+            [timeouts]
+            "textDocument/hover" = 2000
+            "#,
+        );
+
+        let vim = vim(json!({}));
+        let config = Config::parse(&vim, Some(&file)).unwrap();
+        assert!(messages(&vim).is_empty());
+        assert_eq!(config.file, Some(file));
+        assert_eq!(config.trusted_projects, vec!["~/src"]);
+
+        let gopls = config.server("go", "gopls").unwrap();
+        assert_eq!(gopls.command, "gopls");
+        assert_eq!(gopls.change_delay, 100);
+        assert_eq!(gopls.restart_attempts, 3);
+
+        let python = config.servers("python").unwrap();
+        assert_eq!(python.len(), 2);
+        assert_eq!(python[0].name, "pyright");
+        assert_eq!(python[0].args, vec!["--stdio"]);
+        assert_eq!(python[0].change_delay, 0);
+        assert_eq!(python[1].name, "ruff");
+        assert_eq!(python[1].restart_attempts, 0);
+        assert_eq!(python[1].settings, json!({"lineLength": 100}));
+
+        // the timeouts that are set are merged on top of the defaults.
+        assert_eq!(
+            config.timeout("textDocument/hover"),
+            Duration::from_millis(2000)
+        );
+        assert_eq!(config.timeout("initialize"), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let file = config_file(
+            "parse.json",
+            r#"{
+                "servers": {
+                    "rust": {"name": "rust-analyzer", "command": "rust-analyzer"},
+                    "c": [{"name": "clangd", "transport": {"tcp": "127.0.0.1:9000"}}]
+                },
+                "completion": {"strategy": "ncm2"},
+                "diagnostics": {"auto_open": true}
+            }"#,
+        );
+
+        let vim = vim(json!({}));
+        let config = Config::parse(&vim, Some(&file)).unwrap();
+        assert!(messages(&vim).is_empty());
+        assert_eq!(
+            config.server("rust", "rust-analyzer").unwrap().command,
+            "rust-analyzer"
+        );
+        assert_eq!(
+            config.server("c", "clangd").unwrap().transport,
+            Transport::Tcp("127.0.0.1:9000".into())
+        );
+        assert_eq!(config.completion.strategy, CompletionStrategy::NCM2);
+        assert!(config.completion.enabled);
+        assert!(config.diagnostics.auto_open);
+        assert!(config.diagnostics.show_signs);
+    }
+
+    #[test]
+    fn test_parse_error_path() {
+        let cases = [
+            (
+                json!({"servers": {"go": {"name": "gopls", "changeDelay": "soon"}}}),
+                "servers.go.changeDelay",
+            ),
+            (
+                json!({"servers": {"go": [{"name": "gopls"}, {"name": "x", "args": "-v"}]}}),
+                "servers.go[1].args",
+            ),
+            (json!({"servers": {"go": "gopls"}}), "servers.go"),
+            (json!({"log": {"level": 3}}), "log.level"),
+        ];
+
+        for (globals, path) in cases.iter() {
+            let vim = vim(json!({ "servers": globals.get("servers"), "log": globals.get("log") }));
+            let err = Config::parse(&vim, None).unwrap_err().to_string();
+            assert!(err.starts_with(path), "{} does not point at {}", err, path);
+
+            let messages = messages(&vim);
+            assert_eq!(messages.len(), 1);
+            assert!(matches!(messages[0].level, LogLevel::Error));
+            assert!(messages[0].message.contains(path));
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_keys() {
+        let vim = vim(json!({
+            "servers": {
+                "go": {"name": "gopls", "comand": "gopls"},
+                "python": [
+                    {"name": "pyright", "settings": {"anything": "goes"}},
+                    {"name": "ruff", "feature": {}},
+                ],
+            },
+        }));
+        let file = config_file(
+            "unknown.toml",
+            "colour = \"blue\"\n[log]\nfile = \"/tmp/x\"\n",
+        );
+        let config = Config::parse(&vim, Some(&file)).unwrap();
+        assert_eq!(config.server("go", "gopls").unwrap().command, "");
+
+        let mut unknown: Vec<_> = messages(&vim)
+            .into_iter()
+            .inspect(|message| assert!(matches!(message.level, LogLevel::Warning)))
+            .map(|message| message.message)
+            .collect();
+        unknown.sort();
+        assert_eq!(
+            unknown,
+            vec![
+                "unknown config key colour",
+                "unknown config key log.file",
+                "unknown config key servers.go.comand",
+                "unknown config key servers.python.1.feature",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_merges_globals() {
+        let file = config_file(
+            "globals.toml",
+            r#"
+            [servers.go]
+            name = "gopls"
+
+            [servers.rust]
+            name = "rls"
+
+            [log]
+            level = "info"
+            output = "/tmp/file.log"
+
+            [completion]
+            enabled = false
+            strategy = "ncm2"
+
+            [timeouts]
+            default = 1000
+            "#,
+        );
+
+        // this is what vim sends back when only some of the globals are set.
+        let vim = vim(json!({
+            "servers": {"rust": {"name": "rust-analyzer"}},
+            "log": {"level": "debug", "output": null},
+            "completion": {"enabled": true, "strategy": null},
+            "locations": {"auto_open": null},
+            "diagnostics": {"auto_open": null, "show_signs": false},
+            "features": null,
+            "timeouts": {"initialize": 2000},
+            "trustedProjects": null,
+        }));
+        let config = Config::parse(&vim, Some(&file)).unwrap();
+        assert!(messages(&vim).is_empty());
+
+        // the servers set in vim for a language id replace the ones in the file.
+        assert!(config.server("go", "gopls").is_ok());
+        assert!(config.server("rust", "rls").is_err());
+        assert!(config.server("rust", "rust-analyzer").is_ok());
+        assert_eq!(config.log.level, "debug");
+        assert_eq!(config.log.output, "/tmp/file.log");
+        assert!(config.completion.enabled);
+        assert_eq!(config.completion.strategy, CompletionStrategy::NCM2);
+        assert!(config.locations.auto_open);
+        assert!(!config.diagnostics.show_signs);
+        assert_eq!(config.timeout("initialize"), Duration::from_secs(2));
+        assert_eq!(config.timeout("default"), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let timeouts = Timeouts::default();
+        assert_eq!(timeouts.get("initialize"), Duration::from_secs(60));
+        assert_eq!(
+            timeouts.get("textDocument/completion"),
+            Duration::from_millis(500)
+        );
+        // methods without a timeout of their own use the default one.
+        assert_eq!(timeouts.get("textDocument/hover"), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_timeouts_merged_over_defaults() {
+        let timeouts: Timeouts = serde_json::from_value(json!({
+            "default": 2000,
+            "textDocument/completion": 100,
+        }))
+        .unwrap();
+        assert_eq!(
+            timeouts.get("textDocument/completion"),
+            Duration::from_millis(100)
+        );
+        assert_eq!(timeouts.get("textDocument/hover"), Duration::from_secs(2));
+        assert_eq!(timeouts.get("initialize"), Duration::from_secs(60));
+
+        // without a default at all the built in one is used.
+        let timeouts = Timeouts(HashMap::new());
+        assert_eq!(timeouts.get("initialize"), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_timeouts_from_vim() {
+        let file = config_file(
+            "timeouts.toml",
+            "[timeouts]\ndefault = 3000\nshutdown = 1\n",
+        );
+        let vim = vim(json!({"timeouts": {"shutdown": 2000, "textDocument/rename": 50}}));
+        let config = Config::parse(&vim, Some(&file)).unwrap();
+        assert_eq!(config.timeout("shutdown"), Duration::from_secs(2));
+        assert_eq!(
+            config.timeout("textDocument/rename"),
+            Duration::from_millis(50)
+        );
+        assert_eq!(config.timeout("textDocument/hover"), Duration::from_secs(3));
+        assert_eq!(config.timeout("initialize"), Duration::from_secs(60));
+    }
+
+    fn features_config(features: Vec<FeatureSet>) -> Config {
+        let servers = features
+            .into_iter()
+            .enumerate()
+            .map(|(i, features)| ServerConfig {
+                name: format!("server{}", i),
+                features,
+                ..Default::default()
+            })
+            .collect();
+        let mut config = Config::default();
+        config.servers.insert("rust".into(), servers);
+        config
+    }
+
+    #[test]
+    fn test_features() {
+        let only_completion = FeatureSet {
+            code_lenses: false,
+            code_actions: false,
+            completion: true,
+            diagnostics: false,
+        };
+        let only_diagnostics = FeatureSet {
+            completion: false,
+            diagnostics: true,
+            ..only_completion.clone()
+        };
+
+        // a feature is enabled if any of the servers has it.
+        let config = features_config(vec![only_completion.clone(), only_diagnostics]);
+        assert_eq!(
+            config.features("rust").unwrap(),
+            FeatureSet {
+                code_lenses: false,
+                code_actions: false,
+                completion: true,
+                diagnostics: true,
+            }
+        );
+
+        let config = features_config(vec![only_completion.clone()]);
+        assert_eq!(config.features("rust").unwrap(), only_completion);
+
+        assert!(config.features("go").is_err());
+    }
+
+    #[test]
+    fn test_features_turned_off_globally() {
+        let mut config = features_config(vec![FeatureSet::default()]);
+        assert_eq!(config.features("rust").unwrap(), FeatureSet::default());
+
+        config.features.code_lens = false;
+        config.features.diagnostics = false;
+        let features = config.features("rust").unwrap();
+        assert!(!features.code_lenses);
+        assert!(!features.diagnostics);
+        assert!(features.code_actions);
+        assert!(features.completion);
 
-        Ok(command)
+        // completion is off when it is disabled in the completion settings too.
+        config.completion.enabled = false;
+        assert!(!config.features("rust").unwrap().completion);
     }
 
-    pub fn features(&self, language_id: &str) -> Result<&FeatureSet> {
-        let command = self.servers.get(language_id).ok_or(anyhow::anyhow!(
-            "no server command found for filetype {}",
-            language_id
-        ))?;
+    #[test]
+    fn test_file_watcher_ignored_dirs() {
+        let config = Config::parse(&vim(json!({})), None).unwrap();
+        let ignored = config.file_watcher.ignored_dirs;
+        assert!(ignored.contains(&"node_modules".to_string()));
+        assert!(ignored.contains(&"target".to_string()));
 
-        Ok(&command.features)
+        let vim = vim(json!({"fileWatcher": {"ignored_dirs": ["out"]}}));
+        let config = Config::parse(&vim, None).unwrap();
+        assert_eq!(config.file_watcher.ignored_dirs, vec!["out"]);
     }
 }
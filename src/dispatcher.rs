@@ -0,0 +1,196 @@
+use crossbeam::channel::{Receiver, Sender};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::AssertUnwindSafe;
+
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum LaneMessage {
+    Run(Job),
+    // signals the sender once every message queued before it in the lane has been handled.
+    Barrier(Sender<()>),
+}
+
+/// Runs jobs on a pool of worker threads. Ordered jobs are sent to a lane picked by their key, so
+/// that jobs sharing a key (e.g. notifications for the same text document) run one after the
+/// other in the order they were dispatched. Unordered jobs run concurrently on the workers, but
+/// only after every ordered job dispatched before them for the same key has finished.
+pub struct Dispatcher {
+    workers_tx: Sender<Job>,
+    lanes: Vec<Sender<LaneMessage>>,
+}
+
+impl Dispatcher {
+    pub fn new(workers: usize, lanes: usize) -> Self {
+        let (workers_tx, workers_rx) = crossbeam::channel::unbounded::<Job>();
+        for _ in 0..workers {
+            let rx = workers_rx.clone();
+            std::thread::spawn(move || {
+                for job in rx.iter() {
+                    run_job(job);
+                }
+            });
+        }
+
+        let lanes = (0..lanes)
+            .map(|_| {
+                let (tx, rx) = crossbeam::channel::unbounded();
+                std::thread::spawn(move || run_lane(rx));
+                tx
+            })
+            .collect();
+
+        Self { workers_tx, lanes }
+    }
+
+    /// runs the job after every job previously dispatched with the same key.
+    pub fn dispatch_ordered(&self, key: &str, job: Job) {
+        if self.lane(key).send(LaneMessage::Run(job)).is_err() {
+            log::error!("dispatcher lane for {} is gone", key);
+        }
+    }
+
+    /// runs the job on the worker pool once the ordered jobs dispatched before it for the same key
+    /// are done.
+    pub fn dispatch(&self, key: &str, job: Job) {
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        if self.lane(key).send(LaneMessage::Barrier(tx)).is_err() {
+            log::error!("dispatcher lane for {} is gone", key);
+        }
+
+        let job: Job = Box::new(move || {
+            let _ = rx.recv();
+            job()
+        });
+        if self.workers_tx.send(job).is_err() {
+            log::error!("dispatcher workers are gone");
+        }
+    }
+
+    /// blocks until every ordered job dispatched so far has finished.
+    pub fn wait_ordered(&self) {
+        let barriers: Vec<Receiver<()>> = self
+            .lanes
+            .iter()
+            .map(|lane| {
+                let (tx, rx) = crossbeam::channel::bounded(1);
+                let _ = lane.send(LaneMessage::Barrier(tx));
+                rx
+            })
+            .collect();
+
+        for rx in barriers {
+            let _ = rx.recv();
+        }
+    }
+
+    fn lane(&self, key: &str) -> &Sender<LaneMessage> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.lanes[hasher.finish() as usize % self.lanes.len()]
+    }
+}
+
+fn run_lane(rx: Receiver<LaneMessage>) {
+    for message in rx.iter() {
+        match message {
+            LaneMessage::Run(job) => run_job(job),
+            LaneMessage::Barrier(tx) => {
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
+// a job panicking must not take down the thread running it, or the pool would shrink over time.
+fn run_job(job: Job) {
+    if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        log::error!("dispatched job panicked");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // returns a job that records the given value once it has slept for a while, so that jobs that
+    // are not ordered finish out of order.
+    fn record(log: &Arc<Mutex<Vec<u32>>>, value: u32, sleep: u64) -> Job {
+        let log = log.clone();
+        Box::new(move || {
+            std::thread::sleep(Duration::from_millis(sleep));
+            log.lock().push(value);
+        })
+    }
+
+    #[test]
+    fn ordered_jobs_run_in_dispatch_order() {
+        let dispatcher = Dispatcher::new(4, 4);
+        let a = Arc::new(Mutex::new(vec![]));
+        let b = Arc::new(Mutex::new(vec![]));
+        for i in 0..10 {
+            dispatcher.dispatch_ordered("a", record(&a, i, (10 - i as u64) * 2));
+            dispatcher.dispatch_ordered("b", record(&b, i, i as u64 * 2));
+        }
+
+        dispatcher.wait_ordered();
+        assert_eq!(*a.lock(), (0..10).collect::<Vec<_>>());
+        assert_eq!(*b.lock(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unordered_jobs_wait_for_earlier_ordered_jobs() {
+        let dispatcher = Dispatcher::new(4, 4);
+        let log = Arc::new(Mutex::new(vec![]));
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        dispatcher.dispatch_ordered("a", record(&log, 1, 50));
+        let job_log = log.clone();
+        dispatcher.dispatch(
+            "a",
+            Box::new(move || {
+                job_log.lock().push(2);
+                let _ = tx.send(());
+            }),
+        );
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(*log.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn wait_ordered_waits_for_every_lane() {
+        let dispatcher = Dispatcher::new(1, 4);
+        let log = Arc::new(Mutex::new(vec![]));
+        for (i, key) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            dispatcher.dispatch_ordered(key, record(&log, i as u32, 20));
+        }
+
+        dispatcher.wait_ordered();
+        assert_eq!(log.lock().len(), 5);
+    }
+
+    #[test]
+    fn panicking_jobs_do_not_stop_the_pool() {
+        let dispatcher = Dispatcher::new(1, 1);
+        let log = Arc::new(Mutex::new(vec![]));
+        dispatcher.dispatch_ordered("a", Box::new(|| panic!("ordered job")));
+        dispatcher.dispatch_ordered("a", record(&log, 1, 0));
+        dispatcher.dispatch("a", Box::new(|| panic!("unordered job")));
+
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        let job_log = log.clone();
+        dispatcher.dispatch(
+            "a",
+            Box::new(move || {
+                job_log.lock().push(2);
+                let _ = tx.send(());
+            }),
+        );
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(*log.lock(), vec![1, 2]);
+    }
+}
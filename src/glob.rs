@@ -0,0 +1,233 @@
+// glob patterns as used by root markers and the language server protocol. `*` matches anything
+// but a path separator, `**` matches any number of path segments, `?` matches a single character,
+// `[...]` matches one of a set or range of characters (`[!...]` negates it) and `{a,b}` matches
+// either of the comma separated alternatives.
+
+/// returns whether the pattern has any special characters, as opposed to being a plain path.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '{'])
+}
+
+/// returns whether the whole text matches the pattern.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    expand_braces(pattern).iter().any(|pattern| {
+        let pattern: Vec<char> = pattern.chars().collect();
+        match_here(&pattern, &text)
+    })
+}
+
+// expands every `{a,b}` group in the pattern into the patterns it stands for.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let open = match pattern.find('{') {
+        Some(open) => open,
+        None => return vec![pattern.into()],
+    };
+
+    let mut depth = 0;
+    let mut close = None;
+    let mut alternatives = vec![];
+    let mut start = open + 1;
+    for (i, c) in pattern[open..].char_indices().map(|(i, c)| (i + open, c)) {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    alternatives.push(&pattern[start..i]);
+                    close = Some(i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => {
+                alternatives.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    // an unbalanced brace is matched literally.
+    let close = match close {
+        Some(close) => close,
+        None => return vec![pattern.into()],
+    };
+
+    let (prefix, suffix) = (&pattern[..open], &pattern[close + 1..]);
+    alternatives
+        .into_iter()
+        .flat_map(|alternative| expand_braces(&format!("{}{}{}", prefix, alternative, suffix)))
+        .collect()
+}
+
+fn match_here(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            match rest.first() {
+                // `**/` matches zero or more whole segments.
+                Some('/') => {
+                    let rest = &rest[1..];
+                    (0..=text.len())
+                        .filter(|&i| i == 0 || text[i - 1] == '/')
+                        .any(|i| match_here(rest, &text[i..]))
+                }
+                _ => (0..=text.len()).any(|i| match_here(rest, &text[i..])),
+            }
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if match_here(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' {
+                    return false;
+                }
+            }
+            false
+        }
+        Some('?') => match text.first() {
+            Some(c) if *c != '/' => match_here(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some('[') => match (parse_class(pattern), text.first()) {
+            (Some((class, len)), Some(c)) => {
+                *c != '/' && class.matches(*c) && match_here(&pattern[len..], &text[1..])
+            }
+            (Some(_), None) => false,
+            // an unclosed bracket is matched literally.
+            (None, Some('[')) => match_here(&pattern[1..], &text[1..]),
+            (None, _) => false,
+        },
+        Some(c) => match text.first() {
+            Some(t) if t == c => match_here(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+    }
+}
+
+struct Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        let found = self
+            .ranges
+            .iter()
+            .any(|(start, end)| *start <= c && c <= *end);
+        found != self.negated
+    }
+}
+
+// parses the character class at the start of the pattern, returning it along with the number of
+// characters it takes up.
+fn parse_class(pattern: &[char]) -> Option<(Class, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = vec![];
+    let mut first = true;
+    loop {
+        let c = *pattern.get(i)?;
+        // a closing bracket right after the opening one is part of the set.
+        if c == ']' && !first {
+            return Some((Class { negated, ranges }, i + 1));
+        }
+
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(end)) if *end != ']' => {
+                ranges.push((c, *end));
+                i += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_glob() {
+        assert!(is_glob("*.rs"));
+        assert!(is_glob("src/[ab].rs"));
+        assert!(is_glob("{a,b}"));
+        assert!(is_glob("?"));
+        assert!(!is_glob("Cargo.toml"));
+        assert!(!is_glob("/home/user/project"));
+    }
+
+    #[test]
+    fn test_matches() {
+        let cases = [
+            // `*` stays within a segment.
+            ("*.rs", "main.rs", true),
+            ("*.rs", ".rs", true),
+            ("*.rs", "main.go", false),
+            ("*.rs", "src/main.rs", false),
+            ("src/*", "src/main.rs", true),
+            ("src/*", "src/bin/main.rs", false),
+            // `**` crosses segments.
+            ("**/*.rs", "main.rs", true),
+            ("**/*.rs", "src/bin/main.rs", true),
+            ("**/*.rs", "src/bin/main.go", false),
+            ("src/**/*.rs", "src/main.rs", true),
+            ("src/**/*.rs", "src/a/b/main.rs", true),
+            ("src/**/*.rs", "lib/main.rs", false),
+            ("**/target/**", "a/target/debug/vlc", true),
+            ("**", "any/thing/at/all", true),
+            ("a**b", "a/x/b", true),
+            // `?` matches a single character but not a separator.
+            ("?.rs", "a.rs", true),
+            ("?.rs", "ab.rs", false),
+            ("?.rs", ".rs", false),
+            ("a?b", "a/b", false),
+            // `{a,b}` matches either alternative.
+            ("*.{rs,toml}", "Cargo.toml", true),
+            ("*.{rs,toml}", "main.rs", true),
+            ("*.{rs,toml}", "main.go", false),
+            ("{src,tests}/**/*.rs", "tests/it.rs", true),
+            ("{a,{b,c}}.txt", "c.txt", true),
+            ("{a,b.txt", "{a,b.txt", true),
+            // `[...]` matches one of a set or range.
+            ("[abc].rs", "b.rs", true),
+            ("[abc].rs", "d.rs", false),
+            ("[a-c].rs", "c.rs", true),
+            ("[!a-c].rs", "c.rs", false),
+            ("[!a-c].rs", "d.rs", true),
+            ("[]].rs", "].rs", true),
+            ("[a-].rs", "-.rs", true),
+            ("[/]", "/", false),
+            ("[ab", "[ab", true),
+            // absolute patterns only match absolute paths, root relative ones only relative paths.
+            ("/home/*/project", "/home/user/project", true),
+            ("/home/*/project", "home/user/project", false),
+            ("src/*.rs", "/root/src/main.rs", false),
+            ("**/src/*.rs", "/root/src/main.rs", true),
+            ("/root/**", "/root/src/main.rs", true),
+            ("/root/**", "/other/src/main.rs", false),
+        ];
+
+        for (pattern, text, expected) in cases.iter() {
+            assert_eq!(
+                matches(pattern, text),
+                *expected,
+                "{} against {}",
+                pattern,
+                text
+            );
+        }
+    }
+}
@@ -1,24 +1,49 @@
+use crate::changes::PendingChanges;
+use crate::dispatcher::Dispatcher;
+use crate::lsp::capabilities::Document;
+use crate::rpc::{Reader, ServerID, Transport, Writer};
 use crate::state::State;
+use crate::watcher::Watcher;
 use crate::{config::Config, rpc::Message};
 use crate::{config::FeatureSet, rpc::RPCClient};
 use crate::{config::ServerConfig, rpc};
 use anyhow::Result;
 use jsonrpc_core::Value;
-use parking_lot::RwLock;
-use serde::Deserialize;
+use lsp_types::notification::{DidChangeTextDocument, DidChangeWorkspaceFolders, Notification};
+use lsp_types::FileEvent;
+use parking_lot::{Mutex, RwLock};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::BufReader;
+use std::path::Path;
 use std::process::Stdio;
-use std::process::{Child, Command};
+use std::process::{Child, Command, ExitStatus};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+// number of threads handling method calls from vim concurrently.
+const WORKERS: usize = 4;
+// number of threads handling notifications from vim, each one in order for its documents.
+const LANES: usize = 2;
+// vim method calls that are handled on their own, once every message before them is handled.
+const LIFECYCLE_METHODS: &[&str] = &["start", "initialize", "shutdown", "exit"];
+
+// delay before the first restart of a crashed server, doubled on every consecutive restart.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+// longest a crashed server is waited on before restarting it.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+// how long a server needs to stay up for its restart backoff to be reset.
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct Context<C, S>
 where
     C: RPCClient,
     S: RPCClient,
 {
     pub vim: C,
-    pub server: Option<S>,
+    // servers running for the language id of this context, in priority order.
+    pub servers: Vec<(ServerID, S)>,
     pub language_id: String,
     pub bufnr: usize,
     pub filename: String,
@@ -26,6 +51,8 @@ where
     pub state: Arc<RwLock<State>>,
     pub config: Config,
     pub root_path: String,
+    pub watcher: Watcher,
+    pub changes: PendingChanges,
 }
 
 impl<C: RPCClient, S: RPCClient> Context<C, S> {
@@ -73,45 +100,288 @@ impl<C: RPCClient, S: RPCClient> Context<C, S> {
             .unwrap_or_default()
             .unwrap_or_default();
 
-        let root_path = lc
-            .state
-            .read()
-            .roots
-            .get(&language_id)
-            .cloned()
+        Self::for_document(lc, &language_id, bufnr, &filename, message_id)
+    }
+
+    // builds a context for a document that is not tied to a message, e.g. when the language client
+    // needs to talk to a server on its own.
+    pub fn for_document(
+        lc: &LanguageClient<C, S>,
+        language_id: &str,
+        bufnr: usize,
+        filename: &str,
+        message_id: jsonrpc_core::Id,
+    ) -> Self {
+        // documents are handled by the servers started for their workspace root. A context that
+        // is not for a document spans every running instance of the servers for its language id.
+        let config = lc.config();
+        let mut roots = vec![];
+        let mut servers = vec![];
+        for server_config in config.servers(language_id).unwrap_or_default() {
+            let root = lc.root_for(language_id, server_config, filename);
+            let state = lc.state.read();
+            let mut instances: Vec<(ServerID, S)> = lc
+                .servers
+                .read()
+                .iter()
+                .filter(|(id, _)| {
+                    id.language_id == language_id
+                        && id.name == server_config.name
+                        && root
+                            .as_ref()
+                            .map_or(true, |root| state.roots_for(id).contains(root))
+                })
+                .map(|(id, server)| (id.clone(), server.clone()))
+                .collect();
+            drop(state);
+            instances.sort_by(|(a, _), (b, _)| a.root.cmp(&b.root));
+            servers.extend(instances);
+            roots.extend(root);
+        }
+
+        // paths are shown relative to the root of the server with the highest priority.
+        let root_path = roots
+            .into_iter()
+            .next()
+            .map(|root| root + "/")
             .unwrap_or_default();
-        let server = lc.servers.read().get(&language_id).cloned();
         Self {
             vim: lc.vim.clone(),
-            server,
-            language_id,
+            servers,
+            language_id: language_id.into(),
             bufnr,
-            filename,
+            filename: filename.into(),
             message_id,
             state: Arc::clone(&lc.state),
-            config: lc.config.clone(),
+            config,
             root_path,
+            watcher: lc.watcher.clone(),
+            changes: lc.changes.clone(),
         }
     }
 
-    pub fn server(&self) -> Result<&ServerConfig> {
-        let command = self.config.server(&self.language_id)?;
-        Ok(command)
+    /// returns a copy of this context that only talks to the given server.
+    pub fn with_server(&self, id: &ServerID) -> Self {
+        let mut ctx = self.clone();
+        ctx.servers.retain(|(server_id, _)| server_id == id);
+        ctx
+    }
+
+    /// returns the config of the server, including the overrides in the project it was started
+    /// in.
+    pub fn server_config(&self, id: &ServerID) -> Result<ServerConfig> {
+        crate::project::server_config(&self.vim, &self.config, &self.state, id)
+    }
+
+    pub fn features(&self) -> Result<FeatureSet> {
+        self.config.features(&self.language_id)
+    }
+
+    /// returns whether any of the servers for this context handles the method.
+    pub fn supports(&self, method: &str) -> bool {
+        !self.servers_for(method).is_empty()
     }
 
-    pub fn features(&self) -> Result<&FeatureSet> {
-        let features = self.config.features(&self.language_id)?;
-        Ok(&features)
+    /// returns the servers for this context that handle the method, either statically or through
+    /// a dynamic registration, in priority order. Servers that have not been initialized yet are
+    /// assumed to handle it.
+    pub fn servers_for(&self, method: &str) -> Vec<&(ServerID, S)> {
+        let state = self.state.read();
+        self.servers
+            .iter()
+            .filter(|(id, _)| state.supports(id, method, self.document()))
+            .collect()
+    }
+
+    /// returns the document this context is for, if any.
+    pub fn document(&self) -> Option<Document<'_>> {
+        if self.filename.is_empty() {
+            return None;
+        }
+
+        Some(Document {
+            language_id: &self.language_id,
+            filename: &self.filename,
+        })
+    }
+
+    /// sends a request to the servers that handle the method in priority order, returning the
+    /// first result that is not null. Requests are given the timeout configured for the method,
+    /// and the user is warned about the ones that time out.
+    pub fn call<M, R>(&self, method: &str, params: M) -> Result<R>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+    {
+        if self.servers.is_empty() {
+            anyhow::bail!("no server running for filetype {}", self.language_id);
+        }
+
+        let params = serde_json::to_value(params)?;
+        let mut error = None;
+        let mut answered = false;
+        for (id, _) in self.servers_for(method) {
+            match self.call_server::<_, Value>(id, method, &params) {
+                Ok(Value::Null) => answered = true,
+                Ok(value) => return Ok(serde_json::from_value(value)?),
+                Err(err) => {
+                    log::debug!("{} failed on {}: {}", method, id, err);
+                    error = Some(err);
+                }
+            }
+        }
+
+        match error {
+            Some(err) if !answered => Err(err),
+            _ => Ok(serde_json::from_value(Value::Null)?),
+        }
+    }
+
+    /// sends a request to the given server, using the timeout configured for the method.
+    pub fn call_server<M, R>(&self, id: &ServerID, method: &str, params: M) -> Result<R>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+    {
+        self.flush_changes(id)?;
+        let server = self.get_server(id)?;
+        let timeout = self.config.timeout(method);
+        let res = server.call_timeout(method, params, timeout);
+        if let Err(err) = &res {
+            self.warn_timeout(err)?;
+        }
+
+        res
+    }
+
+    /// sends a request to every server that handles the method at once, with params built for
+    /// each of them, and collects the results of the ones that answer within the timeout
+    /// configured for the method.
+    pub fn call_all<M, R, F>(&self, method: &str, params: F) -> Result<Vec<(ServerID, R)>>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+        F: Fn(&ServerID) -> M,
+    {
+        let mut handles = vec![];
+        for (id, server) in self.servers_for(method) {
+            self.flush_changes(id)?;
+            match server.call_cancellable::<_, R>(method, params(id)) {
+                Ok(handle) => handles.push((id.clone(), handle)),
+                Err(err) => log::error!("could not send {} to {}: {}", method, id, err),
+            }
+        }
+
+        let deadline = Instant::now() + self.config.timeout(method);
+        let mut results = vec![];
+        for (id, handle) in handles {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match handle.wait_timeout(timeout) {
+                Ok(res) => results.push((id, res)),
+                Err(err) => {
+                    log::debug!("{} failed on {}: {}", method, id, err);
+                    self.warn_timeout(&err)?;
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// sends a notification to every server for this context.
+    pub fn notify<M>(&self, method: &str, params: M) -> Result<()>
+    where
+        M: Serialize,
+    {
+        let params = serde_json::to_value(params)?;
+        for (id, server) in &self.servers {
+            self.flush_changes(id)?;
+            server.notify(method, &params)?;
+        }
+
+        Ok(())
+    }
+
+    /// sends the server the changes to documents that are waiting to be sent to it.
+    pub fn flush_changes(&self, id: &ServerID) -> Result<()> {
+        self.changes.flush(id, |id, params| {
+            self.get_server(id)?
+                .notify(DidChangeTextDocument::METHOD, params)
+        })
+    }
+
+    /// sends a notification to the given server.
+    pub fn notify_server<M>(&self, id: &ServerID, method: &str, params: M) -> Result<()>
+    where
+        M: Serialize,
+    {
+        self.get_server(id)?.notify(method, params)
+    }
+
+    fn get_server(&self, id: &ServerID) -> Result<&S> {
+        self.servers
+            .iter()
+            .find(|(server_id, _)| server_id == id)
+            .map(|(_, server)| server)
+            .ok_or_else(|| anyhow::anyhow!("server {} is not running", id))
+    }
+
+    fn warn_timeout(&self, err: &anyhow::Error) -> Result<()> {
+        if let Some(rpc::Error::Timeout { .. }) = err.downcast_ref::<rpc::Error>() {
+            crate::vim::show_message(
+                self,
+                crate::vim::Message {
+                    level: crate::vim::LogLevel::Warning,
+                    message: err.to_string(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<C: RPCClient, S: RPCClient> Context<C, S> {
+    // builds a context for the document that talks to the given servers, without a language
+    // client behind it.
+    pub fn fake(vim: C, servers: Vec<(ServerID, S)>, state: State, filename: &str) -> Self {
+        Self {
+            vim,
+            language_id: servers
+                .first()
+                .map(|(id, _)| id.language_id.clone())
+                .unwrap_or_default(),
+            servers,
+            bufnr: 1,
+            filename: filename.into(),
+            message_id: jsonrpc_core::Id::Null,
+            state: Arc::new(RwLock::new(state)),
+            config: Config::default(),
+            root_path: String::new(),
+            watcher: Watcher::new().0,
+            changes: PendingChanges::new().0,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct LanguageClient<C, S> {
-    pub servers: Arc<RwLock<HashMap<String, S>>>,
+    pub servers: Arc<RwLock<HashMap<ServerID, S>>>,
+    // held while servers are being started.
+    starting: Arc<Mutex<()>>,
     pub state: Arc<RwLock<State>>,
     pub root_path: String,
-    pub config: Config,
+    // replaced as a whole when the config changes, contexts get a copy of it as it was when they
+    // were created.
+    config: Arc<RwLock<Config>>,
     pub vim: C,
+    pub watcher: Watcher,
+    // changes to the files watched for each server, waiting to be sent to it.
+    file_events: crossbeam::channel::Receiver<(ServerID, Vec<FileEvent>)>,
+    pub changes: PendingChanges,
+    // signaled when document changes are queued to be sent.
+    changes_queued: crossbeam::channel::Receiver<()>,
 }
 
 impl<C, S> Clone for LanguageClient<C, S>
@@ -122,10 +392,15 @@ where
     fn clone(&self) -> LanguageClient<C, S> {
         Self {
             servers: Arc::clone(&self.servers),
+            starting: Arc::clone(&self.starting),
             state: Arc::clone(&self.state),
             root_path: self.root_path.clone(),
-            config: self.config.clone(),
+            config: Arc::clone(&self.config),
             vim: self.vim.clone(),
+            watcher: self.watcher.clone(),
+            file_events: self.file_events.clone(),
+            changes: self.changes.clone(),
+            changes_queued: self.changes_queued.clone(),
         }
     }
 }
@@ -145,13 +420,20 @@ where
         );
         let root_path = std::env::current_dir().unwrap();
         let root_path = format!("file://{}/", root_path.to_str().unwrap());
+        let (watcher, file_events) = Watcher::new();
+        let (changes, changes_queued) = PendingChanges::new();
 
         Self {
             servers: clients,
+            starting: Arc::new(Mutex::new(())),
             state,
             root_path,
-            config: Config::default(),
+            config: Arc::new(RwLock::new(Config::default())),
             vim,
+            watcher,
+            file_events,
+            changes,
+            changes_queued,
         }
     }
 }
@@ -166,62 +448,835 @@ where
         let state = Arc::new(RwLock::new(State::default()));
         let root_path = std::env::current_dir().unwrap();
         let root_path = format!("file://{}/", root_path.to_str().unwrap());
+        let (watcher, file_events) = Watcher::new();
+        let (changes, changes_queued) = PendingChanges::new();
         Self {
             servers: clients,
+            starting: Arc::new(Mutex::new(())),
             state,
             root_path,
-            config,
+            config: Arc::new(RwLock::new(config)),
             vim,
+            watcher,
+            file_events,
+            changes,
+            changes_queued,
         }
     }
 
-    // runs the binary specified in the config file for the given language_id
-    pub fn start_server(&self, language_id: &str) -> Result<()> {
-        let server_config = self.config.server(language_id)?;
-        let stderr: Stdio = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("/Users/martin/Desktop/lserr.log")?
-            .into();
-
-        let cmd: Child = Command::new(&server_config.command)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(stderr)
-            .spawn()
-            .expect("could not run command");
-
-        let client = S::new(
-            rpc::ClientID::LanguageServer,
-            BufReader::new(cmd.stdout.unwrap()),
-            cmd.stdin.unwrap(),
+    // runs the binaries specified in the config file for the given language_id in the workspace
+    // root of the given file, skipping the ones that are already running. Servers are started in
+    // the current directory if there is no file.
+    pub fn start_server(&self, language_id: &str, filename: &str) -> Result<()> {
+        // two documents from the same new root being opened at once must not start it twice.
+        let _guard = self.starting.lock();
+        let config = self.config();
+        let mut error = None;
+        for server_config in config.servers(language_id)? {
+            let root = if filename.is_empty() {
+                std::env::current_dir()?.to_string_lossy().to_string()
+            } else {
+                match self.root_for(language_id, server_config, filename) {
+                    Some(root) => root,
+                    None => {
+                        log::info!(
+                            "{} is not in a workspace for {}",
+                            filename,
+                            server_config.name
+                        );
+                        continue;
+                    }
+                }
+            };
+            let root = root.as_str();
+            let id = ServerID::new(language_id, &server_config.name, root);
+            if self.servers.read().contains_key(&id) {
+                continue;
+            }
+
+            // servers that can handle more than one workspace folder get the new root added
+            // instead of having another instance started for it.
+            if let Some(running) = self.multi_root_instance(&id) {
+                let ctx = Context::for_document(self, language_id, 0, "", jsonrpc_core::Id::Null);
+                match crate::lsp::workspace::add_workspace_folder(&ctx, &running, root) {
+                    Ok(()) => continue,
+                    Err(err) => log::error!("could not add {} to {}: {}", root, running, err),
+                }
+            }
+
+            if let Err(err) = self.spawn_server(&id, 0) {
+                log::error!("could not start {} server: {}", id, err);
+                error = Some(err);
+            }
+        }
+
+        match error {
+            // vim only needs to know about the failure if none of the servers could be started.
+            Some(err) if !self.is_running(language_id) => Err(err),
+            Some(err) => {
+                let ctx = Context::for_document(self, language_id, 0, "", jsonrpc_core::Id::Null);
+                crate::vim::show_message(
+                    &ctx,
+                    crate::vim::Message {
+                        level: crate::vim::LogLevel::Warning,
+                        message: err.to_string(),
+                    },
+                )
+            }
+            None => Ok(()),
+        }
+    }
+
+    // returns a running instance of the given server that supports workspace folder changes and
+    // does not handle its root yet.
+    fn multi_root_instance(&self, id: &ServerID) -> Option<ServerID> {
+        let state = self.state.read();
+        let servers = self.servers.read();
+        let mut instances: Vec<&ServerID> = servers
+            .keys()
+            .filter(|running| running.language_id == id.language_id && running.name == id.name)
+            .filter(|running| {
+                state.server_capabilities.contains_key(running)
+                    && state.supports(running, DidChangeWorkspaceFolders::METHOD, None)
+            })
+            .collect();
+        instances.sort_by(|a, b| a.root.cmp(&b.root));
+        instances
+            .into_iter()
+            .find(|running| !state.roots_for(running).contains(&id.root))
+            .cloned()
+    }
+
+    /// starts and initializes the servers for the workspace root of the document in the context
+    /// if they are not running yet, and returns a context that includes them. Vim only asks for
+    /// the servers of a language id to be started once, so this is what starts them for any other
+    /// root a document is opened from afterwards.
+    pub fn start_root(&self, ctx: Context<C, S>) -> Result<Context<C, S>> {
+        if ctx.filename.is_empty() || !self.is_running(&ctx.language_id) {
+            return Ok(ctx);
+        }
+
+        let configured = ctx.config.servers(&ctx.language_id)?.len();
+        if ctx.servers.len() >= configured {
+            return Ok(ctx);
+        }
+
+        log::info!("starting {} servers for {}", ctx.language_id, ctx.filename);
+        self.start_server(&ctx.language_id, &ctx.filename)?;
+        let ctx = Context::for_document(
+            self,
+            &ctx.language_id,
+            ctx.bufnr,
+            &ctx.filename,
+            ctx.message_id,
         );
+        crate::lsp::initialize(&ctx)?;
+        Ok(ctx)
+    }
+
+    /// returns the workspace root detected for the given file for the given server, if it is a
+    /// file and the server handles it.
+    pub fn root_for(
+        &self,
+        language_id: &str,
+        server_config: &ServerConfig,
+        filename: &str,
+    ) -> Option<String> {
+        if filename.is_empty() {
+            return None;
+        }
 
+        let key = (filename.to_owned(), server_config.name.clone());
+        if let Some(root) = self.state.read().roots.get(&key) {
+            return Some(root.clone());
+        }
+
+        let root = crate::lsp::get_root_path(Path::new(filename), language_id, server_config)
+            .ok()?
+            .to_string_lossy()
+            .to_string();
+        self.state.write().roots.insert(key, root.clone());
+        Some(root)
+    }
+
+    /// returns a copy of the config as it is now.
+    pub fn config(&self) -> Config {
+        self.config.read().clone()
+    }
+
+    /// reads the config again, along with the project configs, and tells the running servers
+    /// whose settings changed about their new settings. Other changes only apply to servers
+    /// started from now on.
+    pub fn reload_config(&self) -> Result<()> {
+        let file = self.config.read().file.clone();
+        let config = Config::parse(&self.vim, file.as_deref())?;
+
+        let ids: Vec<ServerID> = self.servers.read().keys().cloned().collect();
+        let previous: HashMap<ServerID, Value> = ids
+            .iter()
+            .filter_map(|id| {
+                let ctx =
+                    Context::for_document(self, &id.language_id, 0, "", jsonrpc_core::Id::Null);
+                Some((id.clone(), ctx.server_config(id).ok()?.settings))
+            })
+            .collect();
+
+        *self.config.write() = config;
+        self.state.write().projects.clear();
+
+        for id in ids {
+            let ctx = Context::for_document(self, &id.language_id, 0, "", jsonrpc_core::Id::Null)
+                .with_server(&id);
+            let settings = match ctx.server_config(&id) {
+                Ok(server_config) => server_config.settings,
+                Err(_) => continue,
+            };
+            if previous.get(&id) == Some(&settings) {
+                continue;
+            }
+
+            if let Err(err) = crate::lsp::workspace::did_change_configuration(&ctx, &id) {
+                log::error!("could not send new settings to {}: {}", id, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // returns whether any of the servers for the given language id is running.
+    fn is_running(&self, language_id: &str) -> bool {
         self.servers
-            .write()
-            .insert(language_id.into(), client.clone());
+            .read()
+            .keys()
+            .any(|id| id.language_id == language_id)
+    }
+
+    // spawns the server and supervises it, restart_count being the number of consecutive times it
+    // has been restarted after crashing.
+    fn spawn_server(&self, id: &ServerID, restart_count: u32) -> Result<()> {
+        let config = self.config();
+        let server_config = crate::project::server_config(&self.vim, &config, &self.state, id)?;
+        let (reader, writer, mut child) = connect_server(&server_config)?;
+        let client = S::new(rpc::ClientID::LanguageServer(id.clone()), reader, writer);
+
+        self.state.write().stopping.remove(id);
+        self.servers.write().insert(id.clone(), client.clone());
 
         let rx = client.get_reader();
         let lc = self.clone();
+        let id = id.clone();
+        let started_at = Instant::now();
         std::thread::spawn(move || {
             for message in rx.iter() {
-                if let Err(err) = lc.handle_message(message) {
+                if let Err(err) = lc.handle_message(&id, message) {
                     log::error!("{}", err);
                 }
             }
+
+            // the reader only stops once the server has closed its output, so it is either dead
+            // or about to be.
+            let status = child.as_mut().map(|child| match child.try_wait() {
+                Ok(Some(status)) => Ok(status),
+                _ => child.kill().and_then(|_| child.wait()),
+            });
+
+            // a server that stayed up for a while is considered healthy again.
+            let restart_count = if started_at.elapsed() > STABLE_UPTIME {
+                0
+            } else {
+                restart_count
+            };
+
+            if let Err(err) = lc.handle_server_exit(&id, status, restart_count) {
+                log::error!("{}", err);
+            }
         });
 
         Ok(())
     }
 
+    // cleans up after a server process has exited and restarts it if it crashed.
+    fn handle_server_exit(
+        &self,
+        id: &ServerID,
+        status: Option<std::io::Result<ExitStatus>>,
+        restart_count: u32,
+    ) -> Result<()> {
+        self.servers.write().remove(id);
+        let mut state = self.state.write();
+        state.server_capabilities.remove(id);
+        state.document_sync.remove(id);
+        state.features.remove(id);
+        state.registrations.remove(id);
+        self.watcher.unwatch(id);
+        self.changes.forget(id);
+        for diagnostics in state.diagnostics.values_mut() {
+            diagnostics.remove(id);
+        }
+        let stopping = state.stopping.remove(id);
+        drop(state);
+
+        let ctx = Context::for_document(self, &id.language_id, 0, "", jsonrpc_core::Id::Null);
+        if stopping {
+            log::info!("{} server exited: {:?}", id, status);
+            return self.server_stopped(id);
+        }
+
+        log::error!("{} server crashed: {:?}", id, status);
+        let restart_attempts = ctx.server_config(id)?.restart_attempts;
+        if restart_count >= restart_attempts {
+            crate::vim::show_message(
+                &ctx,
+                crate::vim::Message {
+                    level: crate::vim::LogLevel::Error,
+                    message: format!("{} language server exited unexpectedly", id),
+                },
+            )?;
+            return self.server_stopped(id);
+        }
+
+        let backoff = restart_backoff(restart_count);
+        crate::vim::show_message(
+            &ctx,
+            crate::vim::Message {
+                level: crate::vim::LogLevel::Warning,
+                message: format!(
+                    "{} language server exited unexpectedly, restarting in {:?}",
+                    id, backoff
+                ),
+            },
+        )?;
+
+        std::thread::sleep(backoff);
+        if let Err(err) = self.spawn_server(id, restart_count + 1) {
+            log::error!("could not restart {} server: {}", id, err);
+            crate::vim::show_message(
+                &ctx,
+                crate::vim::Message {
+                    level: crate::vim::LogLevel::Error,
+                    message: format!("could not restart {} language server", id),
+                },
+            )?;
+            return self.server_stopped(id);
+        }
+
+        // if the new process dies while being initialized its own supervisor deals with it.
+        if let Err(err) = self.reinitialize_server(id) {
+            log::error!("could not initialize restarted {} server: {}", id, err);
+        }
+
+        Ok(())
+    }
+
+    // initializes a restarted server and opens every document it was tracking.
+    fn reinitialize_server(&self, id: &ServerID) -> Result<()> {
+        let config = self.config();
+        let server_config = config.server(&id.language_id, &id.name)?;
+        let roots = self.state.read().roots_for(id);
+        let documents: Vec<_> = self
+            .state
+            .read()
+            .text_documents
+            .iter()
+            .filter(|(_, doc)| doc.language_id == id.language_id)
+            .map(|(filename, doc)| (filename.clone(), doc.text()))
+            .collect();
+        let documents: Vec<_> = documents
+            .into_iter()
+            .filter(|(filename, _)| {
+                self.root_for(&id.language_id, server_config, filename)
+                    .is_some_and(|root| roots.contains(&root))
+            })
+            .collect();
+
+        let ctx = Context::for_document(self, &id.language_id, 0, "", jsonrpc_core::Id::Null)
+            .with_server(id);
+        crate::lsp::initialize(&ctx)?;
+
+        for (filename, text) in documents {
+            crate::lsp::text_document::did_open(
+                &ctx,
+                crate::vim::TextDocumentContent {
+                    filename,
+                    text,
+                    language_id: id.language_id.clone(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // called once a server is gone for good, vim is only told about it when it was the last one
+    // running for its language id.
+    fn server_stopped(&self, id: &ServerID) -> Result<()> {
+        self.state.write().workspace_folders.remove(id);
+        if self.is_running(&id.language_id) {
+            return Ok(());
+        }
+
+        self.set_server_stopped(&id.language_id)
+    }
+
+    // lets vim know that the server is no longer running, so that it is started again the next
+    // time a document for it is opened.
+    pub fn set_server_stopped(&self, language_id: &str) -> Result<()> {
+        self.vim
+            .notify("vlc#set_server_stopped", serde_json::json!([language_id]))
+    }
+
     /// spins up the readers for both vim and language server messages.
-    pub fn run(&self) -> () {
+    pub fn run(&self) {
+        let lc = self.clone();
+        std::thread::spawn(move || lc.send_file_events());
+        let lc = self.clone();
+        std::thread::spawn(move || lc.send_pending_changes());
+
+        let dispatcher = Dispatcher::new(WORKERS, LANES);
         let rx = self.vim.get_reader();
         for msg in rx.iter() {
             let lc = self.clone();
-            if let Err(err) = lc.handle_vim_message(msg) {
-                log::error!("error: {:?}", err);
+            let key = document_key(&msg);
+            match &msg {
+                // lifecycle calls change which servers are running, so everything before them
+                // must be done and everything after them must see their effects.
+                Message::MethodCall(call) if LIFECYCLE_METHODS.contains(&call.method.as_str()) => {
+                    dispatcher.wait_ordered();
+                    lc.process_vim_message(msg);
+                }
+                Message::MethodCall(_) => {
+                    dispatcher.dispatch(&key, Box::new(move || lc.process_vim_message(msg)))
+                }
+                _ => {
+                    dispatcher.dispatch_ordered(&key, Box::new(move || lc.process_vim_message(msg)))
+                }
+            }
+        }
+    }
+
+    // sends every server the changes to the files it asked to be told about.
+    fn send_file_events(&self) {
+        for (id, changes) in self.file_events.iter() {
+            let ctx = Context::for_document(self, &id.language_id, 0, "", jsonrpc_core::Id::Null)
+                .with_server(&id);
+            if let Err(err) = crate::lsp::workspace::did_change_watched_files(&ctx, &id, changes) {
+                log::error!("could not send file changes to {}: {}", id, err);
+            }
+        }
+    }
+
+    // sends the changes to documents to the servers once they have waited for long enough.
+    fn send_pending_changes(&self) {
+        loop {
+            let next = self
+                .changes
+                .flush_due(|id, params| match self.servers.read().get(id) {
+                    Some(server) => server.notify(DidChangeTextDocument::METHOD, params),
+                    None => Ok(()),
+                });
+            let timer = match next {
+                Some(deadline) => crossbeam::channel::at(deadline),
+                None => crossbeam::channel::never(),
+            };
+
+            crossbeam::select! {
+                recv(self.changes_queued) -> queued => {
+                    if queued.is_err() {
+                        return;
+                    }
+                },
+                recv(timer) -> _ => {},
+            }
+        }
+    }
+
+    fn process_vim_message(&self, msg: Message) {
+        let message_id = match &msg {
+            Message::MethodCall(msg) => Some(msg.id.clone()),
+            _ => None,
+        };
+
+        if let Err(err) = self.handle_vim_message(msg) {
+            log::error!("error: {:?}", err);
+            // vim is waiting on a response for method calls, so let it know that it failed.
+            if let Some(message_id) = message_id {
+                if let Err(err) = self.vim.reply_failure(&message_id, rpc::to_rpc_error(&err)) {
+                    log::error!("error replying to vim: {:?}", err);
+                }
+            }
+        }
+    }
+}
+
+// starts the server process if needed and connects to it using the configured transport. The
+// child process is returned so that it can be supervised, if one was spawned.
+fn connect_server(server_config: &ServerConfig) -> Result<(Reader, Writer, Option<Child>)> {
+    if server_config.transport == Transport::Stdio {
+        let mut cmd = spawn_command(server_config, Stdio::piped(), Stdio::piped())?;
+        let reader: Reader = Box::new(BufReader::new(cmd.stdout.take().unwrap()));
+        let writer: Writer = Box::new(cmd.stdin.take().unwrap());
+        return Ok((reader, writer, Some(cmd)));
+    }
+
+    // servers listening on a socket may already be running, in which case there is nothing to
+    // spawn.
+    let child = if server_config.command.is_empty() {
+        None
+    } else {
+        Some(spawn_command(server_config, Stdio::null(), Stdio::null())?)
+    };
+
+    match server_config.transport.connect() {
+        Ok((reader, writer)) => Ok((reader, writer, child)),
+        Err(err) => {
+            // the server would otherwise be left running with nothing connected to it, and another
+            // one would be spawned on every restart.
+            if let Some(mut child) = child {
+                let _ = child.kill();
+                let _ = child.wait();
             }
+            Err(err)
         }
     }
 }
+
+// runs the server command with the configured arguments, environment and working directory.
+// Stderr is discarded unless a file to log it to is configured.
+fn spawn_command(server_config: &ServerConfig, stdin: Stdio, stdout: Stdio) -> Result<Child> {
+    if server_config.command.is_empty() {
+        anyhow::bail!("no command configured for server {}", server_config.name);
+    }
+
+    let stderr: Stdio = match &server_config.stderr_log {
+        Some(path) => std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(shellexpand::tilde(path).as_ref())
+            .map_err(|err| anyhow::anyhow!("could not open stderr log {}: {}", path, err))?
+            .into(),
+        None => Stdio::null(),
+    };
+
+    let mut cmd = Command::new(shellexpand::tilde(&server_config.command).as_ref());
+    cmd.args(&server_config.args)
+        .envs(&server_config.env)
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(stderr);
+    if let Some(cwd) = &server_config.cwd {
+        cmd.current_dir(shellexpand::tilde(cwd).as_ref());
+    }
+
+    let child = cmd
+        .spawn()
+        .map_err(|err| anyhow::anyhow!("could not run {}: {}", server_config.command, err))?;
+    Ok(child)
+}
+
+// messages for the same text document are dispatched in order, so they are keyed by file name.
+fn document_key(message: &Message) -> String {
+    let params = match message {
+        Message::MethodCall(msg) => &msg.params,
+        Message::Notification(msg) => &msg.params,
+        Message::Output(_) => return String::new(),
+    };
+
+    Into::<Value>::into(params.clone())
+        .get("filename")
+        .and_then(|f| f.as_str())
+        .unwrap_or_default()
+        .to_owned()
+}
+
+// returns how long to wait before restarting a server that has already been restarted the given
+// number of times in a row.
+fn restart_backoff(restart_count: u32) -> Duration {
+    RESTART_BACKOFF
+        .saturating_mul(2u32.saturating_pow(restart_count))
+        .min(MAX_RESTART_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::FakeClient;
+    use lsp_types::request::Request;
+
+    #[test]
+    fn restart_backoff_doubles_up_to_the_maximum() {
+        let backoffs: Vec<u64> = (0..8).map(|n| restart_backoff(n).as_secs()).collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 16, 30, 30, 30]);
+    }
+
+    #[test]
+    fn restart_backoff_does_not_overflow() {
+        assert_eq!(restart_backoff(32), MAX_RESTART_BACKOFF);
+        assert_eq!(restart_backoff(u32::MAX), MAX_RESTART_BACKOFF);
+    }
+
+    fn server(name: &str) -> ServerID {
+        ServerID::new("rust", name, "/")
+    }
+
+    fn context(servers: Vec<(ServerID, FakeClient)>) -> Context<FakeClient, FakeClient> {
+        Context::fake(FakeClient::default(), servers, State::default(), "/a.rs")
+    }
+
+    #[test]
+    fn call_returns_the_first_answer_in_priority_order() {
+        let method = lsp_types::request::HoverRequest::METHOD;
+        let null = FakeClient::with_response(method, Value::Null);
+        let first = FakeClient::with_response(method, serde_json::json!("first"));
+        let second = FakeClient::with_response(method, serde_json::json!("second"));
+        let ctx = context(vec![
+            (server("null"), null.clone()),
+            (server("first"), first.clone()),
+            (server("second"), second.clone()),
+        ]);
+
+        let res: String = ctx.call(method, Value::Null).unwrap();
+        assert_eq!(res, "first");
+        assert_eq!(null.called(method).len(), 1);
+        assert!(second.called(method).is_empty());
+    }
+
+    #[test]
+    fn call_skips_servers_that_do_not_handle_the_method() {
+        let method = lsp_types::request::HoverRequest::METHOD;
+        let first = FakeClient::with_response(method, serde_json::json!("first"));
+        let second = FakeClient::with_response(method, serde_json::json!("second"));
+        let ctx = context(vec![
+            (server("first"), first.clone()),
+            (server("second"), second),
+        ]);
+        ctx.state
+            .write()
+            .server_capabilities
+            .insert(server("first"), Default::default());
+
+        let res: String = ctx.call(method, Value::Null).unwrap();
+        assert_eq!(res, "second");
+        assert!(first.called(method).is_empty());
+    }
+
+    #[test]
+    fn call_fails_when_no_server_answers() {
+        let method = lsp_types::request::HoverRequest::METHOD;
+        let ctx = context(vec![(server("a"), FakeClient::default())]);
+        assert!(ctx.call::<_, Value>(method, Value::Null).is_err());
+
+        // null answers are not failures.
+        let null = FakeClient::with_response(method, Value::Null);
+        let ctx = context(vec![
+            (server("a"), FakeClient::default()),
+            (server("null"), null),
+        ]);
+        assert_eq!(
+            ctx.call::<_, Value>(method, Value::Null).unwrap(),
+            Value::Null
+        );
+
+        let ctx = context(vec![]);
+        assert!(ctx.call::<_, Value>(method, Value::Null).is_err());
+    }
+
+    // creates a cargo project under a new directory for the test, returning the directory.
+    fn project(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("vlc-lc-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), "").unwrap();
+        std::fs::write(dir.join("src/main.rs"), "").unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn language_client(single_file_mode: bool) -> LanguageClient<FakeClient, FakeClient> {
+        let server_config = ServerConfig {
+            name: "rls".into(),
+            root_patterns: vec!["Cargo.toml".into()],
+            single_file_mode,
+            ..Default::default()
+        };
+        let mut config = Config::default();
+        config.servers.insert("rust".into(), vec![server_config]);
+        LanguageClient::new(FakeClient::default(), config)
+    }
+
+    // adds an initialized instance of the server for the root, which handles more than one
+    // workspace folder if multi_root is set.
+    fn run(
+        lc: &LanguageClient<FakeClient, FakeClient>,
+        root: &str,
+        multi_root: bool,
+    ) -> (ServerID, FakeClient) {
+        let id = ServerID::new("rust", "rls", root);
+        let server = FakeClient::default();
+        let capabilities = serde_json::json!({
+            "workspace": {
+                "workspaceFolders": {"supported": multi_root, "changeNotifications": multi_root},
+            },
+        });
+        lc.servers.write().insert(id.clone(), server.clone());
+        lc.state
+            .write()
+            .server_capabilities
+            .insert(id.clone(), serde_json::from_value(capabilities).unwrap());
+        (id, server)
+    }
+
+    fn document(
+        lc: &LanguageClient<FakeClient, FakeClient>,
+        filename: &str,
+    ) -> Context<FakeClient, FakeClient> {
+        Context::for_document(lc, "rust", 1, filename, jsonrpc_core::Id::Null)
+    }
+
+    fn server_ids(ctx: &Context<FakeClient, FakeClient>) -> Vec<ServerID> {
+        ctx.servers.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    #[test]
+    fn multi_root_instance_handles_new_roots() {
+        let lc = language_client(true);
+        let (a, _) = run(&lc, "/a", true);
+        run(&lc, "/b", false);
+
+        // only an instance that handles workspace folder changes can take another root.
+        let c = ServerID::new("rust", "rls", "/c");
+        assert_eq!(lc.multi_root_instance(&c), Some(a.clone()));
+
+        // an instance that handles the root already is not given it again.
+        lc.state
+            .write()
+            .workspace_folders
+            .insert(a.clone(), vec!["/c".into()]);
+        assert_eq!(lc.multi_root_instance(&c), None);
+        assert_eq!(lc.multi_root_instance(&a), None);
+
+        // other servers don't count.
+        let other = ServerID::new("rust", "analyzer", "/d");
+        assert_eq!(lc.multi_root_instance(&other), None);
+    }
+
+    #[test]
+    fn multi_root_instance_needs_initialized_servers() {
+        let lc = language_client(true);
+        let (a, _) = run(&lc, "/a", true);
+        lc.state.write().server_capabilities.remove(&a);
+        let b = ServerID::new("rust", "rls", "/b");
+        assert_eq!(lc.multi_root_instance(&b), None);
+    }
+
+    #[test]
+    fn start_root_reuses_the_instance_for_the_root() {
+        let root = project("reuse");
+        let lc = language_client(true);
+        let (id, _) = run(&lc, &root, false);
+        run(&lc, "/elsewhere", false);
+
+        let ctx = lc
+            .start_root(document(&lc, &format!("{}/src/main.rs", root)))
+            .unwrap();
+        assert_eq!(server_ids(&ctx), vec![id]);
+        assert_eq!(lc.servers.read().len(), 2);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn start_root_nested_roots() {
+        let outer = project("nested");
+        let inner = format!("{}/inner", outer);
+        std::fs::create_dir_all(format!("{}/src", inner)).unwrap();
+        std::fs::write(format!("{}/Cargo.toml", inner), "").unwrap();
+        let lc = language_client(true);
+        let (outer_id, _) = run(&lc, &outer, false);
+        let (inner_id, _) = run(&lc, &inner, false);
+
+        // documents go to the instance for the closest root.
+        let ctx = lc
+            .start_root(document(&lc, &format!("{}/src/lib.rs", inner)))
+            .unwrap();
+        assert_eq!(server_ids(&ctx), vec![inner_id]);
+        let ctx = lc
+            .start_root(document(&lc, &format!("{}/src/main.rs", outer)))
+            .unwrap();
+        assert_eq!(server_ids(&ctx), vec![outer_id]);
+        std::fs::remove_dir_all(&outer).unwrap();
+    }
+
+    #[test]
+    fn start_root_adds_the_root_to_a_multi_root_instance() {
+        let (first, second) = (project("multi-first"), project("multi-second"));
+        let lc = language_client(true);
+        let (id, server) = run(&lc, &first, true);
+
+        let ctx = lc
+            .start_root(document(&lc, &format!("{}/src/main.rs", second)))
+            .unwrap();
+        assert_eq!(server_ids(&ctx), vec![id.clone()]);
+        assert_eq!(lc.servers.read().len(), 1);
+        assert_eq!(
+            lc.state.read().roots_for(&id),
+            vec![first.clone(), second.clone()]
+        );
+        let added = server.notified(DidChangeWorkspaceFolders::METHOD);
+        assert_eq!(added.len(), 1);
+        let uri = lsp_types::Url::from_directory_path(&second).unwrap();
+        assert_eq!(added[0]["event"]["added"][0]["uri"], uri.as_str());
+        std::fs::remove_dir_all(&first).unwrap();
+        std::fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[test]
+    fn start_root_without_a_workspace() {
+        let root = project("no-workspace");
+        let scripts = std::env::temp_dir().join(format!("vlc-lc-{}-scripts", std::process::id()));
+        let scripts = scripts.to_string_lossy().into_owned();
+        std::fs::create_dir_all(&scripts).unwrap();
+        let file = format!("{}/main.rs", scripts);
+
+        // servers that don't handle single files are not started for documents outside of any
+        // workspace.
+        let lc = language_client(false);
+        let (_, server) = run(&lc, &root, true);
+        lc.start_root(document(&lc, &file)).unwrap();
+        assert_eq!(lc.servers.read().len(), 1);
+        assert!(server
+            .notified(DidChangeWorkspaceFolders::METHOD)
+            .is_empty());
+
+        // the ones that do get the directory of the document as a root.
+        let lc = language_client(true);
+        let (id, server) = run(&lc, &root, true);
+        let ctx = lc.start_root(document(&lc, &file)).unwrap();
+        assert_eq!(server_ids(&ctx), vec![id.clone()]);
+        assert_eq!(
+            lc.state.read().roots_for(&id),
+            vec![root.clone(), scripts.clone()]
+        );
+        assert_eq!(server.notified(DidChangeWorkspaceFolders::METHOD).len(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&scripts).unwrap();
+    }
+
+    #[test]
+    fn start_root_starts_another_instance_for_single_root_servers() {
+        let (first, second) = (project("single-first"), project("single-second"));
+        let lc = language_client(true);
+        lc.config.write().servers.get_mut("rust").unwrap()[0].command =
+            "vlc-test-no-such-server".into();
+        let (id, server) = run(&lc, &first, false);
+
+        // the new root is not added to the running instance, another one is started for it, which
+        // fails here as there is no such server.
+        lc.start_root(document(&lc, &format!("{}/src/main.rs", second)))
+            .unwrap();
+        assert!(server
+            .notified(DidChangeWorkspaceFolders::METHOD)
+            .is_empty());
+        assert_eq!(lc.state.read().roots_for(&id), vec![first.clone()]);
+        let warnings = lc.vim.notified("vlc#show_message");
+        assert_eq!(warnings.len(), 1);
+        std::fs::remove_dir_all(&first).unwrap();
+        std::fs::remove_dir_all(&second).unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+use lsp_types::{
+    notification::{self, Notification},
+    request::{self, Request},
+    CodeActionProviderCapability, DocumentSelector, HoverProviderCapability,
+    ImplementationProviderCapability, OneOf, Registration, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+};
+
+/// The document a request is made for. Capabilities registered dynamically can be limited to some
+/// documents with a document selector.
+#[derive(Debug, Clone, Copy)]
+pub struct Document<'a> {
+    pub language_id: &'a str,
+    pub filename: &'a str,
+}
+
+/// returns whether a server with the given capabilities handles the method. Methods that are not
+/// tied to a capability are assumed to be handled.
+pub fn supports(caps: &ServerCapabilities, method: &str) -> bool {
+    match method {
+        request::HoverRequest::METHOD => match &caps.hover_provider {
+            Some(HoverProviderCapability::Simple(enabled)) => *enabled,
+            Some(HoverProviderCapability::Options(_)) => true,
+            None => false,
+        },
+        request::GotoDefinition::METHOD => one_of(&caps.definition_provider),
+        request::GotoImplementation::METHOD => match &caps.implementation_provider {
+            Some(ImplementationProviderCapability::Simple(enabled)) => *enabled,
+            Some(_) => true,
+            None => false,
+        },
+        request::References::METHOD => one_of(&caps.references_provider),
+        request::Rename::METHOD => one_of(&caps.rename_provider),
+        request::Formatting::METHOD => one_of(&caps.document_formatting_provider),
+        request::CodeActionRequest::METHOD => match &caps.code_action_provider {
+            Some(CodeActionProviderCapability::Simple(enabled)) => *enabled,
+            Some(CodeActionProviderCapability::Options(_)) => true,
+            None => false,
+        },
+        request::CodeLensRequest::METHOD => caps.code_lens_provider.is_some(),
+        request::CodeLensResolve::METHOD => caps
+            .code_lens_provider
+            .as_ref()
+            .and_then(|opts| opts.resolve_provider)
+            .unwrap_or_default(),
+        request::Completion::METHOD => caps.completion_provider.is_some(),
+        request::ResolveCompletionItem::METHOD => caps
+            .completion_provider
+            .as_ref()
+            .and_then(|opts| opts.resolve_provider)
+            .unwrap_or_default(),
+        request::ExecuteCommand::METHOD => caps.execute_command_provider.is_some(),
+        notification::DidChangeWorkspaceFolders::METHOD => supports_workspace_folder_changes(caps),
+        notification::DidChangeTextDocument::METHOD => {
+            sync_kind(caps) != TextDocumentSyncKind::None
+        }
+        notification::WillSaveTextDocument::METHOD => sync_options(caps)
+            .and_then(|opts| opts.will_save)
+            .unwrap_or_default(),
+        request::WillSaveWaitUntil::METHOD => sync_options(caps)
+            .and_then(|opts| opts.will_save_wait_until)
+            .unwrap_or_default(),
+        _ => true,
+    }
+}
+
+/// returns whether any of the capabilities a server registered dynamically handles the method for
+/// the document, if the request is made for one. Registrations with a document selector only
+/// apply to the documents it selects.
+pub fn supports_registered(
+    registrations: &[Registration],
+    method: &str,
+    document: Option<Document>,
+) -> bool {
+    let registrations: Vec<&Registration> = registrations
+        .iter()
+        .filter(|r| document.map_or(true, |document| selects(r, document)))
+        .collect();
+    match method {
+        request::CodeLensResolve::METHOD => registered_option(
+            &registrations,
+            request::CodeLensRequest::METHOD,
+            "resolveProvider",
+        ),
+        request::ResolveCompletionItem::METHOD => registered_option(
+            &registrations,
+            request::Completion::METHOD,
+            "resolveProvider",
+        ),
+        _ => registrations.iter().any(|r| r.method == method),
+    }
+}
+
+// returns whether the registration applies to the document, which it does if it has no document
+// selector or the document matches any of its filters.
+fn selects(registration: &Registration, document: Document) -> bool {
+    let selector = match registration
+        .register_options
+        .as_ref()
+        .and_then(|options| options.get("documentSelector"))
+    {
+        Some(selector) if !selector.is_null() => selector,
+        _ => return true,
+    };
+
+    let selector: DocumentSelector = match serde_json::from_value(selector.clone()) {
+        Ok(selector) => selector,
+        Err(err) => {
+            log::warn!(
+                "invalid document selector in {}: {}",
+                registration.method,
+                err
+            );
+            return true;
+        }
+    };
+
+    // documents are only ever files.
+    selector.iter().any(|filter| {
+        filter
+            .language
+            .as_ref()
+            .map_or(true, |language| language == document.language_id)
+            && filter
+                .scheme
+                .as_ref()
+                .map_or(true, |scheme| scheme == "file")
+            && filter.pattern.as_ref().map_or(true, |pattern| {
+                crate::glob::matches(pattern, document.filename)
+            })
+    })
+}
+
+/// returns whether a server registered the command dynamically.
+pub fn registered_command(registrations: &[Registration], command: &str) -> bool {
+    registrations
+        .iter()
+        .filter(|r| r.method == request::ExecuteCommand::METHOD)
+        .filter_map(|r| r.register_options.as_ref()?.get("commands")?.as_array())
+        .flatten()
+        .any(|c| c.as_str() == Some(command))
+}
+
+// returns whether the method was registered with the given boolean option enabled.
+fn registered_option(registrations: &[&Registration], method: &str, option: &str) -> bool {
+    registrations
+        .iter()
+        .filter(|r| r.method == method)
+        .filter_map(|r| r.register_options.as_ref()?.get(option)?.as_bool())
+        .any(|enabled| enabled)
+}
+
+/// returns whether a server with the given capabilities can run the command.
+pub fn supports_command(caps: &ServerCapabilities, command: &str) -> bool {
+    caps.execute_command_provider
+        .as_ref()
+        .map(|opts| opts.commands.iter().any(|c| c == command))
+        .unwrap_or_default()
+}
+
+/// returns whether a server with the given capabilities wants to be told about workspace folders
+/// being added or removed.
+pub fn supports_workspace_folder_changes(caps: &ServerCapabilities) -> bool {
+    let change_notifications = caps
+        .workspace
+        .as_ref()
+        .and_then(|workspace| workspace.workspace_folders.as_ref())
+        .and_then(|folders| folders.change_notifications.as_ref());
+    match change_notifications {
+        Some(OneOf::Left(enabled)) => *enabled,
+        // a registration id for the notification, meaning the server wants them.
+        Some(OneOf::Right(_)) => true,
+        None => false,
+    }
+}
+
+/// returns how a server with the given capabilities wants to be told about changes to documents.
+/// Servers that don't say are not told about them at all.
+pub fn sync_kind(caps: &ServerCapabilities) -> TextDocumentSyncKind {
+    match &caps.text_document_sync {
+        Some(TextDocumentSyncCapability::Kind(kind)) => *kind,
+        Some(TextDocumentSyncCapability::Options(opts)) => {
+            opts.change.unwrap_or(TextDocumentSyncKind::None)
+        }
+        None => TextDocumentSyncKind::None,
+    }
+}
+
+// returns the options a server gave for keeping documents in sync, if it gave any beyond the kind
+// of changes it wants.
+fn sync_options(caps: &ServerCapabilities) -> Option<&TextDocumentSyncOptions> {
+    match &caps.text_document_sync {
+        Some(TextDocumentSyncCapability::Options(opts)) => Some(opts),
+        _ => None,
+    }
+}
+
+fn one_of<T>(capability: &Option<OneOf<bool, T>>) -> bool {
+    match capability {
+        Some(OneOf::Left(enabled)) => *enabled,
+        Some(OneOf::Right(_)) => true,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn registration(id: &str, method: &str, options: serde_json::Value) -> Registration {
+        Registration {
+            id: id.into(),
+            method: method.into(),
+            register_options: Some(options),
+        }
+    }
+
+    const RUST: Document = Document {
+        language_id: "rust",
+        filename: "/project/src/main.rs",
+    };
+
+    const TOML: Document = Document {
+        language_id: "toml",
+        filename: "/project/Cargo.toml",
+    };
+
+    #[test]
+    fn test_supports_registered() {
+        let registrations = vec![
+            registration("1", "textDocument/hover", json!({})),
+            registration(
+                "2",
+                "textDocument/completion",
+                json!({"resolveProvider": true}),
+            ),
+        ];
+        assert!(supports_registered(
+            &registrations,
+            "textDocument/hover",
+            None
+        ));
+        assert!(supports_registered(
+            &registrations,
+            "textDocument/completion",
+            Some(RUST)
+        ));
+        assert!(supports_registered(
+            &registrations,
+            "completionItem/resolve",
+            Some(RUST)
+        ));
+        assert!(!supports_registered(
+            &registrations,
+            "codeLens/resolve",
+            None
+        ));
+        assert!(!supports_registered(
+            &registrations,
+            "textDocument/rename",
+            None
+        ));
+        assert!(!supports_registered(&[], "textDocument/hover", None));
+
+        let registrations = vec![registration("1", "textDocument/completion", json!({}))];
+        assert!(!supports_registered(
+            &registrations,
+            "completionItem/resolve",
+            None
+        ));
+    }
+
+    #[test]
+    fn test_supports_registered_document_selector() {
+        let registrations = vec![
+            registration(
+                "1",
+                "textDocument/hover",
+                json!({"documentSelector": [{"language": "rust"}]}),
+            ),
+            registration(
+                "2",
+                "textDocument/formatting",
+                json!({"documentSelector": [{"pattern": "**/Cargo.toml"}, {"scheme": "untitled"}]}),
+            ),
+            registration(
+                "3",
+                "textDocument/rename",
+                json!({"documentSelector": null}),
+            ),
+            registration(
+                "4",
+                "textDocument/codeLens",
+                json!({"documentSelector": [{"language": "rust"}], "resolveProvider": true}),
+            ),
+        ];
+        assert!(supports_registered(
+            &registrations,
+            "textDocument/hover",
+            Some(RUST)
+        ));
+        assert!(!supports_registered(
+            &registrations,
+            "textDocument/hover",
+            Some(TOML)
+        ));
+        assert!(!supports_registered(
+            &registrations,
+            "textDocument/formatting",
+            Some(RUST)
+        ));
+        assert!(supports_registered(
+            &registrations,
+            "textDocument/formatting",
+            Some(TOML)
+        ));
+        assert!(supports_registered(
+            &registrations,
+            "textDocument/rename",
+            Some(TOML)
+        ));
+        assert!(supports_registered(
+            &registrations,
+            "codeLens/resolve",
+            Some(RUST)
+        ));
+        assert!(!supports_registered(
+            &registrations,
+            "codeLens/resolve",
+            Some(TOML)
+        ));
+
+        // requests that are not made for a document are handled whatever the selector.
+        assert!(supports_registered(
+            &registrations,
+            "textDocument/hover",
+            None
+        ));
+    }
+
+    #[test]
+    fn test_registered_command() {
+        let registrations = vec![
+            registration(
+                "1",
+                "textDocument/hover",
+                json!({"commands": ["hover.run"]}),
+            ),
+            registration(
+                "2",
+                "workspace/executeCommand",
+                json!({"commands": ["rust-analyzer.runSingle", "rust-analyzer.debugSingle"]}),
+            ),
+        ];
+        assert!(registered_command(
+            &registrations,
+            "rust-analyzer.runSingle"
+        ));
+        assert!(registered_command(
+            &registrations,
+            "rust-analyzer.debugSingle"
+        ));
+        assert!(!registered_command(&registrations, "hover.run"));
+        assert!(!registered_command(
+            &registrations,
+            "rust-analyzer.showReferences"
+        ));
+    }
+
+    #[test]
+    fn test_supports_command() {
+        let caps: ServerCapabilities = serde_json::from_value(json!({
+            "executeCommandProvider": {"commands": ["rust-analyzer.runSingle"]},
+        }))
+        .unwrap();
+        assert!(supports_command(&caps, "rust-analyzer.runSingle"));
+        assert!(!supports_command(&caps, "rust-analyzer.debugSingle"));
+        assert!(!supports_command(
+            &ServerCapabilities::default(),
+            "rust-analyzer.runSingle"
+        ));
+    }
+}
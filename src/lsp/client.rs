@@ -0,0 +1,167 @@
+use crate::language_client::Context;
+use crate::rpc::RPCClient;
+use anyhow::Result;
+use lsp_types::notification::{DidChangeWatchedFiles, Notification};
+use lsp_types::{RegistrationParams, UnregistrationParams};
+
+pub fn register_capability<C, S>(ctx: &Context<C, S>, params: RegistrationParams) -> Result<()>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let (id, _) = ctx
+        .servers
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no server running for filetype {}", ctx.language_id))?;
+
+    let watches_files = params
+        .registrations
+        .iter()
+        .any(|r| r.method == DidChangeWatchedFiles::METHOD);
+    let mut state = ctx.state.write();
+    let registrations = state.registrations.entry(id.clone()).or_default();
+    for registration in params.registrations {
+        log::debug!("{} registered {}", id, registration.method);
+        // registering an id again replaces what was registered under it.
+        registrations.retain(|r| r.id != registration.id);
+        registrations.push(registration);
+    }
+    drop(state);
+
+    if watches_files {
+        crate::lsp::workspace::watch_files(ctx, id);
+    }
+
+    Ok(())
+}
+
+pub fn unregister_capability<C, S>(ctx: &Context<C, S>, params: UnregistrationParams) -> Result<()>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let (id, _) = ctx
+        .servers
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no server running for filetype {}", ctx.language_id))?;
+
+    let watches_files = params
+        .unregisterations
+        .iter()
+        .any(|r| r.method == DidChangeWatchedFiles::METHOD);
+    let mut state = ctx.state.write();
+    if let Some(registrations) = state.registrations.get_mut(id) {
+        for unregistration in params.unregisterations {
+            log::debug!("{} unregistered {}", id, unregistration.method);
+            registrations.retain(|r| r.id != unregistration.id);
+        }
+    }
+    drop(state);
+
+    if watches_files {
+        crate::lsp::workspace::watch_files(ctx, id);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_client::Context;
+    use crate::lsp::capabilities::Document;
+    use crate::rpc::{FakeClient, ServerID};
+    use crate::state::State;
+    use lsp_types::request::{Formatting, Request};
+    use serde_json::json;
+
+    fn context() -> (Context<FakeClient, FakeClient>, ServerID) {
+        let id = ServerID::new("rust", "rust-analyzer", "/project");
+        let mut state = State::default();
+        state
+            .server_capabilities
+            .insert(id.clone(), Default::default());
+        let servers = vec![(id.clone(), FakeClient::default())];
+        let ctx = Context::fake(
+            FakeClient::default(),
+            servers,
+            state,
+            "/project/src/main.rs",
+        );
+        (ctx, id)
+    }
+
+    fn register(ctx: &Context<FakeClient, FakeClient>, registrations: serde_json::Value) {
+        let params = serde_json::from_value(json!({ "registrations": registrations })).unwrap();
+        register_capability(ctx, params).unwrap();
+    }
+
+    fn unregister(ctx: &Context<FakeClient, FakeClient>, id: &str, method: &str) {
+        let params = serde_json::from_value(json!({
+            "unregisterations": [{"id": id, "method": method}],
+        }))
+        .unwrap();
+        unregister_capability(ctx, params).unwrap();
+    }
+
+    #[test]
+    fn test_register_and_unregister_capability() {
+        let (ctx, id) = context();
+        let rust = Some(Document {
+            language_id: "rust",
+            filename: "/project/src/main.rs",
+        });
+        assert!(!ctx.state.read().supports(&id, Formatting::METHOD, rust));
+
+        register(
+            &ctx,
+            json!([{
+                "id": "1",
+                "method": "textDocument/formatting",
+                "registerOptions": {"documentSelector": [{"language": "toml"}]},
+            }]),
+        );
+        assert!(!ctx.state.read().supports(&id, Formatting::METHOD, rust));
+        assert_eq!(ctx.servers_for(Formatting::METHOD).len(), 0);
+
+        // registering the same id again replaces the registration.
+        register(
+            &ctx,
+            json!([{
+                "id": "1",
+                "method": "textDocument/formatting",
+                "registerOptions": {"documentSelector": [{"language": "rust"}]},
+            }]),
+        );
+        assert!(ctx.state.read().supports(&id, Formatting::METHOD, rust));
+        assert_eq!(ctx.servers_for(Formatting::METHOD).len(), 1);
+        assert_eq!(ctx.state.read().registrations[&id].len(), 1);
+
+        unregister(&ctx, "1", "textDocument/formatting");
+        assert!(!ctx.state.read().supports(&id, Formatting::METHOD, rust));
+        assert!(ctx.state.read().registrations[&id].is_empty());
+    }
+
+    #[test]
+    fn test_register_command() {
+        let (ctx, id) = context();
+        register(
+            &ctx,
+            json!([{
+                "id": "commands",
+                "method": "workspace/executeCommand",
+                "registerOptions": {"commands": ["rust-analyzer.runSingle"]},
+            }]),
+        );
+        let state = ctx.state.read();
+        assert!(state.supports_command(&id, "rust-analyzer.runSingle"));
+        assert!(!state.supports_command(&id, "rust-analyzer.debugSingle"));
+        drop(state);
+
+        unregister(&ctx, "commands", "workspace/executeCommand");
+        assert!(!ctx
+            .state
+            .read()
+            .supports_command(&id, "rust-analyzer.runSingle"));
+    }
+}
@@ -1,11 +1,13 @@
 pub mod rust_analyzer;
 
 use crate::language_client::Context;
-use crate::rpc::RPCClient;
+use crate::rpc::{RPCClient, ServerID};
 use anyhow::Result;
 use lsp_types::*;
 
-pub fn run_command<C, S>(ctx: &Context<C, S>, cmd: &Command) -> Result<()>
+// runs the command locally if it is one of the extensions known to the client, or else has a server
+// execute it, which is the given one if the command came from it.
+pub fn run_command<C, S>(ctx: &Context<C, S>, cmd: &Command, id: Option<&ServerID>) -> Result<()>
 where
     C: RPCClient,
     S: RPCClient,
@@ -16,7 +18,7 @@ where
         }
         "rust-analyzer.showReferences" => rust_analyzer::show_references(ctx, &cmd.arguments)?,
         "rust-analyzer.run" | "rust-analyzer.runSingle" => rust_analyzer::run(ctx, &cmd.arguments)?,
-        _ => crate::lsp::workspace::execute_command(ctx, cmd)?,
+        _ => crate::lsp::workspace::execute_command(ctx, cmd, id)?,
     }
 
     Ok(())
@@ -91,7 +91,11 @@ where
         .cloned()
         .unwrap_or_else(|| Value::Array(vec![]));
     let locations: Vec<Location> = serde_json::from_value(locations)?;
-    let locations = locations.into_iter().map(|l| l.into()).collect();
+    let lines = crate::vim::FileLines::new(ctx);
+    let locations = locations
+        .into_iter()
+        .map(|l| crate::vim::Location::from_lsp(l, &lines))
+        .collect();
 
     crate::vim::show_locations(ctx, locations)?;
     Ok(())
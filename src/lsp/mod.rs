@@ -1,3 +1,5 @@
+pub mod capabilities;
+pub mod client;
 pub mod code_lens;
 pub mod extensions;
 pub mod text_document;
@@ -5,18 +7,24 @@ pub mod window;
 pub mod workspace;
 
 use std::path::Path;
+use std::time::Duration;
 
+use crate::config::ServerConfig;
 use crate::language_client::{Context, LanguageClient};
+use crate::lsp::workspace::workspace_folder;
 use crate::rpc;
-use crate::rpc::RPCClient;
+use crate::rpc::{RPCClient, ServerID};
+use crate::state::DocumentSync;
 use anyhow::Result;
 use lsp_types::{
     notification::{self, Notification},
     request::{self, Request},
     ClientCapabilities, ClientInfo, CodeActionClientCapabilities, CodeActionKind,
-    CodeActionKindLiteralSupport, CodeActionLiteralSupport, HoverClientCapabilities,
-    InitializeParams, InitializeResult, InitializedParams, TextDocumentClientCapabilities,
-    TraceOption, Url,
+    CodeActionKindLiteralSupport, CodeActionLiteralSupport, CompletionClientCapabilities,
+    DidChangeWatchedFilesClientCapabilities, DynamicRegistrationClientCapabilities, GotoCapability,
+    HoverClientCapabilities, InitializeParams, InitializeResult, InitializedParams,
+    PublishDiagnosticsClientCapabilities, RenameClientCapabilities, TextDocumentClientCapabilities,
+    TextDocumentSyncClientCapabilities, TraceOption, WorkspaceClientCapabilities,
 };
 
 impl<C, S> LanguageClient<C, S>
@@ -24,18 +32,27 @@ where
     C: RPCClient,
     S: RPCClient,
 {
-    // handles messages sent from vim to the language client
-    pub fn handle_message(&self, message: rpc::Message) -> Result<()> {
-        let ctx = Context::new(&message, self);
+    // handles messages sent from the given language server to the language client
+    pub fn handle_message(&self, id: &ServerID, message: rpc::Message) -> Result<()> {
+        let ctx = Context::for_document(self, &id.language_id, 0, "", message.id()).with_server(id);
         match message {
-            rpc::Message::MethodCall(msg) => match msg.method.as_str() {
-                "workspace/applyEdit" => {
-                    let params: lsp_types::ApplyWorkspaceEditParams =
-                        serde_json::from_value(msg.params.into())?;
-                    crate::lsp::workspace::apply_edit(&ctx, &params)?;
+            rpc::Message::MethodCall(msg) => {
+                let res = handle_request(&ctx, &msg.method, msg.params);
+                let server = self
+                    .servers
+                    .read()
+                    .get(id)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("server {} is not running", id))?;
+
+                match res {
+                    Ok(value) => server.reply_success(&msg.id, value)?,
+                    Err(err) => {
+                        log::debug!("error handling server method call {}: {}", msg.method, err);
+                        server.reply_failure(&msg.id, rpc::to_rpc_error(&err))?;
+                    }
                 }
-                _ => log::debug!("unhandled server method call {}", msg.method),
-            },
+            }
             rpc::Message::Notification(msg) => match msg.method.as_str() {
                 "window/logMessage" => {
                     let params: lsp_types::LogMessageParams =
@@ -66,33 +83,185 @@ where
     }
 }
 
-#[allow(deprecated)]
+// handles a method call from a language server, returning the result to reply with. Requests the
+// client has nothing to do for are answered with an empty result, as long as the specification
+// allows for it, and any other request is answered with a MethodNotFound error.
+fn handle_request<C, S>(
+    ctx: &Context<C, S>,
+    method: &str,
+    params: jsonrpc_core::Params,
+) -> Result<serde_json::Value>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let res = match method {
+        request::ApplyWorkspaceEdit::METHOD => {
+            let params: lsp_types::ApplyWorkspaceEditParams =
+                serde_json::from_value(params.into())?;
+            let res = crate::lsp::workspace::apply_edit(ctx, &params)?;
+            serde_json::to_value(res)?
+        }
+        // progress is reported through $/progress notifications, which are shown regardless of
+        // whether the token was created.
+        request::WorkDoneProgressCreate::METHOD => serde_json::Value::Null,
+        // no action is picked by the user, the message itself is sent as a notification.
+        request::ShowMessageRequest::METHOD => {
+            let params: lsp_types::ShowMessageRequestParams =
+                serde_json::from_value(params.into())?;
+            crate::lsp::window::show_message(
+                ctx,
+                lsp_types::ShowMessageParams {
+                    typ: params.typ,
+                    message: params.message,
+                },
+            )?;
+            serde_json::Value::Null
+        }
+        request::WorkspaceFoldersRequest::METHOD => {
+            serde_json::to_value(crate::lsp::workspace::workspace_folders(ctx)?)?
+        }
+        request::WorkspaceConfiguration::METHOD => {
+            let params: lsp_types::ConfigurationParams = serde_json::from_value(params.into())?;
+            serde_json::to_value(crate::lsp::workspace::configuration(ctx, params)?)?
+        }
+        request::RegisterCapability::METHOD => {
+            let params: lsp_types::RegistrationParams = serde_json::from_value(params.into())?;
+            crate::lsp::client::register_capability(ctx, params)?;
+            serde_json::Value::Null
+        }
+        request::UnregisterCapability::METHOD => {
+            let params: lsp_types::UnregistrationParams = serde_json::from_value(params.into())?;
+            crate::lsp::client::unregister_capability(ctx, params)?;
+            serde_json::Value::Null
+        }
+        _ => return Err(rpc::Error::MethodNotFound(method.into()).into()),
+    };
+
+    Ok(res)
+}
+
 pub fn initialize<C, S>(ctx: &Context<C, S>) -> Result<()>
 where
     C: RPCClient,
     S: RPCClient,
 {
-    let server_command = ctx.server()?;
-    let root_path = get_root_path(&Path::new(&ctx.filename), &ctx.language_id)?
-        .to_string_lossy()
-        .to_string();
-    ctx.state
-        .write()
-        .roots
-        .insert(ctx.language_id.clone(), root_path.clone() + "/");
+    // servers that are already initialized are left alone, so that starting a server for a
+    // language id that has others running only initializes the new one.
+    let uninitialized: Vec<ServerID> = ctx
+        .servers
+        .iter()
+        .map(|(id, _)| id.clone())
+        .filter(|id| !initialized(ctx, id))
+        .collect();
 
-    let message = InitializeParams {
+    // a server failing to initialize does not keep the others from being initialized.
+    let mut error = None;
+    for id in uninitialized {
+        if let Err(err) = initialize_server(ctx, &id) {
+            log::error!("could not initialize {} server: {}", id, err);
+            error = Some(err);
+        }
+    }
+
+    match error {
+        Some(err) if !ctx.servers.iter().any(|(id, _)| initialized(ctx, id)) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn initialize_server<C, S>(ctx: &Context<C, S>, id: &ServerID) -> Result<()>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let server_config = ctx.server_config(id)?;
+    let message = initialize_params(ctx, id, server_config.initialization_options.clone())?;
+    let res: InitializeResult = ctx.call_server(id, request::Initialize::METHOD, message)?;
+    let document_sync = DocumentSync {
+        kind: capabilities::sync_kind(&res.capabilities),
+        change_delay: Duration::from_millis(server_config.change_delay),
+    };
+    let mut state = ctx.state.write();
+    state.document_sync.insert(id.clone(), document_sync);
+    state
+        .features
+        .insert(id.clone(), server_config.features.clone());
+    state
+        .server_capabilities
+        .insert(id.clone(), res.capabilities);
+    drop(state);
+    ctx.notify_server(id, notification::Initialized::METHOD, InitializedParams {})?;
+    // servers that don't ask for their settings expect them to be pushed once initialized.
+    if !server_config.settings.is_null() {
+        crate::lsp::workspace::did_change_configuration(ctx, id)?;
+    }
+
+    Ok(())
+}
+
+fn initialized<C, S>(ctx: &Context<C, S>, id: &ServerID) -> bool
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    ctx.state.read().server_capabilities.contains_key(id)
+}
+
+#[allow(deprecated)]
+fn initialize_params<C, S>(
+    ctx: &Context<C, S>,
+    id: &ServerID,
+    initialization_options: Option<serde_json::Value>,
+) -> Result<InitializeParams>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let params = InitializeParams {
         process_id: Some(ctx.state.read().process_id),
-        root_path: Some(root_path),
-        root_uri: Some(Url::from_directory_path(std::env::current_dir()?).unwrap()),
-        initialization_options: server_command.initialization_options.clone(),
+        root_path: Some(id.root.clone()),
+        root_uri: Some(workspace_folder(&id.root)?.uri),
+        initialization_options,
         capabilities: ClientCapabilities {
             text_document: Some(TextDocumentClientCapabilities {
+                synchronization: Some(TextDocumentSyncClientCapabilities {
+                    dynamic_registration: Some(true),
+                    will_save: Some(true),
+                    will_save_wait_until: Some(true),
+                    did_save: Some(true),
+                }),
                 hover: Some(HoverClientCapabilities {
+                    dynamic_registration: Some(true),
                     content_format: Some(ctx.config.hover.preferred_markup_kind.clone()),
+                }),
+                completion: Some(CompletionClientCapabilities {
+                    dynamic_registration: Some(true),
                     ..Default::default()
                 }),
+                definition: Some(GotoCapability {
+                    dynamic_registration: Some(true),
+                    link_support: None,
+                }),
+                implementation: Some(GotoCapability {
+                    dynamic_registration: Some(true),
+                    link_support: None,
+                }),
+                references: Some(DynamicRegistrationClientCapabilities {
+                    dynamic_registration: Some(true),
+                }),
+                rename: Some(RenameClientCapabilities {
+                    dynamic_registration: Some(true),
+                    ..Default::default()
+                }),
+                formatting: Some(DynamicRegistrationClientCapabilities {
+                    dynamic_registration: Some(true),
+                }),
+                code_lens: Some(DynamicRegistrationClientCapabilities {
+                    dynamic_registration: Some(true),
+                }),
                 code_action: Some(CodeActionClientCapabilities {
+                    dynamic_registration: Some(true),
                     code_action_literal_support: Some(CodeActionLiteralSupport {
                         code_action_kind: CodeActionKindLiteralSupport {
                             value_set: [
@@ -111,12 +280,35 @@ where
                     }),
                     ..CodeActionClientCapabilities::default()
                 }),
+                publish_diagnostics: Some(PublishDiagnosticsClientCapabilities {
+                    version_support: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            workspace: Some(WorkspaceClientCapabilities {
+                workspace_folders: Some(true),
+                configuration: Some(true),
+                execute_command: Some(DynamicRegistrationClientCapabilities {
+                    dynamic_registration: Some(true),
+                }),
+                did_change_watched_files: Some(DidChangeWatchedFilesClientCapabilities {
+                    dynamic_registration: Some(true),
+                }),
                 ..Default::default()
             }),
             ..Default::default()
         },
         trace: Some(TraceOption::Verbose),
-        workspace_folders: None,
+        // a restarted server gets back every folder that was added to it.
+        workspace_folders: Some(
+            ctx.state
+                .read()
+                .roots_for(id)
+                .iter()
+                .map(|root| workspace_folder(root))
+                .collect::<Result<_>>()?,
+        ),
         client_info: Some(ClientInfo {
             name: "vim-lc".into(),
             version: Some("1.0".into()),
@@ -124,100 +316,94 @@ where
         locale: None,
     };
 
-    let res: InitializeResult = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::Initialize::METHOD, message)?;
-
-    ctx.state
-        .write()
-        .server_capabilities
-        .insert(ctx.language_id.clone(), res.capabilities);
-
-    Ok(())
+    Ok(params)
 }
 
-pub fn get_root_path<'a>(path: &'a Path, language_id: &str) -> Result<&'a Path> {
+// root markers for the language ids with a well known project layout, used for servers that do
+// not configure their own. Every group of markers is looked for in order, the first one found
+// going up from the document being the root.
+fn default_root_patterns(language_id: &str) -> &'static [&'static [&'static str]] {
     match language_id {
-        "rust" => traverse_up(path, dir_has_one(&["Cargo.toml"])),
-        "php" => traverse_up(path, dir_has_one(&["composer.json"])),
-        "javascript" | "typescript" | "javascript.jsx" | "typescript.tsx" => {
-            traverse_up(path, dir_has_one(&["package.json"]))
-        }
-        "python" => traverse_up(
-            path,
-            dir_has_one(&["setup.py", "Pipfile", "requirements.txt", "pyproject.toml"]),
-        ),
-        "c" | "cpp" => traverse_up(path, dir_has_one(&["compile_commands.json"])),
-        "cs" => traverse_up(path, is_dotnet_root),
-        "java" => traverse_up(
-            path,
-            dir_has_one(&[
-                "pom.xml",
-                "settings.gradle",
-                "settings.gradle.kts",
-                "WORKSPACE",
-            ]),
-        ),
-        "scala" => traverse_up(path, dir_has_one(&["build.sbt"])),
-        "haskell" => traverse_up(path, dir_has_one(&["stack.yaml"])).or_else(|_| {
-            traverse_up(path, |dir| {
-                dir_contains_file(dir, |f| has_extension(f, "cabal"))
-            })
-        }),
-        "go" => traverse_up(path, dir_has_one(&["go.mod"])),
-        _ => Err(anyhow::anyhow!("Unknown languageId: {}", language_id)),
+        "rust" => &[&["Cargo.toml"]],
+        "php" => &[&["composer.json"]],
+        "javascript" | "typescript" | "javascript.jsx" | "typescript.tsx" => &[&["package.json"]],
+        "python" => &[&["setup.py", "Pipfile", "requirements.txt", "pyproject.toml"]],
+        "c" | "cpp" => &[&["compile_commands.json"]],
+        "cs" => &[&["project.json", "*.csproj"]],
+        "java" => &[&[
+            "pom.xml",
+            "settings.gradle",
+            "settings.gradle.kts",
+            "WORKSPACE",
+        ]],
+        "scala" => &[&["build.sbt"]],
+        "haskell" => &[&["stack.yaml"], &["*.cabal"]],
+        "go" => &[&["go.mod"]],
+        _ => &[],
     }
-    .or_else(|_| {
-        traverse_up(path, |dir| {
-            dir.join(".git").exists() || dir.join(".hg").exists() || dir.join(".svn").exists()
-        })
-    })
-    .or_else(|_| {
-        let parent = path
-            .parent()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get parent dir! path: {:?}", path));
-        log::warn!(
-            "Unknown project type. Fallback to use dir as project root: {:?}",
-            parent
-        );
-        parent
-    })
 }
 
-fn is_dotnet_root(dir: &Path) -> bool {
-    if dir.join("project.json").exists() {
-        return true;
-    }
-    if !dir.is_dir() {
-        return false;
-    }
-
-    let entries = match dir.read_dir() {
-        Ok(entries) => entries,
-        Err(_) => return false,
+/// returns the root of the workspace the document at the given path belongs to for the given
+/// server, falling back to the root of its repository and then to its directory when the server
+/// handles single files.
+pub fn get_root_path<'a>(
+    path: &'a Path,
+    language_id: &str,
+    server: &ServerConfig,
+) -> Result<&'a Path> {
+    let found = if server.root_patterns.is_empty() {
+        default_root_patterns(language_id)
+            .iter()
+            .find_map(|patterns| traverse_up(path, dir_has_one(patterns)).ok())
+    } else {
+        traverse_up(path, dir_has_one(&server.root_patterns)).ok()
     };
-    for entry in entries {
-        if let Ok(entry) = entry {
-            if entry.path().ends_with(".csproj") {
-                return true;
+
+    found
+        .ok_or_else(|| anyhow::anyhow!("no root marker found for {:?}", path))
+        .or_else(|_| traverse_up(path, dir_has_one(&[".git", ".hg", ".svn"])))
+        .or_else(|_| {
+            if !server.single_file_mode {
+                anyhow::bail!("{:?} is not in a workspace for {}", path, server.name);
             }
-        }
-    }
 
-    false
+            let parent = path
+                .parent()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get parent dir! path: {:?}", path));
+            log::warn!(
+                "Unknown project type. Fallback to use dir as project root: {:?}",
+                parent
+            );
+            parent
+        })
 }
 
-fn has_extension(path: &Path, ext: &str) -> bool {
-    matches!(path.extension().and_then(|e| e.to_str()), Some(path_ext) if path_ext == ext)
+// returns whether the directory has a file matching the pattern, which is either a file name or a
+// glob matched against the names of the files in it.
+fn dir_matches(dir: &Path, pattern: &str) -> bool {
+    if !crate::glob::is_glob(pattern) {
+        return dir.join(pattern).exists();
+    }
+
+    dir_contains_file(dir, |file| {
+        file.file_name()
+            .map(|name| crate::glob::matches(pattern, &name.to_string_lossy()))
+            .unwrap_or_default()
+    })
 }
 
-fn dir_has_one<'a>(files: &'a [&str]) -> impl Fn(&'a Path) -> bool {
-    move |dir| files.iter().any(|file| dir.join(file).exists())
+pub fn dir_has_one<P>(patterns: &[P]) -> impl Fn(&Path) -> bool + '_
+where
+    P: AsRef<str>,
+{
+    move |dir| {
+        patterns
+            .iter()
+            .any(|pattern| dir_matches(dir, pattern.as_ref()))
+    }
 }
 
-fn traverse_up<'a, F>(path: &'a Path, predicate: F) -> Result<&'a Path>
+pub fn traverse_up<'a, F>(path: &'a Path, predicate: F) -> Result<&'a Path>
 where
     F: Fn(&'a Path) -> bool,
 {
@@ -230,7 +416,7 @@ where
     traverse_up(next_path, predicate)
 }
 
-fn dir_contains_file<F>(path: &Path, predicate: F) -> bool
+pub fn dir_contains_file<F>(path: &Path, predicate: F) -> bool
 where
     F: Fn(&Path) -> bool,
 {
@@ -252,10 +438,11 @@ where
     C: RPCClient,
     S: RPCClient,
 {
-    ctx.server
-        .as_ref()
-        .unwrap()
-        .call(request::Shutdown::METHOD, ())?;
+    ctx.state
+        .write()
+        .stopping
+        .extend(ctx.servers.iter().map(|(id, _)| id.clone()));
+    let _: Vec<(ServerID, Option<()>)> = ctx.call_all(request::Shutdown::METHOD, |_| ())?;
     Ok(())
 }
 
@@ -264,21 +451,129 @@ where
     C: RPCClient,
     S: RPCClient,
 {
-    ctx.server
-        .as_ref()
-        .unwrap()
-        .notify(notification::Exit::METHOD, ())?;
+    ctx.notify(notification::Exit::METHOD, ())?;
     Ok(())
 }
 
-pub fn initialized<C, S>(ctx: &Context<C, S>) -> Result<()>
-where
-    C: RPCClient,
-    S: RPCClient,
-{
-    ctx.server
-        .as_ref()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::rpc::FakeClient;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn method_call(method: &str, params: serde_json::Value) -> rpc::Message {
+        serde_json::from_value(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": 4,
+        }))
         .unwrap()
-        .notify(notification::Initialized::METHOD, InitializedParams {})?;
-    Ok(())
+    }
+
+    #[test]
+    fn test_unknown_request_is_not_found() {
+        let lc: LanguageClient<FakeClient, FakeClient> =
+            LanguageClient::new(FakeClient::default(), Config::default());
+        let id = ServerID::new("go", "gopls", "/root");
+        let server = FakeClient::default();
+        lc.servers.write().insert(id.clone(), server.clone());
+
+        lc.handle_message(&id, method_call("workspace/unknown", json!({})))
+            .unwrap();
+
+        let replies = server.replies.lock();
+        assert_eq!(replies.len(), 1);
+        let (reply_id, reply) = &replies[0];
+        assert_eq!(reply_id, &jsonrpc_core::Id::Num(4));
+        let error = reply.as_ref().unwrap_err();
+        assert_eq!(error.code.code(), -32601);
+        assert_eq!(error.message, "method not found: workspace/unknown");
+        assert!(lc.vim.notifications.lock().is_empty());
+    }
+
+    #[test]
+    fn test_known_request_is_answered() {
+        let lc: LanguageClient<FakeClient, FakeClient> =
+            LanguageClient::new(FakeClient::default(), Config::default());
+        let id = ServerID::new("go", "gopls", "/root");
+        let server = FakeClient::default();
+        lc.servers.write().insert(id.clone(), server.clone());
+
+        let params = json!({"token": "indexing"});
+        lc.handle_message(&id, method_call("window/workDoneProgress/create", params))
+            .unwrap();
+        let replies = server.replies.lock();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1, Ok(serde_json::Value::Null));
+    }
+
+    // creates the given files under a new directory for the test, returning the directory.
+    fn workspace(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vlc-root-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    fn rust_server(single_file_mode: bool) -> ServerConfig {
+        ServerConfig {
+            name: "rls".into(),
+            root_patterns: vec!["Cargo.toml".into()],
+            single_file_mode,
+            ..Default::default()
+        }
+    }
+
+    fn root(file: &Path, server: &ServerConfig) -> Option<PathBuf> {
+        get_root_path(file, "rust", server).ok().map(Path::to_owned)
+    }
+
+    #[test]
+    fn test_get_root_path_nested() {
+        let dir = workspace(
+            "nested",
+            &[
+                "Cargo.toml",
+                "src/main.rs",
+                "inner/Cargo.toml",
+                "inner/src/lib.rs",
+            ],
+        );
+        let server = rust_server(false);
+
+        // the closest root to the document is the one it belongs to.
+        assert_eq!(
+            root(&dir.join("inner/src/lib.rs"), &server),
+            Some(dir.join("inner"))
+        );
+        assert_eq!(root(&dir.join("src/main.rs"), &server), Some(dir.clone()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_root_path_repository() {
+        let dir = workspace("repository", &[".git/HEAD", "src/main.rs"]);
+        let server = rust_server(false);
+        assert_eq!(root(&dir.join("src/main.rs"), &server), Some(dir.clone()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_root_path_single_file_mode() {
+        let dir = workspace("single", &["scripts/main.rs"]);
+        let file = dir.join("scripts/main.rs");
+
+        // documents outside of any workspace are handled in their directory if the server handles
+        // single files, and not at all otherwise.
+        assert_eq!(root(&file, &rust_server(true)), Some(dir.join("scripts")));
+        assert_eq!(root(&file, &rust_server(false)), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
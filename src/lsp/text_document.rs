@@ -1,17 +1,18 @@
 use crate::language_client::Context;
-use crate::{rpc::RPCClient, vim};
+use crate::rpc::{RPCClient, ServerID};
+use crate::state::{DocumentChange, DocumentSync};
+use crate::vim;
 use anyhow::Result;
 use lsp_types::{
     notification::{self, Notification},
     request::{self, Request},
     CodeActionContext, CodeActionOrCommand, CodeActionParams, CodeActionResponse, CodeLens,
-    CodeLensParams, CompletionItem, CompletionParams, CompletionResponse, DiagnosticSeverity,
-    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    CodeLensParams, CompletionItem, CompletionList, CompletionParams, CompletionResponse,
+    DiagnosticSeverity, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
     DidSaveTextDocumentParams, DocumentFormattingParams, FormattingOptions, GotoDefinitionResponse,
-    Hover, PublishDiagnosticsParams, Range, ReferenceParams, RenameParams,
-    TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
-    TextDocumentPositionParams, TextEdit, Url, VersionedTextDocumentIdentifier,
-    WorkDoneProgressParams, WorkspaceEdit,
+    Hover, Position, PublishDiagnosticsParams, Range, RenameParams, TextDocumentContentChangeEvent,
+    TextDocumentIdentifier, TextDocumentItem, TextDocumentSaveReason, TextDocumentSyncKind,
+    TextEdit, Url, WillSaveTextDocumentParams, WorkDoneProgressParams, WorkspaceEdit,
 };
 use std::collections::HashMap;
 
@@ -36,11 +37,7 @@ pub fn formatting<C: RPCClient, S: RPCClient>(
         },
     };
 
-    let res: Option<Vec<TextEdit>> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::Formatting::METHOD, params)?;
+    let res: Option<Vec<TextEdit>> = ctx.call(request::Formatting::METHOD, params)?;
 
     if res.is_none() {
         return Ok(vec![]);
@@ -54,49 +51,53 @@ pub fn code_action<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::SelectionRange,
 ) -> Result<Vec<CodeActionOrCommand>> {
-    let range = Range {
-        start: input.range.start.into(),
-        end: input.range.end.into(),
-    };
+    let range = input
+        .range
+        .to_lsp(&input.filename, &vim::FileLines::new(ctx));
 
-    let diagnostics: Vec<_> = ctx
+    // every server is only sent the diagnostics it published itself.
+    let diagnostics = ctx
         .state
         .read()
         .diagnostics
         .get(&input.filename)
-        .unwrap_or(&vec![])
-        .iter()
-        .filter(|dn| dn.range.start <= range.start && dn.range.end >= range.end)
         .cloned()
+        .unwrap_or_default();
+    let uri = Url::from_file_path(input.filename).unwrap();
+    let res: Vec<(ServerID, Option<CodeActionResponse>)> =
+        ctx.call_all(request::CodeActionRequest::METHOD, |id| CodeActionParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            range,
+            context: CodeActionContext {
+                diagnostics: diagnostics
+                    .get(id)
+                    .map(|d| {
+                        d.iter()
+                            .filter(|dn| dn.range.start <= range.start && dn.range.end >= range.end)
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                only: None,
+            },
+        })?;
+
+    // actions are stashed along with the server they came from, as that is the one that has to
+    // run them once one is picked.
+    let actions: Vec<(ServerID, CodeActionOrCommand)> = res
+        .into_iter()
+        .flat_map(|(id, actions)| {
+            actions
+                .unwrap_or_default()
+                .into_iter()
+                .map(move |action| (id.clone(), action))
+        })
         .collect();
-
-    let params = CodeActionParams {
-        text_document: TextDocumentIdentifier {
-            uri: Url::from_file_path(input.filename).unwrap(),
-        },
-        work_done_progress_params: Default::default(),
-        partial_result_params: Default::default(),
-        range,
-        context: CodeActionContext {
-            diagnostics,
-            only: None,
-        },
-    };
-
-    let res: Option<CodeActionResponse> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::CodeActionRequest::METHOD, params)?;
-
-    if res.is_none() {
-        return Ok(vec![]);
-    }
-
-    let actions = res.unwrap();
     ctx.state.write().code_actions = actions.clone();
 
-    Ok(actions)
+    Ok(actions.into_iter().map(|(_, action)| action).collect())
 }
 
 pub fn code_lens<C: RPCClient, S: RPCClient>(
@@ -111,11 +112,7 @@ pub fn code_lens<C: RPCClient, S: RPCClient>(
         partial_result_params: Default::default(),
     };
 
-    let response: Option<Vec<CodeLens>> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::CodeLensRequest::METHOD, params)?;
+    let response: Option<Vec<CodeLens>> = ctx.call(request::CodeLensRequest::METHOD, params)?;
     let response = response.unwrap_or_default();
     if response.is_empty() {
         return Ok(vec![]);
@@ -132,12 +129,9 @@ pub fn implementation<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::CursorPosition,
 ) -> Result<Option<request::GotoImplementationResponse>> {
-    let input: TextDocumentPositionParams = input.into();
-    let message: Option<request::GotoImplementationResponse> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::GotoImplementation::METHOD, input)?;
+    let input = input.to_lsp(ctx);
+    let message: Option<request::GotoImplementationResponse> =
+        ctx.call(request::GotoImplementation::METHOD, input)?;
     Ok(message)
 }
 
@@ -145,12 +139,8 @@ pub fn references<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::CursorPosition,
 ) -> Result<Option<Vec<lsp_types::Location>>> {
-    let input: ReferenceParams = input.into();
-    let message: Option<Vec<lsp_types::Location>> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::References::METHOD, input)?;
+    let input = input.to_reference_params(ctx);
+    let message: Option<Vec<lsp_types::Location>> = ctx.call(request::References::METHOD, input)?;
     Ok(message)
 }
 
@@ -158,12 +148,9 @@ pub fn definition<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     params: vim::CursorPosition,
 ) -> Result<Option<GotoDefinitionResponse>> {
-    let input: TextDocumentPositionParams = params.into();
-    let message: Option<GotoDefinitionResponse> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::GotoDefinition::METHOD, input)?;
+    let input = params.to_lsp(ctx);
+    let message: Option<GotoDefinitionResponse> =
+        ctx.call(request::GotoDefinition::METHOD, input)?;
     Ok(message)
 }
 
@@ -178,17 +165,80 @@ pub fn did_save<C: RPCClient, S: RPCClient>(
         text: None,
     };
 
-    ctx.server
-        .as_ref()
-        .unwrap()
-        .notify(notification::DidSaveTextDocument::METHOD, input)
+    ctx.notify(notification::DidSaveTextDocument::METHOD, input)
+}
+
+// tells the servers that want to know that the document is about to be saved, and returns the
+// edits the first server that asks to make any wants made to it before.
+pub fn will_save<C: RPCClient, S: RPCClient>(
+    ctx: &Context<C, S>,
+    filename: &str,
+) -> Result<Vec<TextEdit>> {
+    let params = WillSaveTextDocumentParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(filename).unwrap(),
+        },
+        reason: TextDocumentSaveReason::Manual,
+    };
+
+    for (id, _) in ctx.servers_for(notification::WillSaveTextDocument::METHOD) {
+        ctx.flush_changes(id)?;
+        ctx.notify_server(id, notification::WillSaveTextDocument::METHOD, &params)?;
+    }
+
+    // edits from different servers are all made to the same text, so they can't be applied on top
+    // of each other.
+    let version = ctx
+        .state
+        .read()
+        .text_documents
+        .get(filename)
+        .map(|doc| doc.version);
+    for (id, _) in ctx.servers_for(request::WillSaveWaitUntil::METHOD) {
+        let edits: Option<Vec<TextEdit>> =
+            match ctx.call_server(id, request::WillSaveWaitUntil::METHOD, &params) {
+                Ok(edits) => edits,
+                Err(err) => {
+                    log::error!(
+                        "{} failed on {}: {}",
+                        request::WillSaveWaitUntil::METHOD,
+                        id,
+                        err
+                    );
+                    continue;
+                }
+            };
+
+        // edits made for a version of the document that has changed since would end up in the
+        // wrong place.
+        if !ctx
+            .state
+            .read()
+            .text_documents
+            .is_current(filename, version)
+        {
+            log::debug!(
+                "ignoring edits for an old version of {} from {}",
+                filename,
+                id
+            );
+            return Ok(vec![]);
+        }
+
+        match edits {
+            Some(edits) if !edits.is_empty() => return Ok(edits),
+            _ => continue,
+        }
+    }
+
+    Ok(vec![])
 }
 
 pub fn did_close<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::TextDocumentContent,
 ) -> Result<()> {
-    let _ = ctx.state.write().text_documents.remove(&input.filename);
+    ctx.state.write().text_documents.close(&input.filename);
 
     let input = DidCloseTextDocumentParams {
         text_document: TextDocumentIdentifier {
@@ -196,46 +246,101 @@ pub fn did_close<C: RPCClient, S: RPCClient>(
         },
     };
 
-    ctx.server
-        .as_ref()
-        .unwrap()
-        .notify(notification::DidCloseTextDocument::METHOD, input)
+    ctx.notify(notification::DidCloseTextDocument::METHOD, input)
 }
 
 pub fn did_change<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::TextDocumentContent,
 ) -> Result<()> {
-    let (version, _) = ctx
-        .state
-        .read()
+    let mut state = ctx.state.write();
+    let change = state
         .text_documents
-        .get(&input.filename)
-        .cloned()
-        .unwrap_or_default();
-    // let (version, _) = state
-    //     .text_documents
-    //     .get(&input.text_document)
-    //     .cloned()
-    //     .unwrap_or_default();
-
-    // TODO: not sure if version should actually be an u64
-    let input = DidChangeTextDocumentParams {
-        text_document: VersionedTextDocumentIdentifier {
-            uri: Url::from_file_path(input.filename).unwrap(),
-            version: version as i32,
-        },
-        content_changes: vec![TextDocumentContentChangeEvent {
-            range: None,
-            range_length: None,
-            text: input.text,
-        }],
+        .change(&input.filename, &input.language_id, &input.text);
+    let (version, change) = match change {
+        DocumentChange::Changed(version, previous) => {
+            (version, text_change(&previous, &input.text))
+        }
+        DocumentChange::Unchanged => return Ok(()),
+        // servers have not been told about documents that were not tracked, so they are opened
+        // instead.
+        DocumentChange::Opened => {
+            drop(state);
+            return did_open(ctx, input);
+        }
     };
+    let servers: Vec<(ServerID, DocumentSync)> = ctx
+        .servers
+        .iter()
+        .map(|(id, _)| (id.clone(), state.document_sync(id)))
+        .collect();
+    drop(state);
 
-    ctx.server
-        .as_ref()
-        .unwrap()
-        .notify(notification::DidChangeTextDocument::METHOD, input)
+    let uri = Url::from_file_path(input.filename).unwrap();
+    for (id, sync) in servers {
+        let change = match sync.kind {
+            TextDocumentSyncKind::None => continue,
+            TextDocumentSyncKind::Incremental => change.clone(),
+            _ => TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: input.text.clone(),
+            },
+        };
+
+        ctx.changes
+            .push(&id, uri.clone(), version, change, sync.change_delay);
+        if sync.change_delay.is_zero() {
+            ctx.flush_changes(&id)?;
+        }
+    }
+
+    Ok(())
+}
+
+// returns the change that turns the old text into the new one, replacing the smallest range that
+// covers everything that differs between them.
+fn text_change(old: &str, new: &str) -> TextDocumentContentChangeEvent {
+    let mut prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) {
+        suffix -= 1;
+    }
+
+    TextDocumentContentChangeEvent {
+        range: Some(Range {
+            start: position_at(old, prefix),
+            end: position_at(old, old.len() - suffix),
+        }),
+        range_length: None,
+        text: new[prefix..new.len() - suffix].to_owned(),
+    }
+}
+
+// returns the position of the byte offset in the text, with the character counted in UTF-16 code
+// units.
+fn position_at(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
 }
 
 pub fn rename<C: RPCClient, S: RPCClient>(
@@ -243,16 +348,12 @@ pub fn rename<C: RPCClient, S: RPCClient>(
     input: vim::RenameParams,
 ) -> Result<Option<WorkspaceEdit>> {
     let params = RenameParams {
-        text_document_position: input.position.into(),
+        text_document_position: input.position.to_lsp(ctx),
         new_name: input.new_name,
         work_done_progress_params: WorkDoneProgressParams::default(),
     };
 
-    let response: Option<WorkspaceEdit> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::Rename::METHOD, params)?;
+    let response: Option<WorkspaceEdit> = ctx.call(request::Rename::METHOD, params)?;
     Ok(response)
 }
 
@@ -260,44 +361,30 @@ pub fn did_open<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::TextDocumentContent,
 ) -> Result<()> {
-    let mut state = ctx.state.write();
-    let mut version = state.text_documents.get(&input.filename).cloned();
-
-    if version.is_none() {
-        let v = (0, input.text.split('\n').map(|l| l.to_owned()).collect());
-        state
+    // the text the document is opened with is what later changes are diffed against.
+    let version =
+        ctx.state
+            .write()
             .text_documents
-            .insert(input.filename.clone(), v.clone());
-        version = Some(v);
-    }
-    drop(state);
-
-    let (version, _) = version.unwrap();
+            .open(&input.filename, &input.language_id, &input.text);
     let input = DidOpenTextDocumentParams {
         text_document: TextDocumentItem {
             uri: Url::from_file_path(input.filename).unwrap(),
             language_id: input.language_id,
-            version: version as i32,
+            version,
             text: input.text,
         },
     };
 
-    ctx.server
-        .as_ref()
-        .unwrap()
-        .notify(notification::DidOpenTextDocument::METHOD, input)
+    ctx.notify(notification::DidOpenTextDocument::METHOD, input)
 }
 
 pub fn hover<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::CursorPosition,
 ) -> Result<Option<Hover>> {
-    let input: TextDocumentPositionParams = input.into();
-    let response: Option<Hover> = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::HoverRequest::METHOD, input)?;
+    let input = input.to_lsp(ctx);
+    let response: Option<Hover> = ctx.call(request::HoverRequest::METHOD, input)?;
     Ok(response)
 }
 
@@ -306,33 +393,74 @@ pub fn completion<C: RPCClient, S: RPCClient>(
     input: vim::CursorPosition,
 ) -> Result<Option<CompletionResponse>> {
     let input = CompletionParams {
-        text_document_position: input.into(),
+        text_document_position: input.to_lsp(ctx),
         work_done_progress_params: Default::default(),
         partial_result_params: Default::default(),
         context: Default::default(),
     };
 
-    let message = ctx
-        .server
-        .as_ref()
-        .unwrap()
-        .call(request::Completion::METHOD, input)?;
+    // items from every server are put together in a single list, which is only complete if every
+    // list it was made of is.
+    let res: Vec<(ServerID, Option<CompletionResponse>)> =
+        ctx.call_all(request::Completion::METHOD, |_| &input)?;
+    let mut items = vec![];
+    let mut is_incomplete = false;
+    let mut answered = false;
+    for (id, response) in res {
+        let list = match response {
+            Some(CompletionResponse::Array(list)) => list,
+            Some(CompletionResponse::List(list)) => {
+                is_incomplete |= list.is_incomplete;
+                list.items
+            }
+            None => continue,
+        };
+        items.extend(list.into_iter().map(|item| (id.clone(), item)));
+        answered = true;
+    }
 
-    Ok(message)
+    if !answered {
+        return Ok(None);
+    }
+
+    // items are stashed along with the server they came from, so that they are resolved by it.
+    ctx.state.write().completion_items = items.clone();
+    Ok(Some(CompletionResponse::List(CompletionList {
+        is_incomplete,
+        items: items.into_iter().map(|(_, item)| item).collect(),
+    })))
 }
 
 pub fn completion_item_resolve<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     input: vim::CompletionItem,
 ) -> Result<CompletionItem> {
-    let params: CompletionItem = input.into();
-    let message: CompletionItem = ctx
-        .server
+    // items shown in vim carry the index of the item they were made from in their user data, which
+    // is only trusted if the item at that index is still the same one.
+    let stashed = input
+        .user_data
         .as_ref()
-        .unwrap()
-        .call(request::ResolveCompletionItem::METHOD, params)?;
+        .and_then(|data| data.parse::<usize>().ok())
+        .and_then(|index| ctx.state.read().completion_items.get(index).cloned())
+        .filter(|(_, item)| item.label == input.word);
+
+    let (id, item) = match stashed {
+        Some(stashed) => stashed,
+        None => {
+            let params: CompletionItem = input.into();
+            return ctx.call(request::ResolveCompletionItem::METHOD, params);
+        }
+    };
 
-    Ok(message)
+    if !ctx
+        .state
+        .read()
+        .supports(&id, request::ResolveCompletionItem::METHOD, ctx.document())
+    {
+        return Ok(item);
+    }
+
+    ctx.call_server(&id, request::ResolveCompletionItem::METHOD, item)
 }
 
 pub fn publish_diagnostics<C: RPCClient, S: RPCClient>(
@@ -340,23 +468,474 @@ pub fn publish_diagnostics<C: RPCClient, S: RPCClient>(
     input: PublishDiagnosticsParams,
 ) -> Result<()> {
     let uri = input.uri.to_string().replace("file://", "");
-    ctx.state
-        .write()
-        .diagnostics
-        .insert(uri.clone(), input.diagnostics.clone());
+    // diagnostics are published by a single server, so they only replace the ones that server
+    // published before and are shown along with the ones from the rest of them.
+    let id = match ctx.servers.first() {
+        Some((id, _)) => id.clone(),
+        None => return Ok(()),
+    };
+    if !ctx.config.features.diagnostics || !ctx.state.read().features(&id).diagnostics {
+        return Ok(());
+    }
+
+    let mut state = ctx.state.write();
+    // diagnostics for a version of the document that has since changed are replaced by the ones
+    // for the current version as soon as they are published.
+    if !state.text_documents.is_current(&uri, input.version) {
+        log::debug!("ignoring diagnostics for an old version of {}", uri);
+        return Ok(());
+    }
 
-    let diagnostics = input
+    state
         .diagnostics
+        .entry(uri.clone())
+        .or_default()
+        .insert(id, input.diagnostics);
+    let merged = state.diagnostics_for(&uri);
+    drop(state);
+
+    let lines = vim::FileLines::new(ctx);
+    let diagnostics = merged
         .into_iter()
-        .map(|d| vim::Diagnostic {
-            position: uri.clone(),
-            line: d.range.start.line + 1,
-            col: d.range.start.character + 1,
-            text: d.message,
-            severity: d.severity.unwrap_or(DiagnosticSeverity::Warning),
+        .map(|d| {
+            let start = vim::Position::from_lsp(d.range.start, &uri, &lines);
+            vim::Diagnostic {
+                position: uri.clone(),
+                line: start.line,
+                col: start.column,
+                text: d.message,
+                severity: d.severity.unwrap_or(DiagnosticSeverity::Warning),
+            }
         })
         .collect();
 
     crate::vim::show_diagnostics(ctx, &uri, diagnostics)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{FakeClient, OnCall};
+    use crate::state::State;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    // returns the byte offset of the position in the text, the way a server would resolve it.
+    fn offset_at(text: &str, position: Position) -> usize {
+        let line_start: usize = text
+            .split_inclusive('\n')
+            .take(position.line as usize)
+            .map(str::len)
+            .sum();
+        let mut units = 0;
+        let line = text[line_start..].split('\n').next().unwrap();
+        for (i, c) in line.char_indices() {
+            if units >= position.character {
+                return line_start + i;
+            }
+            units += c.len_utf16() as u32;
+        }
+        line_start + line.len()
+    }
+
+    fn apply(text: &str, change: &TextDocumentContentChangeEvent) -> String {
+        let range = change.range.unwrap();
+        let (start, end) = (offset_at(text, range.start), offset_at(text, range.end));
+        format!("{}{}{}", &text[..start], change.text, &text[end..])
+    }
+
+    fn assert_change(old: &str, new: &str, range: ((u32, u32), (u32, u32)), text: &str) {
+        let change = text_change(old, new);
+        let ((start_line, start_character), (end_line, end_character)) = range;
+        assert_eq!(
+            change.range,
+            Some(Range {
+                start: Position::new(start_line, start_character),
+                end: Position::new(end_line, end_character),
+            }),
+            "{:?} to {:?}",
+            old,
+            new
+        );
+        assert_eq!(change.text, text);
+        assert_eq!(apply(old, &change), new);
+    }
+
+    #[test]
+    fn test_text_change_insertion() {
+        assert_change("fn main() {}", "fn main() { x }", ((0, 11), (0, 11)), " x ");
+        assert_change("abc", "xabc", ((0, 0), (0, 0)), "x");
+        assert_change("", "hello", ((0, 0), (0, 0)), "hello");
+        // repeated characters are matched from the start.
+        assert_change("aaa", "aaaa", ((0, 3), (0, 3)), "a");
+    }
+
+    #[test]
+    fn test_text_change_deletion() {
+        assert_change("let x = 1;", "let x;", ((0, 5), (0, 9)), "");
+        assert_change("abc", "bc", ((0, 0), (0, 1)), "");
+        assert_change("hello", "", ((0, 0), (0, 5)), "");
+    }
+
+    #[test]
+    fn test_text_change_replacement() {
+        assert_change("let x = 1;", "let y = 1;", ((0, 4), (0, 5)), "y");
+        assert_change("foo(bar)", "foo(baz, qux)", ((0, 6), (0, 7)), "z, qux");
+        assert_change("same", "same", ((0, 4), (0, 4)), "");
+    }
+
+    #[test]
+    fn test_text_change_multiline() {
+        assert_change("a\nb\nc\n", "a\nx\ny\nc\n", ((1, 0), (1, 1)), "x\ny");
+        assert_change("a\nb\nc\nd\n", "a\nd\n", ((1, 0), (3, 0)), "");
+        assert_change(
+            "fn a() {\n    1\n}\n",
+            "fn a() {\n    let x = 1;\n    x\n}\n",
+            ((1, 4), (1, 5)),
+            "let x = 1;\n    x",
+        );
+        assert_change("a\r\nb\r\n", "a\r\nc\r\n", ((1, 0), (1, 1)), "c");
+    }
+
+    #[test]
+    fn test_text_change_eof() {
+        assert_change("a\nb", "a\nb\n", ((1, 1), (1, 1)), "\n");
+        assert_change("a\nb\n", "a\nb\nc\n", ((2, 0), (2, 0)), "c\n");
+        assert_change("a\nb\n", "a\n", ((1, 0), (2, 0)), "");
+        assert_change("a\n", "a", ((0, 1), (1, 0)), "");
+    }
+
+    #[test]
+    fn test_text_change_non_ascii() {
+        // é is 2 bytes and 1 UTF-16 unit, 中 is 3 bytes and 1 unit, 🦀 is 4 bytes and 2 units.
+        assert_change("café = 1", "café = 2", ((0, 7), (0, 8)), "2");
+        assert_change("🦀 crab", "🦀🦀 crab", ((0, 2), (0, 2)), "🦀");
+        assert_change("x = \"中文\"", "x = \"中\"", ((0, 6), (0, 7)), "");
+        // characters sharing leading bytes are not split.
+        assert_change("é", "è", ((0, 0), (0, 1)), "è");
+        assert_change("a中b", "a丰b", ((0, 1), (0, 2)), "丰");
+        assert_change("🦀\n🦀x", "🦀\n🦀yx", ((1, 2), (1, 2)), "y");
+    }
+
+    #[test]
+    fn test_position_at() {
+        let text = "ab\nc🦀d\n中\n";
+        assert_eq!(position_at(text, 0), Position::new(0, 0));
+        assert_eq!(position_at(text, 2), Position::new(0, 2));
+        assert_eq!(position_at(text, 3), Position::new(1, 0));
+        assert_eq!(position_at(text, 4), Position::new(1, 1));
+        assert_eq!(position_at(text, 8), Position::new(1, 3));
+        assert_eq!(position_at(text, 9), Position::new(1, 4));
+        assert_eq!(position_at(text, 10), Position::new(2, 0));
+        assert_eq!(position_at(text, 13), Position::new(2, 1));
+        assert_eq!(position_at(text, text.len()), Position::new(3, 0));
+    }
+
+    fn server(name: &str) -> ServerID {
+        ServerID::new("rust", name, "/")
+    }
+
+    fn context(servers: Vec<(ServerID, FakeClient)>) -> Context<FakeClient, FakeClient> {
+        let mut state = State::default();
+        state.text_documents.open("/a.rs", "rust", "fn main() {}\n");
+        Context::fake(FakeClient::default(), servers, state, "/a.rs")
+    }
+
+    fn cursor() -> vim::CursorPosition {
+        vim::CursorPosition {
+            filename: "/a.rs".into(),
+            language_id: "rust".into(),
+            position: vim::Position { line: 1, column: 1 },
+        }
+    }
+
+    fn completion_item(word: &str, user_data: Option<&str>) -> vim::CompletionItem {
+        vim::CompletionItem {
+            word: word.into(),
+            user_data: user_data.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_completion_merges_servers() {
+        let a = FakeClient::with_response(request::Completion::METHOD, json!([{"label": "a"}]));
+        let b = FakeClient::with_response(
+            request::Completion::METHOD,
+            json!({"isIncomplete": true, "items": [{"label": "b"}, {"label": "c"}]}),
+        );
+        let none = FakeClient::with_response(request::Completion::METHOD, Value::Null);
+        let ctx = context(vec![
+            (server("a"), a),
+            (server("none"), none),
+            (server("b"), b),
+        ]);
+
+        let list = match completion(&ctx, cursor()).unwrap() {
+            Some(CompletionResponse::List(list)) => list,
+            response => panic!("unexpected response {:?}", response),
+        };
+        assert!(list.is_incomplete);
+        let labels: Vec<_> = list.items.iter().map(|i| i.label.as_str()).collect();
+        assert_eq!(labels, vec!["a", "b", "c"]);
+
+        // every item remembers the server it came from.
+        let origins: Vec<_> = ctx
+            .state
+            .read()
+            .completion_items
+            .iter()
+            .map(|(id, item)| (id.name.clone(), item.label.clone()))
+            .collect();
+        assert_eq!(
+            origins,
+            vec![
+                ("a".into(), "a".into()),
+                ("b".into(), "b".into()),
+                ("b".into(), "c".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_completion_is_complete_when_every_list_is() {
+        let a = FakeClient::with_response(
+            request::Completion::METHOD,
+            json!({"isIncomplete": false, "items": [{"label": "a"}]}),
+        );
+        let b = FakeClient::with_response(request::Completion::METHOD, json!([{"label": "b"}]));
+        let ctx = context(vec![(server("a"), a), (server("b"), b)]);
+        match completion(&ctx, cursor()).unwrap() {
+            Some(CompletionResponse::List(list)) => assert!(!list.is_incomplete),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_completion_without_answers() {
+        let none = FakeClient::with_response(request::Completion::METHOD, Value::Null);
+        let ctx = context(vec![(server("none"), none)]);
+        assert_eq!(completion(&ctx, cursor()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_completion_item_resolve_by_origin() {
+        let resolved = |detail: &str| {
+            FakeClient::with_response(
+                request::ResolveCompletionItem::METHOD,
+                json!({"label": "b", "detail": detail}),
+            )
+        };
+        let ctx = context(vec![
+            (server("a"), resolved("from a")),
+            (server("b"), resolved("from b")),
+        ]);
+        ctx.state.write().completion_items = vec![
+            (
+                server("a"),
+                CompletionItem::new_simple("a".into(), "".into()),
+            ),
+            (
+                server("b"),
+                CompletionItem::new_simple("b".into(), "".into()),
+            ),
+        ];
+
+        let item = completion_item_resolve(&ctx, completion_item("b", Some("1"))).unwrap();
+        assert_eq!(item.detail.as_deref(), Some("from b"));
+
+        // items that are no longer stashed are resolved by the servers in priority order.
+        let item = completion_item_resolve(&ctx, completion_item("b", Some("0"))).unwrap();
+        assert_eq!(item.detail.as_deref(), Some("from a"));
+        let item = completion_item_resolve(&ctx, completion_item("b", None)).unwrap();
+        assert_eq!(item.detail.as_deref(), Some("from a"));
+    }
+
+    #[test]
+    fn test_completion_item_resolve_unsupported_by_origin() {
+        let a = FakeClient::with_response(
+            request::ResolveCompletionItem::METHOD,
+            json!({"label": "a", "detail": "from a"}),
+        );
+        let ctx = context(vec![(server("a"), a)]);
+        let mut state = ctx.state.write();
+        state
+            .server_capabilities
+            .insert(server("a"), Default::default());
+        state.completion_items = vec![(
+            server("a"),
+            CompletionItem::new_simple("a".into(), "stashed".into()),
+        )];
+        drop(state);
+
+        let item = completion_item_resolve(&ctx, completion_item("a", Some("0"))).unwrap();
+        assert_eq!(item.detail.as_deref(), Some("stashed"));
+    }
+
+    #[test]
+    fn test_code_action_remembers_servers() {
+        let action = |title: &str| {
+            FakeClient::with_response(
+                request::CodeActionRequest::METHOD,
+                json!([{"title": title, "command": "run"}]),
+            )
+        };
+        let ctx = context(vec![(server("a"), action("a")), (server("b"), action("b"))]);
+        let input = vim::SelectionRange {
+            filename: "/a.rs".into(),
+            language_id: "rust".into(),
+            range: vim::Range {
+                start: vim::Position { line: 1, column: 1 },
+                end: vim::Position { line: 1, column: 3 },
+            },
+        };
+
+        let actions = code_action(&ctx, input).unwrap();
+        assert_eq!(actions.len(), 2);
+        let origins: Vec<_> = ctx
+            .state
+            .read()
+            .code_actions
+            .iter()
+            .map(|(id, action)| match action {
+                CodeActionOrCommand::Command(command) => (id.name.clone(), command.title.clone()),
+                CodeActionOrCommand::CodeAction(action) => (id.name.clone(), action.title.clone()),
+            })
+            .collect();
+        assert_eq!(
+            origins,
+            vec![("a".into(), "a".into()), ("b".into(), "b".into())]
+        );
+    }
+
+    #[test]
+    fn test_publish_diagnostics_feature() {
+        let publish = |ctx: &Context<FakeClient, FakeClient>| {
+            let params = PublishDiagnosticsParams {
+                uri: Url::from_file_path("/a.rs").unwrap(),
+                diagnostics: vec![lsp_types::Diagnostic::new_simple(
+                    Range::default(),
+                    "unused".into(),
+                )],
+                version: None,
+            };
+            publish_diagnostics(ctx, params).unwrap();
+        };
+
+        let ctx = context(vec![(server("a"), FakeClient::default())]);
+        publish(&ctx);
+        assert_eq!(ctx.state.read().diagnostics_for("/a.rs").len(), 1);
+
+        // servers that have diagnostics turned off in their config have theirs ignored.
+        let ctx = context(vec![(server("a"), FakeClient::default())]);
+        ctx.state.write().features.insert(
+            server("a"),
+            crate::config::FeatureSet {
+                diagnostics: false,
+                ..Default::default()
+            },
+        );
+        publish(&ctx);
+        assert!(ctx.state.read().diagnostics_for("/a.rs").is_empty());
+        assert!(ctx.vim.notifications.lock().is_empty());
+    }
+
+    #[test]
+    fn test_did_change_opens_untracked_documents() {
+        let server = FakeClient::default();
+        let ctx = Context::fake(
+            FakeClient::default(),
+            vec![(self::server("a"), server.clone())],
+            State::default(),
+            "/a.rs",
+        );
+        let content = |text: &str| vim::TextDocumentContent {
+            filename: "/a.rs".into(),
+            text: text.into(),
+            language_id: "rust".into(),
+        };
+
+        did_change(&ctx, content("a")).unwrap();
+        let opened = server.notified(notification::DidOpenTextDocument::METHOD);
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0]["textDocument"]["version"], 0);
+        assert_eq!(opened[0]["textDocument"]["text"], "a");
+        assert!(server
+            .notified(notification::DidChangeTextDocument::METHOD)
+            .is_empty());
+
+        // once opened, the document is changed as usual.
+        did_change(&ctx, content("ab")).unwrap();
+        let changed = server.notified(notification::DidChangeTextDocument::METHOD);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0]["textDocument"]["version"], 1);
+        assert_eq!(changed[0]["contentChanges"][0]["text"], "ab");
+        assert_eq!(
+            server
+                .notified(notification::DidOpenTextDocument::METHOD)
+                .len(),
+            1
+        );
+    }
+
+    // returns a server that answers willSaveWaitUntil with an edit, initialized with the given
+    // text document sync options.
+    fn saving_server(ctx: &Context<FakeClient, FakeClient>, name: &str, sync: Value) -> FakeClient {
+        let caps = serde_json::from_value(json!({ "textDocumentSync": sync })).unwrap();
+        ctx.state
+            .write()
+            .server_capabilities
+            .insert(server(name), caps);
+        FakeClient::with_response(
+            request::WillSaveWaitUntil::METHOD,
+            json!([{"range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 0}}, "newText": name}]),
+        )
+    }
+
+    #[test]
+    fn test_will_save_only_notifies_servers_that_ask() {
+        let mut ctx = context(vec![]);
+        let a = saving_server(&ctx, "a", json!({"willSave": true}));
+        let b = saving_server(&ctx, "b", json!({"willSaveWaitUntil": true}));
+        let c = saving_server(&ctx, "c", json!({"openClose": true}));
+        ctx.servers = vec![
+            (server("a"), a.clone()),
+            (server("b"), b.clone()),
+            (server("c"), c.clone()),
+        ];
+
+        let edits = will_save(&ctx, "/a.rs").unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].new_text, "b");
+
+        let method = notification::WillSaveTextDocument::METHOD;
+        let notified = a.notified(method);
+        assert_eq!(notified.len(), 1);
+        assert_eq!(notified[0]["textDocument"]["uri"], "file:///a.rs");
+        assert!(b.notified(method).is_empty());
+        assert!(c.notified(method).is_empty());
+
+        let method = request::WillSaveWaitUntil::METHOD;
+        assert!(a.called(method).is_empty());
+        assert_eq!(b.called(method).len(), 1);
+        assert!(c.called(method).is_empty());
+    }
+
+    #[test]
+    fn test_will_save_wait_until_ignores_edits_for_old_versions() {
+        let mut ctx = context(vec![]);
+        let mut server = saving_server(&ctx, "a", json!({"willSaveWaitUntil": true}));
+        let state = ctx.state.clone();
+        server.on_call = Some(OnCall(Arc::new(move |_| {
+            state
+                .write()
+                .text_documents
+                .change("/a.rs", "rust", "fn main() {}\n\n");
+        })));
+        ctx.servers = vec![(self::server("a"), server.clone())];
+
+        assert!(will_save(&ctx, "/a.rs").unwrap().is_empty());
+        assert_eq!(server.called(request::WillSaveWaitUntil::METHOD).len(), 1);
+    }
+}
@@ -1,22 +1,47 @@
 use crate::language_client::Context;
-use crate::rpc::RPCClient;
+use crate::rpc::{RPCClient, ServerID};
 use anyhow::Result;
+use lsp_types::{
+    notification::{self, Notification},
+    ConfigurationParams, DidChangeConfigurationParams, DidChangeWatchedFilesParams,
+    DidChangeWatchedFilesRegistrationOptions, DidChangeWorkspaceFoldersParams, FileEvent, Url,
+    WorkspaceFolder, WorkspaceFoldersChangeEvent,
+};
 use lsp_types::{request, WorkDoneProgressParams};
 use lsp_types::{request::Request, ExecuteCommandParams};
+use std::path::Path;
 
-pub fn execute_command<C, S>(ctx: &Context<C, S>, command: &lsp_types::Command) -> Result<()>
+pub fn execute_command<C, S>(
+    ctx: &Context<C, S>,
+    command: &lsp_types::Command,
+    id: Option<&ServerID>,
+) -> Result<()>
 where
     C: RPCClient,
     S: RPCClient,
 {
-    let _: serde_json::Value = ctx.server.as_ref().unwrap().call(
-        request::ExecuteCommand::METHOD,
-        ExecuteCommandParams {
-            command: command.command.clone(),
-            arguments: command.arguments.clone().unwrap_or_default(),
-            work_done_progress_params: WorkDoneProgressParams::default(),
-        },
-    )?;
+    let params = ExecuteCommandParams {
+        command: command.command.clone(),
+        arguments: command.arguments.clone().unwrap_or_default(),
+        work_done_progress_params: WorkDoneProgressParams::default(),
+    };
+
+    // the command is sent to the server it came from, if any, or to the one that says it can run
+    // it, or else to the servers that handle executeCommand in priority order.
+    let state = ctx.state.read();
+    let id = id.cloned().or_else(|| {
+        ctx.servers
+            .iter()
+            .map(|(id, _)| id)
+            .find(|id| state.supports_command(id, &command.command))
+            .cloned()
+    });
+    drop(state);
+
+    let _: serde_json::Value = match id {
+        Some(id) => ctx.call_server(&id, request::ExecuteCommand::METHOD, params)?,
+        None => ctx.call(request::ExecuteCommand::METHOD, params)?,
+    };
 
     Ok(())
 }
@@ -24,11 +49,287 @@ where
 pub fn apply_edit<C, S>(
     ctx: &Context<C, S>,
     params: &lsp_types::ApplyWorkspaceEditParams,
-) -> Result<()>
+) -> Result<lsp_types::ApplyWorkspaceEditResponse>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    match crate::vim::apply_workspace_edit(ctx, &params.edit) {
+        Ok(()) => Ok(lsp_types::ApplyWorkspaceEditResponse {
+            applied: true,
+            failure_reason: None,
+            failed_change: None,
+        }),
+        Err(err) => Ok(lsp_types::ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some(err.to_string()),
+            failed_change: None,
+        }),
+    }
+}
+
+/// answers workspace/configuration with the settings under each of the sections asked for.
+pub fn configuration<C, S>(
+    ctx: &Context<C, S>,
+    params: ConfigurationParams,
+) -> Result<Vec<serde_json::Value>>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let (id, _) = ctx
+        .servers
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no server running for filetype {}", ctx.language_id))?;
+    let server_config = ctx.server_config(id)?;
+    Ok(params
+        .items
+        .iter()
+        .map(|item| server_config.settings_section(item.section.as_deref().unwrap_or_default()))
+        .collect())
+}
+
+/// pushes the settings in the config to the server.
+pub fn did_change_configuration<C, S>(ctx: &Context<C, S>, id: &ServerID) -> Result<()>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let settings = ctx.server_config(id)?.settings;
+    ctx.notify_server(
+        id,
+        notification::DidChangeConfiguration::METHOD,
+        DidChangeConfigurationParams { settings },
+    )
+}
+
+/// returns the workspace folder for the given root.
+pub fn workspace_folder(root: &str) -> Result<WorkspaceFolder> {
+    let uri = Url::from_directory_path(root)
+        .map_err(|_| anyhow::anyhow!("invalid workspace root {}", root))?;
+    let name = Path::new(root)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| root.into());
+    Ok(WorkspaceFolder { uri, name })
+}
+
+/// answers workspace/workspaceFolders with every root handled by the server in the context.
+pub fn workspace_folders<C, S>(ctx: &Context<C, S>) -> Result<Vec<WorkspaceFolder>>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let (id, _) = ctx
+        .servers
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no server running for filetype {}", ctx.language_id))?;
+    let roots = ctx.state.read().roots_for(id);
+    roots.iter().map(|root| workspace_folder(root)).collect()
+}
+
+/// adds a workspace root to a running server, so that it handles the documents in it as well.
+pub fn add_workspace_folder<C, S>(ctx: &Context<C, S>, id: &ServerID, root: &str) -> Result<()>
 where
     C: RPCClient,
     S: RPCClient,
 {
-    crate::vim::apply_workspace_edit(ctx, &params.edit)?;
+    let params = DidChangeWorkspaceFoldersParams {
+        event: WorkspaceFoldersChangeEvent {
+            added: vec![workspace_folder(root)?],
+            removed: vec![],
+        },
+    };
+
+    ctx.notify_server(id, notification::DidChangeWorkspaceFolders::METHOD, params)?;
+    ctx.state
+        .write()
+        .workspace_folders
+        .entry(id.clone())
+        .or_default()
+        .push(root.into());
+    // files in the new root are of interest to the server as well.
+    watch_files(ctx, id);
     Ok(())
 }
+
+/// watches the files the server registered watchers for in every root it handles, or stops
+/// watching them if it has no watchers registered.
+pub fn watch_files<C, S>(ctx: &Context<C, S>, id: &ServerID)
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    let state = ctx.state.read();
+    let watchers = state
+        .registrations
+        .get(id)
+        .into_iter()
+        .flatten()
+        .filter(|r| r.method == notification::DidChangeWatchedFiles::METHOD)
+        .filter_map(|r| r.register_options.clone())
+        .filter_map(|options| {
+            match serde_json::from_value::<DidChangeWatchedFilesRegistrationOptions>(options) {
+                Ok(options) => Some(options.watchers),
+                Err(err) => {
+                    log::error!("invalid file watchers registered by {}: {}", id, err);
+                    None
+                }
+            }
+        })
+        .flatten()
+        .collect();
+    let roots = state.roots_for(id);
+    drop(state);
+
+    let ignored = &ctx.config.file_watcher.ignored_dirs;
+    ctx.watcher.watch(id, &roots, ignored, watchers);
+}
+
+pub fn did_change_watched_files<C, S>(
+    ctx: &Context<C, S>,
+    id: &ServerID,
+    changes: Vec<FileEvent>,
+) -> Result<()>
+where
+    C: RPCClient,
+    S: RPCClient,
+{
+    ctx.notify_server(
+        id,
+        notification::DidChangeWatchedFiles::METHOD,
+        DidChangeWatchedFilesParams { changes },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::FakeClient;
+    use crate::state::State;
+    use lsp_types::{
+        ApplyWorkspaceEditParams, DocumentChanges, OneOf, OptionalVersionedTextDocumentIdentifier,
+        Position, Range, TextDocumentEdit, TextEdit, WorkspaceEdit,
+    };
+    use serde_json::json;
+
+    const FILENAME: &str = "/tmp/vlc-workspace-edit.rs";
+
+    // returns a context for a document that has changed once since it was opened.
+    fn context() -> Context<FakeClient, FakeClient> {
+        let mut state = State::default();
+        state.text_documents.open(FILENAME, "rust", "fn main() {}");
+        state
+            .text_documents
+            .change(FILENAME, "rust", "fn main() {\n}");
+        Context::fake(FakeClient::default(), vec![], state, FILENAME)
+    }
+
+    fn edit(version: i32) -> ApplyWorkspaceEditParams {
+        let edit = TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: Url::from_file_path(FILENAME).unwrap(),
+                version: Some(version),
+            },
+            edits: vec![OneOf::Left(TextEdit {
+                range: Range::new(Position::new(0, 3), Position::new(0, 7)),
+                new_text: "start".into(),
+            })],
+        };
+
+        ApplyWorkspaceEditParams {
+            label: None,
+            edit: WorkspaceEdit {
+                document_changes: Some(DocumentChanges::Edits(vec![edit])),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_apply_edit() {
+        let ctx = context();
+        let response = apply_edit(&ctx, &edit(1)).unwrap();
+        assert!(response.applied);
+        assert_eq!(response.failure_reason, None);
+
+        let applied = ctx.vim.notified("vlc#apply_edits");
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0][0][0]["changes"][0]["lines"], json!(["start"]));
+    }
+
+    #[test]
+    fn test_apply_edit_stale() {
+        let ctx = context();
+        let response = apply_edit(&ctx, &edit(0)).unwrap();
+        assert!(!response.applied);
+        assert_eq!(
+            response.failure_reason,
+            Some(format!("{} has changed since the edit was made", FILENAME))
+        );
+        assert!(ctx.vim.notified("vlc#apply_edits").is_empty());
+    }
+
+    #[test]
+    fn test_workspace_folders() {
+        let id = ServerID::new("rust", "rls", "/root/first");
+        let ctx = Context::fake(
+            FakeClient::default(),
+            vec![(id.clone(), FakeClient::default())],
+            State::default(),
+            FILENAME,
+        );
+        let folders = workspace_folders(&ctx).unwrap();
+        assert_eq!(folders.len(), 1);
+        assert_eq!(folders[0].uri.as_str(), "file:///root/first/");
+        assert_eq!(folders[0].name, "first");
+
+        // folders added since the server started come after the root it was started in.
+        ctx.state
+            .write()
+            .workspace_folders
+            .insert(id, vec!["/root/second".into()]);
+        let folders: Vec<_> = workspace_folders(&ctx)
+            .unwrap()
+            .into_iter()
+            .map(|folder| folder.name)
+            .collect();
+        assert_eq!(folders, vec!["first", "second"]);
+
+        let ctx: Context<FakeClient, FakeClient> =
+            Context::fake(FakeClient::default(), vec![], State::default(), FILENAME);
+        assert!(workspace_folders(&ctx).is_err());
+    }
+
+    #[test]
+    fn test_add_workspace_folder() {
+        let id = ServerID::new("rust", "rls", "/root/first");
+        let server = FakeClient::default();
+        let ctx = Context::fake(
+            FakeClient::default(),
+            vec![(id.clone(), server.clone())],
+            State::default(),
+            FILENAME,
+        );
+
+        add_workspace_folder(&ctx, &id, "/root/second").unwrap();
+        assert_eq!(
+            ctx.state.read().roots_for(&id),
+            vec!["/root/first", "/root/second"]
+        );
+        let changes = server.notified(notification::DidChangeWorkspaceFolders::METHOD);
+        assert_eq!(
+            changes,
+            vec![json!({
+                "event": {
+                    "added": [{"uri": "file:///root/second/", "name": "second"}],
+                    "removed": [],
+                },
+            })]
+        );
+
+        // roots that are not absolute paths can't be sent as folders.
+        assert!(add_workspace_folder(&ctx, &id, "relative").is_err());
+        assert_eq!(ctx.state.read().roots_for(&id).len(), 2);
+    }
+}
@@ -1,24 +1,82 @@
+mod changes;
 mod config;
+mod dispatcher;
+mod glob;
 mod language_client;
 mod lsp;
+mod project;
+mod replay;
 mod rpc;
 mod state;
 mod vim;
+mod watcher;
 
 use anyhow::Result;
 use config::Config;
 use language_client::LanguageClient;
+use replay::Replay;
 use rpc::RPCClient;
 use std::io::BufReader;
+use std::path::PathBuf;
 use std::str::FromStr;
 
+#[derive(Debug, Default)]
+struct Args {
+    // file to read the config from, the one set in vim is merged on top of it.
+    config: Option<PathBuf>,
+    // file to record every message exchanged with vim and the language servers to.
+    record: Option<PathBuf>,
+    // recording to replay instead of talking to vim and the language servers.
+    replay: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> Result<Args> {
+        let mut args = Args::default();
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--config" => args.config = Some(Args::value(&arg, argv.next())?),
+                "--record" => args.record = Some(Args::value(&arg, argv.next())?),
+                "--replay" => args.replay = Some(Args::value(&arg, argv.next())?),
+                _ => {}
+            }
+        }
+
+        Ok(args)
+    }
+
+    fn value(arg: &str, value: Option<String>) -> Result<PathBuf> {
+        let value = value.ok_or_else(|| anyhow::anyhow!("missing value for {}", arg))?;
+        Ok(shellexpand::tilde(&value).to_string().into())
+    }
+}
+
 fn main() -> Result<()> {
-    let vim = crate::rpc::Client::new(
-        rpc::ClientID::VIM,
-        BufReader::new(std::io::stdin()),
-        std::io::stdout(),
-    );
-    let config = Config::parse(&vim)?;
+    let args = Args::parse()?;
+    if let Some(path) = &args.record {
+        rpc::record_to(path)?;
+    }
+
+    let replay = args.replay.as_deref().map(Replay::load).transpose()?;
+    let vim = match &replay {
+        Some(replay) => replay.vim()?,
+        None => crate::rpc::Client::new(
+            rpc::ClientID::VIM,
+            BufReader::new(std::io::stdin()),
+            std::io::stdout(),
+        ),
+    };
+
+    // errors in the config have already been shown in vim by now, running without any servers
+    // keeps vim from losing the language client altogether.
+    let mut config = Config::parse(&vim, args.config.as_deref()).unwrap_or_else(|_| Config {
+        file: args.config.clone(),
+        ..Config::default()
+    });
+    if let Some(replay) = &replay {
+        replay.fake_servers(&mut config)?;
+    }
 
     fern::Dispatch::new()
         .format(|out, message, record| {
@@ -0,0 +1,270 @@
+// project-local configuration, read from a .vlc.json or .vlc.toml file at the root of a project
+// and merged over the configuration of the servers started in it. Project files can change the
+// command a server is run with, so they are only read for projects the user trusts.
+
+use crate::config::{self, Config, ServerConfig};
+use crate::rpc::{RPCClient, ServerID};
+use crate::state::State;
+use crate::vim::LogLevel;
+use anyhow::Result;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// names of the project file, looked for in this order.
+const FILES: &[&str] = &[".vlc.json", ".vlc.toml"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    // file the overrides were read from.
+    #[serde(skip)]
+    pub file: PathBuf,
+    // overrides for the config of each server, keyed by server name.
+    #[serde(default)]
+    pub servers: HashMap<String, Value>,
+}
+
+/// returns the config of the server with the overrides for it in the project file at its root
+/// merged over it.
+pub fn server_config<C: RPCClient>(
+    vim: &C,
+    config: &Config,
+    state: &RwLock<State>,
+    id: &ServerID,
+) -> Result<ServerConfig> {
+    let server_config = config.server(&id.language_id, &id.name)?;
+    let project = project_config(vim, config, state, &id.root);
+    match project
+        .as_ref()
+        .and_then(|p| Some((p, p.servers.get(&id.name)?)))
+    {
+        Some((project, overrides)) => apply(server_config, overrides, &project.file),
+        None => Ok(server_config.clone()),
+    }
+}
+
+// returns the project config at the given root, reading it the first time it is needed. Problems
+// with it are shown in vim when it is read.
+fn project_config<C: RPCClient>(
+    vim: &C,
+    config: &Config,
+    state: &RwLock<State>,
+    root: &str,
+) -> Option<ProjectConfig> {
+    if let Some(project) = state.read().projects.get(root) {
+        return project.clone();
+    }
+
+    let project = find(root).and_then(|file| {
+        if !config.is_trusted(root) {
+            let message = format!(
+                "{} is ignored, add {} to g:vlc#trusted_projects to use it",
+                file.display(),
+                root
+            );
+            show_message(vim, LogLevel::Warning, message);
+            return None;
+        }
+
+        match read(file) {
+            Ok(project) => {
+                validate(vim, config, &project);
+                Some(project)
+            }
+            Err(err) => {
+                show_message(vim, LogLevel::Error, format!("{:#}", err));
+                None
+            }
+        }
+    });
+
+    state.write().projects.insert(root.into(), project.clone());
+    project
+}
+
+fn find(root: &str) -> Option<PathBuf> {
+    FILES
+        .iter()
+        .map(|file| Path::new(root).join(file))
+        .find(|file| file.is_file())
+}
+
+fn read(file: PathBuf) -> Result<ProjectConfig> {
+    let value = config::read_file(&file)?;
+    let mut project: ProjectConfig = serde_path_to_error::deserialize(value)
+        .map_err(|err| anyhow::anyhow!("invalid {}: {}", file.display(), err))?;
+    project.file = file;
+    Ok(project)
+}
+
+// checks that the overrides in the project file apply to the servers configured for them, so that
+// mistakes are shown when the file is read rather than whenever a server is started.
+fn validate<C: RPCClient>(vim: &C, config: &Config, project: &ProjectConfig) {
+    for (name, overrides) in &project.servers {
+        let server_config = config.servers.values().flatten().find(|s| &s.name == name);
+        let server_config = match server_config {
+            Some(server_config) => server_config,
+            None => {
+                let message = format!("{}: no server named {}", project.file.display(), name);
+                show_message(vim, LogLevel::Warning, message);
+                continue;
+            }
+        };
+
+        let mut unknown = vec![];
+        let result = config::merge_server(server_config, overrides.clone(), &mut unknown);
+        for key in unknown {
+            let message = format!("{}: unknown key {}", project.file.display(), key);
+            show_message(vim, LogLevel::Warning, message);
+        }
+
+        if let Err(err) = result {
+            let message = format!("invalid {}: {}.{}", project.file.display(), name, err);
+            show_message(vim, LogLevel::Error, message);
+        }
+    }
+}
+
+fn apply(server_config: &ServerConfig, overrides: &Value, file: &Path) -> Result<ServerConfig> {
+    config::merge_server(server_config, overrides.clone(), &mut vec![])
+        .map_err(|err| anyhow::anyhow!("invalid {}: {}", file.display(), err))
+}
+
+fn show_message<C: RPCClient>(vim: &C, level: LogLevel, message: String) {
+    if let Err(err) = config::show_message(vim, level, message) {
+        log::error!("could not show message in vim: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::FakeClient;
+    use serde_json::json;
+
+    fn gopls() -> ServerConfig {
+        ServerConfig {
+            name: "gopls".into(),
+            command: "gopls".into(),
+            args: vec!["serve".into()],
+            settings: json!({
+                "gopls": {"staticcheck": false, "analyses": {"unusedparams": true}},
+                "other": 1,
+            }),
+            ..Default::default()
+        }
+    }
+
+    // returns a project root with the given project file in it.
+    fn project(name: &str, contents: &str) -> String {
+        let root = std::env::temp_dir().join(format!("vlc-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(".vlc.json"), contents).unwrap();
+        root.to_string_lossy().into_owned()
+    }
+
+    fn config(trusted_projects: Vec<String>) -> Config {
+        let mut servers = HashMap::new();
+        servers.insert("go".to_string(), vec![gopls()]);
+        Config {
+            servers,
+            trusted_projects,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_server() {
+        let overrides = json!({
+            "args": ["serve", "-rpc.trace"],
+            "changeDelay": 0,
+            "settings": {"gopls": {"staticcheck": true, "analyses": {"shadow": true}}},
+        });
+
+        let mut unknown = vec![];
+        let merged = config::merge_server(&gopls(), overrides, &mut unknown).unwrap();
+        assert!(unknown.is_empty());
+        assert_eq!(merged.command, "gopls");
+        // lists are replaced rather than merged.
+        assert_eq!(merged.args, vec!["serve", "-rpc.trace"]);
+        assert_eq!(merged.change_delay, 0);
+        assert_eq!(merged.restart_attempts, gopls().restart_attempts);
+        assert_eq!(
+            merged.settings,
+            json!({
+                "gopls": {
+                    "staticcheck": true,
+                    "analyses": {"unusedparams": true, "shadow": true},
+                },
+                "other": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_server_keeps_name() {
+        let overrides = json!({"name": "other", "command": "other"});
+        let merged = config::merge_server(&gopls(), overrides, &mut vec![]).unwrap();
+        assert_eq!(merged.name, "gopls");
+        assert_eq!(merged.command, "other");
+    }
+
+    #[test]
+    fn test_merge_server_errors() {
+        let mut unknown = vec![];
+        let overrides = json!({"comand": "other", "settings": {"anything": "goes"}});
+        let merged = config::merge_server(&gopls(), overrides, &mut unknown).unwrap();
+        assert_eq!(merged.command, "gopls");
+        assert_eq!(unknown, vec!["comand"]);
+
+        let overrides = json!({"features": {"completion": "no"}});
+        let err = config::merge_server(&gopls(), overrides, &mut vec![]).unwrap_err();
+        assert!(err.to_string().starts_with("features.completion"));
+    }
+
+    #[test]
+    fn test_server_config_trusted() {
+        let root = project(
+            "trusted",
+            r#"{"servers": {"gopls": {"settings": {"gopls": {"staticcheck": true}}}}}"#,
+        );
+        let vim = FakeClient::default();
+        let state = RwLock::new(State::default());
+        let id = ServerID::new("go", "gopls", &root);
+
+        let config = config(vec![root.clone()]);
+        let merged = server_config(&vim, &config, &state, &id).unwrap();
+        assert_eq!(merged.settings["gopls"]["staticcheck"], json!(true));
+        assert_eq!(
+            merged.settings["gopls"]["analyses"],
+            json!({"unusedparams": true})
+        );
+        assert!(vim.notified("vlc#show_message").is_empty());
+        assert!(state.read().projects[&root].is_some());
+    }
+
+    #[test]
+    fn test_server_config_untrusted() {
+        let root = project(
+            "untrusted",
+            r#"{"servers": {"gopls": {"command": "/tmp/evil"}}}"#,
+        );
+        let vim = FakeClient::default();
+        let state = RwLock::new(State::default());
+        let id = ServerID::new("go", "gopls", &root);
+
+        let config = config(vec!["/somewhere/else".into()]);
+        let merged = server_config(&vim, &config, &state, &id).unwrap();
+        assert_eq!(merged.command, "gopls");
+        assert!(state.read().projects[&root].is_none());
+
+        // the project being ignored is only shown the first time it is found.
+        server_config(&vim, &config, &state, &id).unwrap();
+        let messages = vim.notified("vlc#show_message");
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].to_string().contains("is ignored"));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::config::Config;
+use crate::rpc::{
+    self, ClientID, Direction, Message, RPCClient, RecordedMessage, ServerID, Transport,
+};
+use anyhow::Result;
+use crossbeam::channel::Receiver;
+use jsonrpc_core::Value;
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+// how long a fake client waits for the language client to send a message it has in the recording
+// before giving up on it.
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Replays a session recorded with --record. Vim and every language server in the recording are
+/// replaced by fake clients listening on local sockets, which send what the real ones sent and
+/// wait for the language client to send what it sent back then.
+pub struct Replay {
+    messages: Vec<RecordedMessage>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self> {
+        let messages = rpc::read_recording(path)?;
+        Ok(Self { messages })
+    }
+
+    /// starts the fake vim and returns a client connected to it.
+    pub fn vim(&self) -> Result<rpc::Client> {
+        let address = self.listen(ClientID::VIM)?;
+        let (reader, writer) = Transport::Tcp(address.to_string()).connect()?;
+        Ok(rpc::Client::new(ClientID::VIM, reader, writer))
+    }
+
+    /// starts a fake server for every language server in the recording, and points the config
+    /// for them to it.
+    pub fn fake_servers(&self, config: &mut Config) -> Result<()> {
+        // instances of a server that were started for different roots share its config, so they
+        // are told apart by the root they are initialized with.
+        let mut instances: HashMap<(String, String), Vec<ServerID>> = HashMap::new();
+        for message in &self.messages {
+            if let ClientID::LanguageServer(id) = &message.client {
+                let ids = instances
+                    .entry((id.language_id.clone(), id.name.clone()))
+                    .or_default();
+                if !ids.contains(id) {
+                    ids.push(id.clone());
+                }
+            }
+        }
+
+        for ((language_id, name), ids) in instances {
+            let address = self.listen_server(ids)?;
+            let server = config
+                .servers
+                .get_mut(&language_id)
+                .and_then(|servers| servers.iter_mut().find(|s| s.name == name));
+            match server {
+                Some(server) => {
+                    server.command = String::new();
+                    server.transport = Transport::Tcp(address.to_string());
+                }
+                None => log::warn!("replay: no server configured for {} {}", language_id, name),
+            }
+        }
+
+        Ok(())
+    }
+
+    // returns the messages exchanged with the given client.
+    fn messages_for(&self, client: &ClientID) -> Vec<RecordedMessage> {
+        self.messages
+            .iter()
+            .filter(|m| &m.client == client)
+            .cloned()
+            .collect()
+    }
+
+    // listens on a local socket and plays back the messages for the given client to the first
+    // connection it gets.
+    fn listen(&self, client: ClientID) -> Result<SocketAddr> {
+        let messages = self.messages_for(&client);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        std::thread::spawn(move || {
+            let res = listener
+                .accept()
+                .map_err(anyhow::Error::from)
+                .and_then(|(stream, _)| {
+                    let rx = read_stream(&stream)?;
+                    play(&client, messages, stream, rx, vec![])
+                });
+            if let Err(err) = res {
+                log::error!("replay {:?}: {}", client, err);
+            }
+        });
+
+        Ok(address)
+    }
+
+    // listens on a local socket for a connection to each of the instances of a server, and plays
+    // back the messages for the instance whose root each connection is initialized with.
+    fn listen_server(&self, ids: Vec<ServerID>) -> Result<SocketAddr> {
+        let mut instances: Vec<(ServerID, Vec<RecordedMessage>)> = ids
+            .into_iter()
+            .map(|id| {
+                let messages = self.messages_for(&ClientID::LanguageServer(id.clone()));
+                (id, messages)
+            })
+            .collect();
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        std::thread::spawn(move || {
+            while !instances.is_empty() {
+                let res = listener
+                    .accept()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|(stream, _)| {
+                        let rx = read_stream(&stream)?;
+                        let first = rx.recv_timeout(EXPECT_TIMEOUT)?;
+                        let idx = instance_for(&instances, &first).ok_or_else(|| {
+                            anyhow::anyhow!("no recorded server matches {:?}", first)
+                        })?;
+                        let (id, messages) = instances.remove(idx);
+                        std::thread::spawn(move || {
+                            let client = ClientID::LanguageServer(id);
+                            if let Err(err) = play(&client, messages, stream, rx, vec![first]) {
+                                log::error!("replay {:?}: {}", client, err);
+                            }
+                        });
+                        Ok(())
+                    });
+                if let Err(err) = res {
+                    log::error!("replay: {}", err);
+                    return;
+                }
+            }
+        });
+
+        Ok(address)
+    }
+}
+
+// returns which of the recorded instances of a server a connection is for, going by the root it
+// is initialized with.
+fn instance_for(instances: &[(ServerID, Vec<RecordedMessage>)], first: &Message) -> Option<usize> {
+    let root = match first {
+        Message::MethodCall(call) => Value::from(call.params.clone())
+            .get("rootPath")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+        _ => None,
+    };
+
+    match root {
+        Some(root) => instances.iter().position(|(id, _)| id.root == root),
+        None if instances.len() == 1 => Some(0),
+        None => None,
+    }
+}
+
+// reads the messages sent by the language client on the stream.
+fn read_stream(stream: &TcpStream) -> Result<Receiver<Message>> {
+    let (tx, rx) = crossbeam::channel::unbounded();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    std::thread::spawn(move || {
+        while let Ok(Some(message)) = rpc::read_message(&mut reader) {
+            if tx.send(message).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+// plays back the recorded messages on the stream, received being the messages already read from
+// it that have not been matched with the recording yet.
+fn play(
+    client: &ClientID,
+    messages: Vec<RecordedMessage>,
+    stream: TcpStream,
+    rx: Receiver<Message>,
+    mut received: Vec<Message>,
+) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    // ids the language client used for its requests, keyed by the id they had in the recording.
+    let mut ids = HashMap::new();
+    for entry in messages {
+        match entry.direction {
+            Direction::Outgoing => match expect(&entry.message, &mut received, &rx) {
+                Some(Message::MethodCall(actual)) => {
+                    ids.insert(entry.message.id(), actual.id);
+                }
+                Some(_) => {}
+                None => log::warn!(
+                    "replay {:?}: expected message was never sent: {}",
+                    client,
+                    serde_json::to_string(&entry.message)?
+                ),
+            },
+            Direction::Incoming => {
+                let mut message = entry.message;
+                if let Message::Output(output) = &mut message {
+                    let id = match output {
+                        jsonrpc_core::Output::Success(s) => &mut s.id,
+                        jsonrpc_core::Output::Failure(f) => &mut f.id,
+                    };
+                    if let Some(actual) = ids.get(id) {
+                        *id = actual.clone();
+                    }
+                }
+
+                rpc::write_message(&mut writer, &message)?;
+            }
+        }
+    }
+
+    for message in received {
+        log::warn!(
+            "replay {:?}: unexpected message: {}",
+            client,
+            serde_json::to_string(&message)?
+        );
+    }
+
+    log::info!("replay {:?} finished", client);
+    stream.shutdown(Shutdown::Both)?;
+    Ok(())
+}
+
+// waits for the language client to send a message like the recorded one, keeping any other
+// message it sends in the meantime for later.
+fn expect(
+    recorded: &Message,
+    received: &mut Vec<Message>,
+    rx: &Receiver<Message>,
+) -> Option<Message> {
+    if let Some(idx) = received.iter().position(|m| matches(recorded, m)) {
+        return Some(received.remove(idx));
+    }
+
+    while let Ok(message) = rx.recv_timeout(EXPECT_TIMEOUT) {
+        if matches(recorded, &message) {
+            return Some(message);
+        }
+
+        received.push(message);
+    }
+
+    None
+}
+
+// requests and notifications are matched by method, since ids of requests made by the language
+// client may differ between runs. Responses are matched by id, as they answer requests whose ids
+// come from the recording.
+fn matches(recorded: &Message, actual: &Message) -> bool {
+    match (recorded, actual) {
+        (Message::MethodCall(r), Message::MethodCall(a)) => r.method == a.method,
+        (Message::Notification(r), Message::Notification(a)) => r.method == a.method,
+        (Message::Output(_), Message::Output(_)) => recorded.id() == actual.id(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::rpc::{write_entry, Direction};
+    use serde_json::json;
+
+    fn message(value: Value) -> Message {
+        serde_json::from_value(value).unwrap()
+    }
+
+    // records a session with vim and two instances of gopls, one for each of two roots.
+    fn recording(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("vlc-{}-{}.jsonl", std::process::id(), name));
+        let mut file = std::fs::File::create(&path).unwrap();
+        let mut entries = vec![(
+            ClientID::VIM,
+            Direction::Incoming,
+            message(json!({"jsonrpc": "2.0", "method": "start", "params": {}, "id": 1})),
+        )];
+        for root in &["/a", "/b"] {
+            let server = ClientID::LanguageServer(ServerID::new("go", "gopls", root));
+            let initialize = json!({
+                "jsonrpc": "2.0",
+                "method": "initialize",
+                "params": {"rootPath": root},
+                "id": 0,
+            });
+            let result = json!({"jsonrpc": "2.0", "result": {"root": root}, "id": 0});
+            entries.push((server.clone(), Direction::Outgoing, message(initialize)));
+            entries.push((server, Direction::Incoming, message(result)));
+        }
+
+        for (client, direction, message) in entries {
+            write_entry(&mut file, &client, direction, &message).unwrap();
+        }
+
+        path
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        let gopls = ServerConfig {
+            name: "gopls".into(),
+            command: "gopls".into(),
+            ..Default::default()
+        };
+        config.servers.insert("go".into(), vec![gopls]);
+        config
+    }
+
+    #[test]
+    fn test_replay_vim() {
+        let replay = Replay::load(&recording("replay-vim")).unwrap();
+        let vim = replay.vim().unwrap();
+        let message = vim.get_reader().recv_timeout(EXPECT_TIMEOUT).unwrap();
+        match message {
+            Message::MethodCall(call) => assert_eq!(call.method, "start"),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_replay_servers_by_root() {
+        let replay = Replay::load(&recording("replay-servers")).unwrap();
+        let mut config = config();
+        replay.fake_servers(&mut config).unwrap();
+
+        let server = config.server("go", "gopls").unwrap();
+        assert!(server.command.is_empty());
+        let transport = server.transport.clone();
+        assert!(matches!(transport, Transport::Tcp(_)));
+
+        // instances connect in whatever order they are started in.
+        for root in &["/b", "/a"] {
+            let (reader, writer) = transport.connect().unwrap();
+            let id = ServerID::new("go", "gopls", root);
+            let client = rpc::Client::new(ClientID::LanguageServer(id), reader, writer);
+            let result: Value = client
+                .call_timeout("initialize", json!({ "rootPath": root }), EXPECT_TIMEOUT)
+                .unwrap();
+            assert_eq!(result, json!({ "root": root }));
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use super::{CallHandle, ClientID, Message, RPCClient};
+use anyhow::Result;
+use crossbeam::channel::Receiver;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The result or the error a method call was answered with.
+pub type Reply = Result<Value, jsonrpc_core::Error>;
+
+/// Code run whenever a method is called through a fake client, standing in for whatever happens
+/// while the call is in flight.
+#[derive(Clone)]
+pub struct OnCall(pub Arc<dyn Fn(&str) + Send + Sync>);
+
+impl std::fmt::Debug for OnCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("OnCall")
+    }
+}
+
+/// Client that answers calls with canned responses and records the notifications sent through it,
+/// standing in for vim or a language server in tests.
+#[derive(Debug, Clone, Default)]
+pub struct FakeClient {
+    // response to every call, keyed by method.
+    pub responses: HashMap<String, Value>,
+    // method calls made through it, with their params.
+    pub calls: Arc<Mutex<Vec<(String, Value)>>>,
+    pub on_call: Option<OnCall>,
+    pub notifications: Arc<Mutex<Vec<(String, Value)>>>,
+    // replies sent to method calls, either their result or their error.
+    pub replies: Arc<Mutex<Vec<(jsonrpc_core::Id, Reply)>>>,
+}
+
+impl FakeClient {
+    pub fn with_response(method: &str, response: Value) -> Self {
+        let mut client = Self::default();
+        client.responses.insert(method.into(), response);
+        client
+    }
+
+    // records a call to the method and runs the code set to run on calls, if any.
+    fn record_call(&self, method: &str, params: Value) {
+        self.calls.lock().push((method.into(), params));
+        if let Some(OnCall(f)) = &self.on_call {
+            f(method);
+        }
+    }
+
+    /// returns the params of the calls made to the method so far.
+    pub fn called(&self, method: &str) -> Vec<Value> {
+        self.calls
+            .lock()
+            .iter()
+            .filter(|(m, _)| m == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+
+    /// returns the params of the notifications sent for the method so far.
+    pub fn notified(&self, method: &str) -> Vec<Value> {
+        self.notifications
+            .lock()
+            .iter()
+            .filter(|(m, _)| m == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+}
+
+impl RPCClient for FakeClient {
+    fn new<I, O>(_: ClientID, _: I, _: O) -> Self
+    where
+        I: std::io::BufRead + Unpin + Send + 'static,
+        O: std::io::Write + Unpin + Send + 'static,
+    {
+        Self::default()
+    }
+
+    fn get_reader(&self) -> Receiver<Message> {
+        crossbeam::channel::never()
+    }
+
+    fn reply_success(&self, id: &jsonrpc_core::Id, message: Value) -> Result<()> {
+        self.replies.lock().push((id.clone(), Ok(message)));
+        Ok(())
+    }
+
+    fn reply_failure(&self, id: &jsonrpc_core::Id, error: jsonrpc_core::Error) -> Result<()> {
+        self.replies.lock().push((id.clone(), Err(error)));
+        Ok(())
+    }
+
+    fn call<M, R>(&self, method: &str, message: M) -> Result<R>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+    {
+        let params = serde_json::to_value(message)?;
+        self.record_call(method, params);
+        match self.responses.get(method) {
+            Some(response) => Ok(serde_json::from_value(response.clone())?),
+            None => anyhow::bail!("no response for {}", method),
+        }
+    }
+
+    fn call_cancellable<M, R>(&self, method: &str, message: M) -> Result<CallHandle<R>>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+    {
+        let params = serde_json::to_value(message)?;
+        self.record_call(method, params);
+        // the handle is answered right away with the response to the method.
+        let result = match self.responses.get(method) {
+            Some(response) => response.clone(),
+            None => anyhow::bail!("no response for {}", method),
+        };
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        let id = jsonrpc_core::Id::Num(0);
+        tx.send(jsonrpc_core::Output::Success(jsonrpc_core::Success {
+            jsonrpc: Some(jsonrpc_core::Version::V2),
+            result,
+            id: id.clone(),
+        }))?;
+        let (writer_tx, _) = crossbeam::channel::unbounded();
+        let (pending_tx, _) = crossbeam::channel::unbounded();
+        Ok(CallHandle::new(id, method, rx, writer_tx, pending_tx))
+    }
+
+    fn notify<M>(&self, method: &str, message: M) -> Result<()>
+    where
+        M: Serialize,
+    {
+        let params = serde_json::to_value(message)?;
+        self.notifications.lock().push((method.into(), params));
+        Ok(())
+    }
+}
@@ -1,22 +1,30 @@
+#[cfg(test)]
+mod fake;
 mod protocol;
+mod recorder;
+mod transport;
 
 use anyhow::Result;
 use crossbeam::channel::{Receiver, Sender};
+#[cfg(test)]
+pub use fake::*;
 pub use protocol::*;
+pub use recorder::*;
 use serde::{de::DeserializeOwned, Serialize};
 use std::collections::HashMap;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, Read, Write};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc,
 };
+pub use transport::*;
 
 #[derive(Debug)]
 pub struct Client {
     server_id: ClientID,
     reader_rx: Receiver<Message>,
     writer_tx: Sender<Message>,
-    pending_tx: Sender<(jsonrpc_core::Id, Sender<jsonrpc_core::Output>)>,
+    pending_tx: Sender<PendingRequest>,
     id: Arc<AtomicU64>,
 }
 
@@ -37,30 +45,138 @@ where
     O: Write + Unpin + Send + 'static,
 {
     for message in receiver.iter() {
-        let message = serde_json::to_string(&message)?;
         log::debug!(
             "{:?} [thread: {:?}] <== {}\n",
             server_id,
             std::thread::current().id(),
-            message
+            serde_json::to_string(&message)?
         );
-        let message = message + "\r\n";
+        record(&server_id, Direction::Outgoing, &message);
+        write_message(&mut writer, &message)?;
+    }
 
-        let message = message.as_bytes();
-        let headers = format!("Content-Length: {}\r\n\r\n", message.len());
+    Ok(())
+}
 
-        writer.write_all(headers.as_bytes())?;
-        writer.write_all(message)?;
-        writer.flush()?;
-    }
+/// writes a single message, along with its headers.
+pub fn write_message<O>(writer: &mut O, message: &Message) -> Result<()>
+where
+    O: Write,
+{
+    let message = serde_json::to_string(message)? + "\r\n";
+    let message = message.as_bytes();
+    let headers = format!("Content-Length: {}\r\n\r\n", message.len());
 
+    writer.write_all(headers.as_bytes())?;
+    writer.write_all(message)?;
+    writer.flush()?;
     Ok(())
 }
 
+// frames with a body larger than this are discarded without being parsed.
+const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+
+// reads the header part of a frame, up to and including the blank line that separates it from the
+// content. Header names are lowercased so they can be matched case-insensitively. Lines that are
+// not valid headers are skipped, which lets the reader resynchronise after a malformed frame.
+// Returns None when the input has been closed.
+fn read_headers<I>(reader: &mut I) -> Result<Option<HashMap<String, String>>>
+where
+    I: BufRead,
+{
+    let mut headers = HashMap::new();
+    loop {
+        // lines are read as bytes so that invalid utf-8 in them is skipped like any other invalid
+        // header instead of failing the read.
+        let mut line = vec![];
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim();
+        if line.is_empty() {
+            // stray blank lines before the headers, e.g. trailing newlines after a message body.
+            if headers.is_empty() {
+                continue;
+            }
+
+            return Ok(Some(headers));
+        }
+
+        match line.split_once(':') {
+            Some((name, value)) => {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+            }
+            None => log::warn!("skipping invalid header line: {}", line),
+        }
+    }
+}
+
+/// reads a single message. Returns None once the input has been closed, and an InvalidFrame error
+/// if the frame could not be read, in which case the reader is left at the start of the next one.
+pub fn read_message<I>(reader: &mut I) -> Result<Option<Message>>
+where
+    I: BufRead,
+{
+    let headers = match read_headers(reader)? {
+        Some(headers) => headers,
+        None => return Ok(None),
+    };
+
+    let content_length = match headers.get("content-length").map(|v| v.parse::<usize>()) {
+        Some(Ok(content_length)) => content_length,
+        _ => {
+            return Err(Error::InvalidFrame(format!(
+                "frame without a valid Content-Length: {:?}",
+                headers
+            ))
+            .into())
+        }
+    };
+
+    if content_length > MAX_CONTENT_LENGTH {
+        std::io::copy(
+            &mut reader.by_ref().take(content_length as u64),
+            &mut std::io::sink(),
+        )?;
+        return Err(Error::InvalidFrame(format!(
+            "frame of {} bytes, maximum is {}",
+            content_length, MAX_CONTENT_LENGTH
+        ))
+        .into());
+    }
+
+    let mut message = vec![0 as u8; content_length];
+    reader.read_exact(&mut message)?;
+    let message = String::from_utf8(message)
+        .map_err(|err| Error::InvalidFrame(format!("frame with invalid utf-8: {}", err)))?;
+    let message = serde_json::from_str(message.as_str())
+        .map_err(|err| Error::InvalidFrame(format!("malformed message: {}", err)))?;
+    Ok(Some(message))
+}
+
+// applies the requests that were sent or cancelled since the last response was read.
+fn update_pending(
+    pending_outputs: &mut HashMap<jsonrpc_core::Id, Sender<jsonrpc_core::Output>>,
+    pending_receiver: &Receiver<PendingRequest>,
+) {
+    while let Ok(pending) = pending_receiver.try_recv() {
+        match pending {
+            PendingRequest::Register(id, tx) => {
+                pending_outputs.insert(id, tx);
+            }
+            PendingRequest::Cancel(id) => {
+                pending_outputs.remove(&id);
+            }
+        }
+    }
+}
+
 fn loop_read<I>(
     server_id: ClientID,
     mut reader: I,
-    pending_receiver: Receiver<(jsonrpc_core::Id, Sender<jsonrpc_core::Output>)>,
+    pending_receiver: Receiver<PendingRequest>,
     sender: Sender<Message>,
 ) -> Result<()>
 where
@@ -68,34 +184,47 @@ where
 {
     let mut pending_outputs = HashMap::new();
     loop {
-        let mut content_length = String::new();
-        reader.read_line(&mut content_length)?;
-        let content_length: String = content_length.trim().split(':').skip(1).take(1).collect();
-        let content_length = content_length.trim().parse()?;
-
-        let mut content_type = String::new();
-        reader.read_line(&mut content_type)?;
+        let message = match read_message(&mut reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                log::debug!("{:?} reached end of input", server_id);
+                return Ok(());
+            }
+            Err(err) => match err.downcast_ref::<Error>() {
+                Some(Error::InvalidFrame(_)) => {
+                    log::error!("{:?} {}", server_id, err);
+                    continue;
+                }
+                _ => return Err(err),
+            },
+        };
 
-        let mut message = vec![0 as u8; content_length];
-        reader.read_exact(&mut message)?;
-        let message = String::from_utf8(message)?;
         log::debug!(
             "{:?} [thread: {:?}] ==> {}",
             server_id,
             std::thread::current().id(),
-            message
+            serde_json::to_string(&message)?
         );
+        record(&server_id, Direction::Incoming, &message);
 
-        let message: Message = serde_json::from_str(message.as_str())?;
         let message_id = message.id();
         match message {
             Message::Output(output) => {
-                while let Ok((id, tx)) = pending_receiver.try_recv() {
-                    pending_outputs.insert(id, tx);
-                }
-
-                if let Some(tx) = pending_outputs.remove(&message_id) {
-                    tx.send(output)?;
+                update_pending(&mut pending_outputs, &pending_receiver);
+                match pending_outputs.remove(&message_id) {
+                    // the receiving end may have gone away if the caller stopped waiting for
+                    // this response, that is not a reason to stop reading.
+                    Some(tx) => {
+                        let _ = tx.send(output);
+                    }
+                    None => match output {
+                        jsonrpc_core::Output::Failure(f)
+                            if f.error.code.code() == REQUEST_CANCELLED =>
+                        {
+                            log::debug!("request {:?} was cancelled", message_id)
+                        }
+                        _ => log::debug!("discarding response to request {:?}", message_id),
+                    },
                 }
             }
             _ => {
@@ -105,13 +234,21 @@ where
     }
 }
 
+// lsp request ids have to fit in an i32, the counter wraps around before going past that so that
+// every request can be cancelled by its id.
+const MAX_REQUEST_ID: u64 = i32::MAX as u64;
+
+fn next_id(counter: &AtomicU64) -> u64 {
+    counter.fetch_add(1, Ordering::SeqCst) % (MAX_REQUEST_ID + 1)
+}
+
 impl RPCClient for Client {
     fn new<I, O>(server_id: ClientID, reader: I, writer: O) -> Self
     where
         I: BufRead + Unpin + Send + 'static,
         O: Write + Unpin + Send + 'static,
     {
-        let (pending_tx, pending_rx) = crossbeam::channel::bounded(1);
+        let (pending_tx, pending_rx) = crossbeam::channel::unbounded();
         let (reader_tx, reader_rx) = crossbeam::channel::unbounded();
         {
             let server_id = server_id.clone();
@@ -166,6 +303,21 @@ impl RPCClient for Client {
         Ok(())
     }
 
+    fn reply_failure(
+        &self,
+        message_id: &jsonrpc_core::Id,
+        error: jsonrpc_core::Error,
+    ) -> Result<()> {
+        let message = jsonrpc_core::Output::Failure(jsonrpc_core::Failure {
+            jsonrpc: Some(jsonrpc_core::Version::V2),
+            error,
+            id: message_id.clone(),
+        });
+
+        self.writer_tx.send(Message::Output(message))?;
+        Ok(())
+    }
+
     fn get_reader(&self) -> Receiver<Message> {
         self.reader_rx.clone()
     }
@@ -185,12 +337,20 @@ impl RPCClient for Client {
     }
 
     fn call<M, R>(&self, method: &str, message: M) -> Result<R>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+    {
+        self.call_cancellable(method, message)?.wait()
+    }
+
+    fn call_cancellable<M, R>(&self, method: &str, message: M) -> Result<CallHandle<R>>
     where
         M: Serialize,
         R: DeserializeOwned,
     {
         let (tx, rx) = crossbeam::channel::bounded(1);
-        let id = self.id.fetch_add(1, Ordering::SeqCst);
+        let id = next_id(&self.id);
 
         let message = jsonrpc_core::MethodCall {
             jsonrpc: Some(jsonrpc_core::Version::V2),
@@ -199,13 +359,270 @@ impl RPCClient for Client {
             id: jsonrpc_core::Id::Num(id),
         };
 
-        self.pending_tx.send((jsonrpc_core::Id::Num(id), tx))?;
+        self.pending_tx
+            .send(PendingRequest::Register(jsonrpc_core::Id::Num(id), tx))?;
         self.writer_tx.send(Message::MethodCall(message))?;
 
-        let message = rx.recv()?;
-        match message {
-            jsonrpc_core::Output::Success(s) => Ok(serde_json::from_value(s.result)?),
-            jsonrpc_core::Output::Failure(s) => Err(anyhow::anyhow!(s.error)),
+        Ok(CallHandle::new(
+            jsonrpc_core::Id::Num(id),
+            method,
+            rx,
+            self.writer_tx.clone(),
+            self.pending_tx.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn frame(body: &str) -> Vec<u8> {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+    }
+
+    const GOOD: &str = r#"{"jsonrpc":"2.0","method":"good","params":null,"id":7}"#;
+
+    // reads the first frame, which is expected to be invalid, followed by the good one.
+    fn assert_resynchronises(bad: &[u8]) {
+        let mut input = bad.to_vec();
+        input.extend(frame(GOOD));
+        let mut reader = Cursor::new(input.as_slice());
+
+        let err = read_message(&mut reader).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<Error>(), Some(Error::InvalidFrame(_))),
+            "unexpected error {}",
+            err
+        );
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message.id(), jsonrpc_core::Id::Num(7));
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_lowercase_and_extra_headers() {
+        let input = format!(
+            "content-length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}",
+            GOOD.len(),
+            GOOD
+        );
+        let mut reader = Cursor::new(input.as_bytes());
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message.id(), jsonrpc_core::Id::Num(7));
+    }
+
+    #[test]
+    fn skips_stray_blank_lines() {
+        let mut input = b"\r\n\n\r\n".to_vec();
+        input.extend(frame(GOOD));
+        input.extend(b"\r\n\r\n");
+        input.extend(frame(GOOD));
+        let mut reader = Cursor::new(input.as_slice());
+
+        for _ in 0..2 {
+            let message = read_message(&mut reader).unwrap().unwrap();
+            assert_eq!(message.id(), jsonrpc_core::Id::Num(7));
         }
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_invalid_header_lines() {
+        let mut input = b"garbage\r\n\xff\xfe: \xc3\r\n".to_vec();
+        input.extend(frame(GOOD));
+        let mut reader = Cursor::new(input.as_slice());
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message.id(), jsonrpc_core::Id::Num(7));
+    }
+
+    #[test]
+    fn resynchronises_after_missing_content_length() {
+        assert_resynchronises(b"Content-Type: text/plain\r\n\r\n");
+    }
+
+    #[test]
+    fn resynchronises_after_unparseable_content_length() {
+        assert_resynchronises(b"Content-Length: 99999999999999999999999\r\n\r\n");
+    }
+
+    #[test]
+    fn resynchronises_after_oversized_content_length() {
+        let mut bad = format!("Content-Length: {}\r\n\r\n", MAX_CONTENT_LENGTH + 1).into_bytes();
+        bad.resize(bad.len() + MAX_CONTENT_LENGTH + 1, b' ');
+        assert_resynchronises(&bad);
+    }
+
+    #[test]
+    fn resynchronises_after_invalid_utf8_body() {
+        let mut bad = b"Content-Length: 4\r\n\r\n".to_vec();
+        bad.extend(b"\xff\xfe\xfd\xfc");
+        assert_resynchronises(&bad);
+    }
+
+    #[test]
+    fn resynchronises_after_malformed_json() {
+        assert_resynchronises(&frame("{\"jsonrpc\":"));
+    }
+
+    #[test]
+    fn round_trips_written_messages() {
+        let message: Message = serde_json::from_str(GOOD).unwrap();
+        let mut output = vec![];
+        write_message(&mut output, &message).unwrap();
+        let mut reader = Cursor::new(output.as_slice());
+        let read = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(read.id(), message.id());
+    }
+
+    // returns a handle to the request with the given id, along with the end its response is sent
+    // on and the channels it writes and cancels on.
+    #[allow(clippy::type_complexity)]
+    fn call_handle(
+        id: u64,
+    ) -> (
+        CallHandle<serde_json::Value>,
+        Sender<jsonrpc_core::Output>,
+        Receiver<Message>,
+        Receiver<PendingRequest>,
+    ) {
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        let (writer_tx, writer_rx) = crossbeam::channel::unbounded();
+        let (pending_tx, pending_rx) = crossbeam::channel::unbounded();
+        let id = jsonrpc_core::Id::Num(id);
+        let handle = CallHandle::new(id, "test", rx, writer_tx, pending_tx);
+        (handle, tx, writer_rx, pending_rx)
+    }
+
+    fn assert_cancelled(
+        id: u64,
+        writer_rx: &Receiver<Message>,
+        pending_rx: &Receiver<PendingRequest>,
+    ) {
+        assert!(matches!(
+            pending_rx.try_recv(),
+            Ok(PendingRequest::Cancel(jsonrpc_core::Id::Num(i))) if i == id
+        ));
+        match writer_rx.try_recv() {
+            Ok(Message::Notification(n)) => {
+                assert_eq!(n.method, "$/cancelRequest");
+                assert_eq!(
+                    serde_json::Value::from(n.params),
+                    serde_json::json!({ "id": id })
+                );
+            }
+            message => panic!("expected a cancellation, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn request_ids_stay_within_lsp_range() {
+        let counter = AtomicU64::new(MAX_REQUEST_ID - 1);
+        assert_eq!(next_id(&counter), MAX_REQUEST_ID - 1);
+        assert_eq!(next_id(&counter), MAX_REQUEST_ID);
+        assert_eq!(next_id(&counter), 0);
+        assert_eq!(next_id(&counter), 1);
+    }
+
+    #[test]
+    fn dropping_a_call_handle_cancels_it() {
+        let (handle, _tx, writer_rx, pending_rx) = call_handle(3);
+        drop(handle);
+        assert_cancelled(3, &writer_rx, &pending_rx);
+
+        // the largest id there is can be cancelled too.
+        let (handle, _tx, writer_rx, pending_rx) = call_handle(MAX_REQUEST_ID);
+        handle.cancel();
+        assert_cancelled(MAX_REQUEST_ID, &writer_rx, &pending_rx);
+    }
+
+    #[test]
+    fn answered_requests_are_not_cancelled() {
+        let (handle, tx, writer_rx, pending_rx) = call_handle(3);
+        tx.send(jsonrpc_core::Output::Success(jsonrpc_core::Success {
+            jsonrpc: Some(jsonrpc_core::Version::V2),
+            result: serde_json::json!("done"),
+            id: jsonrpc_core::Id::Num(3),
+        }))
+        .unwrap();
+
+        assert_eq!(handle.wait().unwrap(), serde_json::json!("done"));
+        assert!(writer_rx.try_recv().is_err());
+        assert!(pending_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn wait_timeout_cancels_the_request() {
+        let (handle, _tx, writer_rx, pending_rx) = call_handle(5);
+        let err = handle
+            .wait_timeout(std::time::Duration::from_millis(10))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Timeout { .. })
+        ));
+        assert_cancelled(5, &writer_rx, &pending_rx);
+    }
+
+    #[test]
+    fn cancelled_requests_are_no_longer_pending() {
+        let (writer_tx, _writer_rx) = crossbeam::channel::unbounded();
+        let (pending_tx, pending_rx) = crossbeam::channel::unbounded();
+        let mut pending = HashMap::new();
+        let mut handles = vec![];
+        for id in 1..=2 {
+            let (tx, rx) = crossbeam::channel::bounded(1);
+            let id = jsonrpc_core::Id::Num(id);
+            pending_tx
+                .send(PendingRequest::Register(id.clone(), tx))
+                .unwrap();
+            let handle: CallHandle<serde_json::Value> =
+                CallHandle::new(id, "test", rx, writer_tx.clone(), pending_tx.clone());
+            handles.push(handle);
+        }
+        update_pending(&mut pending, &pending_rx);
+        assert_eq!(pending.len(), 2);
+
+        drop(handles.remove(0));
+        update_pending(&mut pending, &pending_rx);
+        assert!(!pending.contains_key(&jsonrpc_core::Id::Num(1)));
+        assert!(pending.contains_key(&jsonrpc_core::Id::Num(2)));
+    }
+
+    #[test]
+    fn wait_timeout_expires() {
+        let (handle, _tx, _writer_rx, _pending_rx) = call_handle(5);
+        let timeout = std::time::Duration::from_millis(50);
+        let err = handle.wait_timeout(timeout).unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::Timeout { method, elapsed }) => {
+                assert_eq!(method, "test");
+                assert!(*elapsed >= timeout);
+            }
+            _ => panic!("expected a timeout, got {}", err),
+        }
+        assert!(err.to_string().starts_with("request test timed out after"));
+    }
+
+    #[test]
+    fn wait_timeout_returns_the_response() {
+        let (handle, tx, writer_rx, pending_rx) = call_handle(5);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            tx.send(jsonrpc_core::Output::Failure(jsonrpc_core::Failure {
+                jsonrpc: Some(jsonrpc_core::Version::V2),
+                error: jsonrpc_core::Error::invalid_params("bad"),
+                id: jsonrpc_core::Id::Num(5),
+            }))
+        });
+
+        let err = handle
+            .wait_timeout(std::time::Duration::from_secs(10))
+            .unwrap_err();
+        assert!(err.downcast_ref::<Error>().is_none());
+        assert!(err.to_string().contains("bad"));
+        assert!(writer_rx.try_recv().is_err());
+        assert!(pending_rx.try_recv().is_err());
     }
 }
@@ -1,7 +1,16 @@
 use anyhow::Result;
+use crossbeam::channel::{Receiver, Sender};
 use jsonrpc_core::Params;
+use lsp_types::notification::{self, Notification};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+// error code sent by language servers in the reply to a request that was cancelled with
+// $/cancelRequest.
+pub const REQUEST_CANCELLED: i64 = -32800;
 
 pub trait RPCClient: Send + Sync + Clone + 'static {
     fn new<I, O>(server_id: ClientID, reader: I, writer: O) -> Self
@@ -10,19 +19,61 @@ pub trait RPCClient: Send + Sync + Clone + 'static {
         O: std::io::Write + Unpin + Send + 'static;
     fn get_reader(&self) -> crossbeam::channel::Receiver<Message>;
     fn reply_success(&self, id: &jsonrpc_core::Id, message: serde_json::Value) -> Result<()>;
+    fn reply_failure(&self, id: &jsonrpc_core::Id, error: jsonrpc_core::Error) -> Result<()>;
     fn call<M, R>(&self, method: &str, message: M) -> Result<R>
     where
         M: Serialize,
         R: DeserializeOwned;
+    // sends a method call and returns a handle to the in-flight request instead of blocking until
+    // the response arrives. Dropping the handle before it yields a response cancels the request.
+    fn call_cancellable<M, R>(&self, method: &str, message: M) -> Result<CallHandle<R>>
+    where
+        M: Serialize,
+        R: DeserializeOwned;
+    // same as call, but gives up on the request and cancels it if no response arrives within the
+    // given timeout.
+    fn call_timeout<M, R>(&self, method: &str, message: M, timeout: Duration) -> Result<R>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+    {
+        self.call_cancellable(method, message)?
+            .wait_timeout(timeout)
+    }
     fn notify<M>(&self, method: &str, message: M) -> Result<()>
     where
         M: Serialize;
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub enum ClientID {
     VIM,
-    LanguageServer,
+    LanguageServer(ServerID),
+}
+
+/// Identifies a running language server by the language id it was started for, the name it has
+/// in the config and the root of the workspace it was started in.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct ServerID {
+    pub language_id: String,
+    pub name: String,
+    pub root: String,
+}
+
+impl ServerID {
+    pub fn new(language_id: &str, name: &str, root: &str) -> Self {
+        Self {
+            language_id: language_id.into(),
+            name: name.into(),
+            root: root.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ServerID {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}, {})", self.name, self.language_id, self.root)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,6 +97,149 @@ impl Message {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("request {method} timed out after {elapsed:?}")]
+    Timeout { method: String, elapsed: Duration },
+    #[error("method not found: {0}")]
+    MethodNotFound(String),
+    #[error("discarding invalid {0}")]
+    InvalidFrame(String),
+}
+
+// builds the error object sent back in reply to a method call that could not be handled.
+pub fn to_rpc_error(err: &anyhow::Error) -> jsonrpc_core::Error {
+    let code = match err.downcast_ref::<Error>() {
+        Some(Error::Timeout { .. }) => jsonrpc_core::ErrorCode::ServerError(REQUEST_CANCELLED),
+        Some(Error::MethodNotFound(_)) => jsonrpc_core::ErrorCode::MethodNotFound,
+        Some(Error::InvalidFrame(_)) => jsonrpc_core::ErrorCode::ParseError,
+        None if err.is::<serde_json::Error>() => jsonrpc_core::ErrorCode::InvalidParams,
+        None => jsonrpc_core::ErrorCode::InternalError,
+    };
+
+    jsonrpc_core::Error {
+        code,
+        message: err.to_string(),
+        data: None,
+    }
+}
+
+// messages sent to the reader thread to keep track of requests waiting for a response.
+#[derive(Debug)]
+pub enum PendingRequest {
+    Register(jsonrpc_core::Id, Sender<jsonrpc_core::Output>),
+    Cancel(jsonrpc_core::Id),
+}
+
+/// Handle to a request that has been sent and is waiting for a response.
+#[derive(Debug)]
+pub struct CallHandle<R> {
+    id: jsonrpc_core::Id,
+    method: String,
+    rx: Receiver<jsonrpc_core::Output>,
+    writer_tx: Sender<Message>,
+    pending_tx: Sender<PendingRequest>,
+    done: bool,
+    _result: PhantomData<R>,
+}
+
+impl<R> CallHandle<R> {
+    pub fn new(
+        id: jsonrpc_core::Id,
+        method: &str,
+        rx: Receiver<jsonrpc_core::Output>,
+        writer_tx: Sender<Message>,
+        pending_tx: Sender<PendingRequest>,
+    ) -> Self {
+        Self {
+            id,
+            method: method.into(),
+            rx,
+            writer_tx,
+            pending_tx,
+            done: false,
+            _result: PhantomData,
+        }
+    }
+
+    /// cancels the request, its response will be discarded when it arrives.
+    pub fn cancel(self) {
+        drop(self)
+    }
+
+    // forgets about the pending response and tells the other end to stop working on the request.
+    fn send_cancel(&self) -> Result<()> {
+        let id = match &self.id {
+            // ids are allocated within the range of lsp ids, see next_id.
+            jsonrpc_core::Id::Num(id) => lsp_types::NumberOrString::Number(i32::try_from(*id)?),
+            jsonrpc_core::Id::Str(id) => lsp_types::NumberOrString::String(id.clone()),
+            jsonrpc_core::Id::Null => return Ok(()),
+        };
+
+        self.pending_tx
+            .send(PendingRequest::Cancel(self.id.clone()))?;
+        let message = jsonrpc_core::Notification {
+            jsonrpc: Some(jsonrpc_core::Version::V2),
+            method: notification::Cancel::METHOD.into(),
+            params: lsp_types::CancelParams { id }.to_params()?,
+        };
+
+        self.writer_tx.send(Message::Notification(message))?;
+        Ok(())
+    }
+}
+
+impl<R> CallHandle<R>
+where
+    R: DeserializeOwned,
+{
+    /// blocks until the response to the request arrives.
+    pub fn wait(mut self) -> Result<R> {
+        let output = self.rx.recv()?;
+        self.done = true;
+        match output {
+            jsonrpc_core::Output::Success(s) => Ok(serde_json::from_value(s.result)?),
+            jsonrpc_core::Output::Failure(s) => Err(anyhow::anyhow!(s.error)),
+        }
+    }
+
+    /// blocks until the response to the request arrives or the timeout expires, in which case the
+    /// request is cancelled.
+    pub fn wait_timeout(mut self, timeout: Duration) -> Result<R> {
+        let start = Instant::now();
+        let output = match self.rx.recv_timeout(timeout) {
+            Ok(output) => output,
+            Err(crossbeam::channel::RecvTimeoutError::Timeout) => {
+                let elapsed = start.elapsed();
+                log::warn!("request {} timed out after {:?}", self.method, elapsed);
+                let method = self.method.clone();
+                self.cancel();
+                return Err(Error::Timeout { method, elapsed }.into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        self.done = true;
+        match output {
+            jsonrpc_core::Output::Success(s) => Ok(serde_json::from_value(s.result)?),
+            jsonrpc_core::Output::Failure(s) => Err(anyhow::anyhow!(s.error)),
+        }
+    }
+}
+
+impl<R> Drop for CallHandle<R> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        log::debug!("cancelling request {:?} ({})", self.id, self.method);
+        if let Err(err) = self.send_cancel() {
+            log::error!("could not cancel request {:?}: {}", self.id, err);
+        }
+    }
+}
+
 pub trait ToParams {
     fn to_params(self) -> Result<Params>;
 }
@@ -67,3 +261,49 @@ where
         Ok(params)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpc_core::ErrorCode;
+
+    fn code(err: anyhow::Error) -> ErrorCode {
+        let rpc_error = to_rpc_error(&err);
+        assert_eq!(rpc_error.message, err.to_string());
+        rpc_error.code
+    }
+
+    #[test]
+    fn timeouts_are_reported_as_cancelled() {
+        let err = Error::Timeout {
+            method: "textDocument/hover".into(),
+            elapsed: Duration::from_secs(1),
+        };
+        assert_eq!(code(err.into()), ErrorCode::ServerError(REQUEST_CANCELLED));
+    }
+
+    #[test]
+    fn unknown_methods_are_reported_as_not_found() {
+        let err = Error::MethodNotFound("foo/bar".into());
+        assert_eq!(code(err.into()), ErrorCode::MethodNotFound);
+    }
+
+    #[test]
+    fn invalid_frames_are_reported_as_parse_errors() {
+        let err = Error::InvalidFrame("frame".into());
+        assert_eq!(code(err.into()), ErrorCode::ParseError);
+    }
+
+    #[test]
+    fn deserialisation_errors_are_reported_as_invalid_params() {
+        let err = serde_json::from_str::<lsp_types::Position>("{}").unwrap_err();
+        assert_eq!(code(err.into()), ErrorCode::InvalidParams);
+    }
+
+    #[test]
+    fn other_errors_are_reported_as_internal() {
+        assert_eq!(code(anyhow::anyhow!("boom")), ErrorCode::InternalError);
+        let err = std::io::Error::other("io");
+        assert_eq!(code(err.into()), ErrorCode::InternalError);
+    }
+}
@@ -0,0 +1,139 @@
+use super::{ClientID, Message};
+use anyhow::Result;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static RECORDER: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Direction of a message, as seen from the language client.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// A single message exchanged with vim or a language server, as written to a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    // milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub client: ClientID,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+/// starts recording every message sent or received by any client to the given file, one JSON
+/// object per line.
+pub fn record_to(path: &Path) -> Result<()> {
+    let file = File::create(path)?;
+    RECORDER
+        .set(Mutex::new(file))
+        .map_err(|_| anyhow::anyhow!("already recording"))?;
+    Ok(())
+}
+
+pub(super) fn record(client: &ClientID, direction: Direction, message: &Message) {
+    let file = match RECORDER.get() {
+        Some(file) => file,
+        None => return,
+    };
+
+    if let Err(err) = write_entry(&mut *file.lock(), client, direction, message) {
+        log::error!("could not record message: {}", err);
+    }
+}
+
+/// writes the message to a recording as it is sent or received now.
+pub(crate) fn write_entry<W: Write>(
+    writer: &mut W,
+    client: &ClientID,
+    direction: Direction,
+    message: &Message,
+) -> Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let entry = RecordedMessage {
+        timestamp,
+        client: client.clone(),
+        direction,
+        message: message.clone(),
+    };
+
+    writeln!(writer, "{}", serde_json::to_string(&entry)?)?;
+    Ok(())
+}
+
+/// reads back every message in a recording.
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedMessage>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut messages = vec![];
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        messages.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::ServerID;
+
+    #[test]
+    fn test_recording_round_trip() {
+        let path = std::env::temp_dir().join(format!("vlc-{}-recording.jsonl", std::process::id()));
+        let server = ClientID::LanguageServer(ServerID::new("go", "gopls", "/a"));
+        let messages: Vec<(ClientID, Direction, Message)> = vec![
+            (
+                ClientID::VIM,
+                Direction::Incoming,
+                serde_json::from_str(r#"{"jsonrpc":"2.0","method":"start","params":{},"id":1}"#)
+                    .unwrap(),
+            ),
+            (
+                server.clone(),
+                Direction::Outgoing,
+                serde_json::from_str(r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#)
+                    .unwrap(),
+            ),
+            (
+                server,
+                Direction::Incoming,
+                serde_json::from_str(r#"{"jsonrpc":"2.0","result":null,"id":0}"#).unwrap(),
+            ),
+        ];
+
+        let mut file = File::create(&path).unwrap();
+        for (client, direction, message) in &messages {
+            write_entry(&mut file, client, *direction, message).unwrap();
+            // blank lines, e.g. at the end of a file edited by hand, are skipped.
+            writeln!(file).unwrap();
+        }
+        drop(file);
+
+        let recorded = read_recording(&path).unwrap();
+        assert_eq!(recorded.len(), messages.len());
+        for (recorded, (client, direction, message)) in recorded.iter().zip(messages) {
+            assert_eq!(recorded.client, client);
+            assert_eq!(recorded.direction, direction);
+            assert_eq!(
+                serde_json::to_value(&recorded.message).unwrap(),
+                serde_json::to_value(&message).unwrap()
+            );
+            assert!(recorded.timestamp > 0);
+        }
+    }
+}
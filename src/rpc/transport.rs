@@ -0,0 +1,165 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub type Reader = Box<dyn BufRead + Send>;
+pub type Writer = Box<dyn Write + Send>;
+
+// how long to keep trying to connect to a server that was just spawned and may not be listening
+// yet, and how long to wait between attempts.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Transport used to talk to a language server.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// the server is spawned and spoken to through its stdin and stdout.
+    #[default]
+    Stdio,
+    /// the server listens on a TCP socket at the given host:port address.
+    Tcp(String),
+    /// the server listens on a unix domain socket at the given path.
+    Unix(PathBuf),
+}
+
+impl Transport {
+    /// connects to a server listening on a socket, retrying for a while in case it is still
+    /// starting up.
+    pub fn connect(&self) -> Result<(Reader, Writer)> {
+        let start = Instant::now();
+        loop {
+            match self.try_connect() {
+                Ok(streams) => return Ok(streams),
+                Err(err) if start.elapsed() < CONNECT_TIMEOUT => {
+                    log::debug!("could not connect to {:?}, retrying: {}", self, err);
+                    std::thread::sleep(CONNECT_RETRY_INTERVAL);
+                }
+                Err(err) => {
+                    return Err(anyhow::anyhow!("could not connect to {:?}: {}", self, err))
+                }
+            }
+        }
+    }
+
+    fn try_connect(&self) -> Result<(Reader, Writer)> {
+        match self {
+            Transport::Stdio => Err(anyhow::anyhow!("stdio transport can not be connected to")),
+            Transport::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                let writer = stream.try_clone()?;
+                Ok((Box::new(BufReader::new(stream)), Box::new(writer)))
+            }
+            #[cfg(unix)]
+            Transport::Unix(path) => {
+                let stream = std::os::unix::net::UnixStream::connect(path)?;
+                let writer = stream.try_clone()?;
+                Ok((Box::new(BufReader::new(stream)), Box::new(writer)))
+            }
+            #[cfg(not(unix))]
+            Transport::Unix(_) => Err(anyhow::anyhow!(
+                "unix sockets are not supported on this platform"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{read_message, write_message, Message};
+    use std::net::TcpListener;
+
+    const MESSAGE: &str = r#"{"jsonrpc":"2.0","method":"ping","params":null,"id":1}"#;
+
+    // answers the first frame read from the stream with the same frame.
+    fn echo<S: std::io::Read + Write>(stream: S) {
+        let mut reader = BufReader::new(stream);
+        let message = read_message(&mut reader).unwrap().unwrap();
+        write_message(reader.get_mut(), &message).unwrap();
+    }
+
+    fn round_trip(transport: &Transport) {
+        let (mut reader, mut writer) = transport.connect().unwrap();
+        let message: Message = serde_json::from_str(MESSAGE).unwrap();
+        write_message(&mut writer, &message).unwrap();
+        let echoed = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(echoed.id(), message.id());
+    }
+
+    #[test]
+    fn connects_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || echo(listener.accept().unwrap().0));
+
+        round_trip(&Transport::Tcp(address));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn retries_until_the_tcp_listener_is_up() {
+        // the port is freed so that it can be listened on again once the client is retrying.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let server_address = address.clone();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(CONNECT_RETRY_INTERVAL * 3);
+            let listener = TcpListener::bind(server_address).unwrap();
+            echo(listener.accept().unwrap().0);
+        });
+
+        round_trip(&Transport::Tcp(address));
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("vlc-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn connects_over_unix_sockets() {
+        use std::os::unix::net::UnixListener;
+
+        let path = socket_path("connect");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || echo(listener.accept().unwrap().0));
+
+        round_trip(&Transport::Unix(path.clone()));
+        server.join().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retries_until_the_unix_listener_is_up() {
+        use std::os::unix::net::UnixListener;
+
+        let path = socket_path("retry");
+        let server_path = path.clone();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(CONNECT_RETRY_INTERVAL * 3);
+            let listener = UnixListener::bind(server_path).unwrap();
+            echo(listener.accept().unwrap().0);
+        });
+
+        round_trip(&Transport::Unix(path.clone()));
+        server.join().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn stdio_can_not_be_connected_to() {
+        assert!(Transport::Stdio.try_connect().is_err());
+    }
+}
@@ -1,21 +1,403 @@
-use std::collections::HashMap;
+use crate::config::FeatureSet;
+use crate::lsp::capabilities::{self, Document};
+use crate::project::ProjectConfig;
+use crate::rpc::ServerID;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default)]
+pub struct TextDocument {
+    pub version: i32,
+    pub language_id: String,
+    pub lines: Vec<String>,
+}
+
+impl TextDocument {
+    fn new(language_id: &str, text: &str) -> Self {
+        Self {
+            version: 0,
+            language_id: language_id.into(),
+            lines: text.split('\n').map(|l| l.to_owned()).collect(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+}
+
+/// What setting the text of a document amounts to for the servers.
+#[derive(Debug, PartialEq)]
+pub enum DocumentChange {
+    /// the text is the same as before.
+    Unchanged,
+    /// the document was not tracked, so it has to be opened on the servers with its whole text.
+    Opened,
+    /// the text changed, the document having the given version now and the text being what it
+    /// was before.
+    Changed(i32, String),
+}
+
+/// Documents open in vim, keyed by file name, along with the text and version the servers were
+/// last sent for them.
+#[derive(Debug, Default)]
+pub struct Documents(HashMap<String, TextDocument>);
+
+impl Documents {
+    pub fn get(&self, filename: &str) -> Option<&TextDocument> {
+        self.0.get(filename)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &TextDocument)> {
+        self.0.iter()
+    }
+
+    /// starts tracking the document, or sets the text of one that is tracked already, returning
+    /// the version it is opened with.
+    pub fn open(&mut self, filename: &str, language_id: &str, text: &str) -> i32 {
+        match self.0.get_mut(filename) {
+            Some(doc) => {
+                if doc.text() != text {
+                    *doc = TextDocument {
+                        version: doc.version + 1,
+                        ..TextDocument::new(language_id, text)
+                    };
+                }
+
+                doc.version
+            }
+            None => {
+                self.0
+                    .insert(filename.into(), TextDocument::new(language_id, text));
+                0
+            }
+        }
+    }
+
+    /// sets the text of the document and bumps its version if it changed. Documents that are not
+    /// tracked start being tracked.
+    pub fn change(&mut self, filename: &str, language_id: &str, text: &str) -> DocumentChange {
+        let doc = match self.0.get_mut(filename) {
+            Some(doc) => doc,
+            None => {
+                self.0
+                    .insert(filename.into(), TextDocument::new(language_id, text));
+                return DocumentChange::Opened;
+            }
+        };
+
+        let previous = doc.text();
+        if previous == text {
+            return DocumentChange::Unchanged;
+        }
+
+        doc.version += 1;
+        doc.lines = text.split('\n').map(|l| l.to_owned()).collect();
+        DocumentChange::Changed(doc.version, previous)
+    }
+
+    pub fn close(&mut self, filename: &str) -> Option<TextDocument> {
+        self.0.remove(filename)
+    }
+
+    /// returns whether something computed by a server for the given version of a document, e.g.
+    /// diagnostics or edits, still applies to it. Results without a version, or for documents
+    /// that are not tracked, are assumed to apply.
+    pub fn is_current(&self, filename: &str, version: Option<i32>) -> bool {
+        match (self.0.get(filename), version) {
+            (Some(doc), Some(version)) => doc.version == version,
+            _ => true,
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct State {
     pub process_id: u32,
-    // contains file uris as a key and a tuple of (Version, Text) as a value.
-    pub text_documents: HashMap<String, (u64, Vec<String>)>,
-    pub server_capabilities: HashMap<String, lsp_types::ServerCapabilities>,
+    pub text_documents: Documents,
+    pub server_capabilities: HashMap<ServerID, lsp_types::ServerCapabilities>,
+    // capabilities registered by each server with client/registerCapability, on top of the ones it
+    // was initialized with.
+    pub registrations: HashMap<ServerID, Vec<lsp_types::Registration>>,
     // when a user calls textDocument/codeAction actions are fetched from the server and stashed in
-    // this vec for later resolution.
-    pub code_actions: Vec<lsp_types::CodeActionOrCommand>,
+    // this vec for later resolution, along with the server each of them came from.
+    pub code_actions: Vec<(ServerID, lsp_types::CodeActionOrCommand)>,
+    // items of the last completion, along with the server each of them came from so that they are
+    // resolved by it.
+    pub completion_items: Vec<(ServerID, lsp_types::CompletionItem)>,
     // when textDocument/codeLens is resolved, we insert the result in this hashmap where the key
     // is the name of the text document. This hashmap will be used to fetch the code lens actions
     // in a specific line and file.
     pub code_lens: HashMap<String, Vec<lsp_types::CodeLens>>,
-    // hashmap keyed by file name and having diagnostics as values.
-    pub diagnostics: HashMap<String, Vec<lsp_types::Diagnostic>>,
-    // hashmap keyed by language id, values being root paths for the language server running for
-    // it.
-    pub roots: HashMap<String, String>,
+    // hashmap keyed by file name and having the diagnostics published by each server as values.
+    pub diagnostics: HashMap<String, HashMap<ServerID, Vec<lsp_types::Diagnostic>>>,
+    // hashmap keyed by file name and server name, values being the workspace root detected for
+    // the file, so that it is not looked up in the filesystem for every message.
+    pub roots: HashMap<(String, String), String>,
+    // workspace roots other than the one a server was started in that were added to it with
+    // workspace/didChangeWorkspaceFolders, keyed by server.
+    pub workspace_folders: HashMap<ServerID, Vec<String>>,
+    // project config found at each workspace root, None if there is none or it can't be used.
+    pub projects: HashMap<String, Option<ProjectConfig>>,
+    // servers that have been asked to shut down, so that their exit is not mistaken for a crash.
+    pub stopping: HashSet<ServerID>,
+    // how each server is sent changes to documents, resolved once it is initialized rather than
+    // for every change.
+    pub document_sync: HashMap<ServerID, DocumentSync>,
+    // features enabled for each server in its config, resolved once it is initialized.
+    pub features: HashMap<ServerID, FeatureSet>,
+}
+
+/// How a server is told about changes to documents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DocumentSync {
+    pub kind: lsp_types::TextDocumentSyncKind,
+    // how long changes are held back for before they are sent.
+    pub change_delay: Duration,
+}
+
+impl State {
+    /// returns whether the server handles the method for the document, if the request is made for
+    /// one, according to both the capabilities it was initialized with and the ones it registered
+    /// since. Servers that have not been initialized yet are assumed to handle it.
+    pub fn supports(&self, id: &ServerID, method: &str, document: Option<Document>) -> bool {
+        match self.server_capabilities.get(id) {
+            Some(caps) => {
+                capabilities::supports(caps, method)
+                    || capabilities::supports_registered(self.registrations(id), method, document)
+            }
+            None => true,
+        }
+    }
+
+    /// returns whether the server can run the command, either because it said so when it was
+    /// initialized or because it registered it since.
+    pub fn supports_command(&self, id: &ServerID, command: &str) -> bool {
+        let initialized = self
+            .server_capabilities
+            .get(id)
+            .is_some_and(|caps| capabilities::supports_command(caps, command));
+        initialized || capabilities::registered_command(self.registrations(id), command)
+    }
+
+    /// returns how the server wants to be told about changes to documents. Servers that have not
+    /// been initialized yet are sent the whole document right away.
+    pub fn document_sync(&self, id: &ServerID) -> DocumentSync {
+        self.document_sync.get(id).copied().unwrap_or(DocumentSync {
+            kind: lsp_types::TextDocumentSyncKind::Full,
+            change_delay: Duration::from_millis(0),
+        })
+    }
+
+    /// returns the features enabled for the server. Every feature is enabled for servers that have
+    /// not been initialized yet.
+    pub fn features(&self, id: &ServerID) -> FeatureSet {
+        self.features.get(id).cloned().unwrap_or_default()
+    }
+
+    fn registrations(&self, id: &ServerID) -> &[lsp_types::Registration] {
+        self.registrations
+            .get(id)
+            .map(|r| r.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// returns every workspace root the given server handles, starting with the one it was
+    /// started in.
+    pub fn roots_for(&self, id: &ServerID) -> Vec<String> {
+        std::iter::once(id.root.clone())
+            .chain(self.workspace_folders.get(id).cloned().unwrap_or_default())
+            .collect()
+    }
+
+    /// returns the diagnostics every server published for the given file.
+    pub fn diagnostics_for(&self, filename: &str) -> Vec<lsp_types::Diagnostic> {
+        let mut servers: Vec<_> = self
+            .diagnostics
+            .get(filename)
+            .map(|d| d.iter().collect())
+            .unwrap_or_default();
+        // keep the merged list stable between publishes.
+        servers.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        servers
+            .into_iter()
+            .flat_map(|(_, diagnostics)| diagnostics.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let mut documents = Documents::default();
+        assert_eq!(documents.open("/a.rs", "rust", "fn main() {}\n"), 0);
+        let doc = documents.get("/a.rs").unwrap();
+        assert_eq!(doc.language_id, "rust");
+        assert_eq!(doc.lines, vec!["fn main() {}", ""]);
+        assert_eq!(doc.text(), "fn main() {}\n");
+
+        // opening it again with the same text keeps the version, with another text bumps it.
+        assert_eq!(documents.open("/a.rs", "rust", "fn main() {}\n"), 0);
+        assert_eq!(documents.open("/a.rs", "rust", "fn main() {}"), 1);
+        assert_eq!(documents.get("/a.rs").unwrap().text(), "fn main() {}");
+    }
+
+    #[test]
+    fn test_change() {
+        let mut documents = Documents::default();
+        documents.open("/a.rs", "rust", "a");
+        assert_eq!(
+            documents.change("/a.rs", "rust", "ab"),
+            DocumentChange::Changed(1, "a".into())
+        );
+        assert_eq!(
+            documents.change("/a.rs", "rust", "ab"),
+            DocumentChange::Unchanged
+        );
+        assert_eq!(
+            documents.change("/a.rs", "rust", "abc\n"),
+            DocumentChange::Changed(2, "ab".into())
+        );
+        assert_eq!(documents.get("/a.rs").unwrap().lines, vec!["abc", ""]);
+
+        // documents changed before they are opened start being tracked, and have to be opened.
+        assert_eq!(
+            documents.change("/b.rs", "rust", "b"),
+            DocumentChange::Opened
+        );
+        assert_eq!(documents.get("/b.rs").unwrap().version, 0);
+        assert_eq!(documents.get("/b.rs").unwrap().text(), "b");
+        assert_eq!(
+            documents.change("/b.rs", "rust", "bc"),
+            DocumentChange::Changed(1, "b".into())
+        );
+    }
+
+    #[test]
+    fn test_close() {
+        let mut documents = Documents::default();
+        documents.open("/a.rs", "rust", "a");
+        documents.change("/a.rs", "rust", "ab");
+        let doc = documents.close("/a.rs").unwrap();
+        assert_eq!((doc.version, doc.text()), (1, "ab".into()));
+        assert!(documents.get("/a.rs").is_none());
+        assert!(documents.close("/a.rs").is_none());
+
+        // a document that is opened again starts over.
+        assert_eq!(documents.open("/a.rs", "rust", "ab"), 0);
+        assert_eq!(
+            documents.change("/a.rs", "rust", "abc"),
+            DocumentChange::Changed(1, "ab".into())
+        );
+    }
+
+    #[test]
+    fn test_is_current() {
+        let mut documents = Documents::default();
+        documents.open("/a.rs", "rust", "a");
+        assert!(documents.is_current("/a.rs", Some(0)));
+        assert!(documents.is_current("/a.rs", None));
+
+        documents.change("/a.rs", "rust", "ab");
+        assert!(!documents.is_current("/a.rs", Some(0)));
+        assert!(documents.is_current("/a.rs", Some(1)));
+        assert!(documents.is_current("/b.rs", Some(3)));
+
+        // versions from before the document was closed don't apply to it once it is reopened.
+        documents.close("/a.rs");
+        assert!(documents.is_current("/a.rs", Some(1)));
+        documents.open("/a.rs", "rust", "ab");
+        assert!(!documents.is_current("/a.rs", Some(1)));
+        assert!(documents.is_current("/a.rs", Some(0)));
+    }
+
+    #[test]
+    fn test_diagnostics_for() {
+        let diagnostic = |message: &str| {
+            lsp_types::Diagnostic::new_simple(lsp_types::Range::default(), message.into())
+        };
+        let mut state = State::default();
+        assert!(state.diagnostics_for("/a.rs").is_empty());
+
+        let published = state.diagnostics.entry("/a.rs".into()).or_default();
+        published.insert(
+            ServerID::new("rust", "clippy", "/"),
+            vec![diagnostic("c1"), diagnostic("c2")],
+        );
+        published.insert(ServerID::new("rust", "rls", "/"), vec![diagnostic("r")]);
+        published.insert(
+            ServerID::new("rust", "analyzer", "/"),
+            vec![diagnostic("a")],
+        );
+
+        // diagnostics are ordered by server name, keeping the order each server published them in.
+        let messages: Vec<_> = state
+            .diagnostics_for("/a.rs")
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(messages, vec!["a", "c1", "c2", "r"]);
+        assert!(state.diagnostics_for("/b.rs").is_empty());
+    }
+
+    #[test]
+    fn test_supports() {
+        let id = ServerID::new("rust", "rust-analyzer", "/project");
+        let mut state = State::default();
+        // servers are assumed to handle everything until they are initialized.
+        assert!(state.supports(&id, "textDocument/hover", None));
+
+        let caps: lsp_types::ServerCapabilities =
+            serde_json::from_value(serde_json::json!({"hoverProvider": true})).unwrap();
+        state.server_capabilities.insert(id.clone(), caps);
+        assert!(state.supports(&id, "textDocument/hover", None));
+        assert!(!state.supports(&id, "textDocument/formatting", None));
+
+        let registration = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "method": "textDocument/formatting",
+            "registerOptions": {"documentSelector": [{"language": "rust"}]},
+        }))
+        .unwrap();
+        state.registrations.insert(id.clone(), vec![registration]);
+        let document = |language_id| {
+            Some(Document {
+                language_id,
+                filename: "/project/src/main.rs",
+            })
+        };
+        assert!(state.supports(&id, "textDocument/formatting", document("rust")));
+        assert!(!state.supports(&id, "textDocument/formatting", document("toml")));
+    }
+
+    #[test]
+    fn test_supports_command() {
+        let id = ServerID::new("rust", "rust-analyzer", "/project");
+        let mut state = State::default();
+        assert!(!state.supports_command(&id, "rust-analyzer.runSingle"));
+
+        let caps: lsp_types::ServerCapabilities = serde_json::from_value(serde_json::json!({
+            "executeCommandProvider": {"commands": ["rust-analyzer.runSingle"]},
+        }))
+        .unwrap();
+        state.server_capabilities.insert(id.clone(), caps);
+        assert!(state.supports_command(&id, "rust-analyzer.runSingle"));
+        assert!(!state.supports_command(&id, "rust-analyzer.debugSingle"));
+
+        let registration = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "method": "workspace/executeCommand",
+            "registerOptions": {"commands": ["rust-analyzer.debugSingle"]},
+        }))
+        .unwrap();
+        state.registrations.insert(id.clone(), vec![registration]);
+        assert!(state.supports_command(&id, "rust-analyzer.debugSingle"));
+        assert!(!state.supports_command(
+            &ServerID::new("rust", "clippy", "/project"),
+            "rust-analyzer.debugSingle"
+        ));
+    }
 }
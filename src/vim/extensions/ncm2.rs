@@ -2,28 +2,31 @@ use crate::language_client::Context;
 use crate::rpc::RPCClient;
 use anyhow::Result;
 
-pub async fn register_ncm2_source<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>) -> Result<()> {
-    let state = ctx.state.read();
-    let caps = state.server_capabilities.get(&ctx.language_id).cloned();
-    drop(state);
-
-    if caps.is_none() {
+// registers the language client as a completion source for ncm2, completing on the trigger
+// characters of every server for the language id.
+pub fn register_ncm2_source<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>) -> Result<()> {
+    if !ctx.features()?.completion {
         return Ok(());
     }
 
-    let opts = caps.unwrap().completion_provider;
-    if opts.is_none() {
-        return Ok(());
-    }
+    let state = ctx.state.read();
+    let mut complete_pattern: Vec<String> = ctx
+        .servers
+        .iter()
+        .filter_map(|(id, _)| state.server_capabilities.get(id))
+        .filter_map(|caps| caps.completion_provider.as_ref())
+        .flat_map(|opts| opts.trigger_characters.clone().unwrap_or_default())
+        .collect();
+    drop(state);
 
-    let opts = opts.unwrap();
-    let complete_pattern: Vec<_> = opts.trigger_characters.unwrap_or_default();
+    complete_pattern.sort();
+    complete_pattern.dedup();
     let params = serde_json::json!({
         "complete_pattern": complete_pattern,
         "language_id": ctx.language_id,
     });
 
-    // ctx.vim
-    //     .notify("vlc#register_ncm2", serde_json::json!([params]))?;
+    ctx.vim
+        .notify("vlc#ncm2#register", serde_json::json!([params]))?;
     Ok(())
 }
@@ -1,3 +1,4 @@
+mod extensions;
 mod types;
 
 use crate::{config, language_client::Context};
@@ -7,8 +8,10 @@ use crate::{
 };
 use anyhow::Result;
 use jsonrpc_core::Params;
-use lsp_types::{CodeAction, CodeActionOrCommand};
+use lsp_types::{request::Request, CodeAction, CodeActionOrCommand};
 use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::Read;
 pub use types::*;
 
@@ -23,14 +26,20 @@ where
         match message {
             rpc::Message::MethodCall(msg) => match msg.method.as_str() {
                 "start" => {
-                    log::error!("STARTING");
                     let params: BufInfo = serde_json::from_value(msg.params.into())?;
-                    self.start_server(&params.language_id)?;
+                    if let Err(err) = self.start_server(&params.language_id, &params.filename) {
+                        // vim assumes the server is running as soon as it asks for it to start.
+                        self.set_server_stopped(&params.language_id)?;
+                        return Err(err);
+                    }
+
                     return Ok(());
                 }
                 "initialize" => {
                     crate::lsp::initialize(&ctx)?;
-                    crate::lsp::initialized(&ctx)?;
+                    if ctx.config.completion.strategy == config::CompletionStrategy::NCM2 {
+                        extensions::ncm2::register_ncm2_source(&ctx)?;
+                    }
                 }
                 "shutdown" => {
                     crate::lsp::shutdown(&ctx)?;
@@ -82,13 +91,20 @@ where
                 "vlc/diagnosticDetail" => {
                     diagnostic_detail(&ctx, msg.params)?;
                 }
-                _ => log::debug!("unhandled vim method call {}", msg.method),
+                "textDocument/willSave" => {
+                    will_save(&ctx, msg.params)?;
+                }
+                _ => {
+                    log::debug!("unhandled vim method call {}", msg.method);
+                    return Err(rpc::Error::MethodNotFound(msg.method).into());
+                }
             },
             rpc::Message::Notification(msg) => match msg.method.as_str() {
                 "textDocument/didSave" => {
                     did_save(&ctx, msg.params)?;
                 }
                 "textDocument/didOpen" => {
+                    let ctx = self.start_root(ctx)?;
                     did_open(&ctx, msg.params)?;
                 }
                 "textDocument/didClose" => {
@@ -97,6 +113,9 @@ where
                 "textDocument/didChange" => {
                     did_change(&ctx, msg.params)?;
                 }
+                "workspace/didChangeConfiguration" => {
+                    self.reload_config()?;
+                }
                 _ => log::debug!("unhandled notification {}", msg.method),
             },
             rpc::Message::Output(_) => unreachable!(),
@@ -123,23 +142,18 @@ pub fn diagnostic_detail<C: RPCClient, S: RPCClient>(
     let pos: CursorPosition = serde_json::from_value(params.into())?;
     // let filename = ctx.filename.replace(&ctx.root_path, "");
     let filename = ctx.filename.clone();
-    let diagnostics = ctx
+    let position = pos.position.to_lsp(&pos.filename, ctx);
+    let position = (position.line, position.character);
+    let diagnostics: Vec<lsp_types::Diagnostic> = ctx
         .state
         .read()
-        .diagnostics
-        .get(&filename)
-        .cloned()
-        .map(|d| {
-            d.into_iter()
-                .filter(|d| {
-                    pos.position.line - 1 >= d.range.start.line
-                        && pos.position.column >= d.range.start.character
-                        && pos.position.line - 1 <= d.range.end.line
-                        && pos.position.column <= d.range.end.character
-                })
-                .collect::<Vec<lsp_types::Diagnostic>>()
+        .diagnostics_for(&filename)
+        .into_iter()
+        .filter(|d| {
+            (d.range.start.line, d.range.start.character) <= position
+                && position <= (d.range.end.line, d.range.end.character)
         })
-        .unwrap_or_default();
+        .collect();
 
     if diagnostics.is_empty() {
         return Ok(());
@@ -177,18 +191,9 @@ pub fn apply_text_edits<C: RPCClient, S: RPCClient>(
     filename: &str,
     edits: &[lsp_types::TextEdit],
 ) -> Result<()> {
-    let changes: Vec<BufChanges> = edits
-        .into_iter()
-        .map(|e| BufChanges {
-            start: e.range.start.into(),
-            end: e.range.end.into(),
-            lines: vec![e.new_text.clone()],
-        })
-        .collect();
-
     let changes = DocumentChanges {
         filename: filename.to_string(),
-        changes,
+        changes: buf_changes(ctx, filename, edits),
     };
 
     ctx.vim
@@ -196,41 +201,79 @@ pub fn apply_text_edits<C: RPCClient, S: RPCClient>(
     Ok(())
 }
 
+// converts the edits to a file to the changes vim makes to its buffer.
+fn buf_changes<C: RPCClient, S: RPCClient>(
+    ctx: &Context<C, S>,
+    filename: &str,
+    edits: &[lsp_types::TextEdit],
+) -> Vec<BufChanges> {
+    let lines = FileLines::new(ctx);
+    edits
+        .iter()
+        .map(|e| BufChanges {
+            start: Position::from_lsp(e.range.start, filename, &lines),
+            end: Position::from_lsp(e.range.end, filename, &lines),
+            lines: vec![e.new_text.clone()],
+        })
+        .collect()
+}
+
 pub fn apply_workspace_edit<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
-    edits: &lsp_types::WorkspaceEdit,
+    edit: &lsp_types::WorkspaceEdit,
 ) -> Result<()> {
-    let changes: &lsp_types::DocumentChanges = &edits.document_changes.as_ref().unwrap();
-    let changes: Vec<DocumentChanges> = match changes {
-        lsp_types::DocumentChanges::Edits(edits) => edits
+    let document_edits: Vec<&lsp_types::TextDocumentEdit> = match &edit.document_changes {
+        Some(lsp_types::DocumentChanges::Edits(edits)) => edits.iter().collect(),
+        Some(lsp_types::DocumentChanges::Operations(operations)) => operations
+            .iter()
+            .map(|operation| match operation {
+                lsp_types::DocumentChangeOperation::Edit(tde) => Ok(tde),
+                lsp_types::DocumentChangeOperation::Op(_) => Err(anyhow::anyhow!(
+                    "creating, renaming and deleting files is not supported"
+                )),
+            })
+            .collect::<Result<_>>()?,
+        None => vec![],
+    };
+
+    // edits made for a version of a document that has changed since would end up in the wrong
+    // place, so none of them are applied.
+    let state = ctx.state.read();
+    let stale = document_edits.iter().find(|tde| {
+        let filename = tde.text_document.uri.to_file_path().unwrap_or_default();
+        !state
+            .text_documents
+            .is_current(&filename.to_string_lossy(), tde.text_document.version)
+    });
+    if let Some(tde) = stale {
+        anyhow::bail!(
+            "{} has changed since the edit was made",
+            tde.text_document.uri.path()
+        );
+    }
+    drop(state);
+
+    // servers that don't send document changes send a plain map of edits for each document.
+    let changes: Vec<DocumentChanges> = match &edit.changes {
+        Some(changes) if edit.document_changes.is_none() => changes
+            .iter()
+            .map(|(uri, edits)| document_changes(ctx, uri, edits))
+            .collect(),
+        _ => document_edits
             .into_iter()
             .map(|tde| {
-                let tde: lsp_types::TextDocumentEdit = tde.clone();
-                let text_document = tde
-                    .text_document
-                    .uri
-                    .to_string()
-                    .replace(ctx.root_path.as_str(), "");
-
-                DocumentChanges {
-                    filename: text_document,
-                    changes: tde
-                        .edits
-                        .into_iter()
-                        .filter_map(|e| match e {
-                            lsp_types::OneOf::Left(e) => Some(BufChanges {
-                                start: e.range.start.into(),
-                                end: e.range.end.into(),
-                                lines: vec![e.new_text],
-                            }),
-                            // annotated text edits are not supported yet
-                            lsp_types::OneOf::Right(_) => None,
-                        })
-                        .collect(),
-                }
+                let edits: Vec<lsp_types::TextEdit> = tde
+                    .edits
+                    .iter()
+                    .filter_map(|e| match e {
+                        lsp_types::OneOf::Left(e) => Some(e.clone()),
+                        // annotated text edits are not supported yet
+                        lsp_types::OneOf::Right(_) => None,
+                    })
+                    .collect();
+                document_changes(ctx, &tde.text_document.uri, &edits)
             })
             .collect(),
-        lsp_types::DocumentChanges::Operations(_) => vec![],
     };
 
     ctx.vim
@@ -238,6 +281,19 @@ pub fn apply_workspace_edit<C: RPCClient, S: RPCClient>(
     Ok(())
 }
 
+// converts the edits to the document at the uri to the changes vim makes to it.
+fn document_changes<C: RPCClient, S: RPCClient>(
+    ctx: &Context<C, S>,
+    uri: &lsp_types::Url,
+    edits: &[lsp_types::TextEdit],
+) -> DocumentChanges {
+    let path = uri.to_file_path().unwrap_or_default();
+    DocumentChanges {
+        filename: uri.to_string().replace(ctx.root_path.as_str(), ""),
+        changes: buf_changes(ctx, &path.to_string_lossy(), edits),
+    }
+}
+
 pub fn show_diagnostics<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     file: &str,
@@ -245,10 +301,12 @@ pub fn show_diagnostics<C: RPCClient, S: RPCClient>(
 ) -> Result<()> {
     let quickfix_list: Vec<QuickfixItem> =
         diagnostics.clone().into_iter().map(|l| l.into()).collect();
-    set_quickfix(ctx, quickfix_list)?;
+    set_quickfix(ctx, quickfix_list, ctx.config.diagnostics.auto_open)?;
 
-    let signs: Vec<Sign> = diagnostics.into_iter().map(|l| l.into()).collect();
-    set_signs(ctx, file, signs)?;
+    if ctx.config.diagnostics.show_signs {
+        let signs: Vec<Sign> = diagnostics.into_iter().map(|l| l.into()).collect();
+        set_signs(ctx, file, signs)?;
+    }
 
     Ok(())
 }
@@ -323,8 +381,10 @@ pub fn setloclist<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     items: Vec<LocationItem>,
 ) -> Result<()> {
-    ctx.vim
-        .notify("vlc#show_locations", serde_json::json!([items, ""]))?;
+    ctx.vim.notify(
+        "vlc#show_locations",
+        serde_json::json!([items, ctx.config.locations.auto_open]),
+    )?;
 
     Ok(())
 }
@@ -353,11 +413,17 @@ pub fn show_locations<C: RPCClient, S: RPCClient>(
         return jump_to_location(ctx, input.first().cloned().unwrap());
     }
 
+    let lines = FileLines::new(ctx);
     let locations: Vec<_> = input
         .into_iter()
         .map(|l| {
+            let path = lsp_types::Url::parse(&l.filename)
+                .ok()
+                .and_then(|uri| uri.to_file_path().ok())
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_else(|| l.filename.clone());
+            let text = lines.line(&path, l.position.line.saturating_sub(1));
             let filename = l.filename.replace(ctx.root_path.as_str(), "");
-            let text = crate::vim::get_line(ctx, &filename, l.position.line).unwrap_or_default();
             LocationItem {
                 filename,
                 lnum: l.position.line,
@@ -414,12 +480,14 @@ pub fn set_signs<C: RPCClient, S: RPCClient>(
     Ok(())
 }
 
+// sets the quickfix list, opening it if there is anything in it and open is set.
 pub fn set_quickfix<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     list: Vec<QuickfixItem>,
+    open: bool,
 ) -> Result<()> {
     ctx.vim
-        .notify("vlc#set_quickfix", serde_json::json!([list]))?;
+        .notify("vlc#set_quickfix", serde_json::json!([list, open]))?;
     Ok(())
 }
 
@@ -445,21 +513,68 @@ pub fn get_line<C: RPCClient, S: RPCClient>(
     filename: &str,
     line_number: u32,
 ) -> Result<String> {
+    let idx = line_number as usize - 1;
     let state = ctx.state.read();
-    let text_document = state.text_documents.get(filename).cloned();
+    let line = state
+        .text_documents
+        .get(filename)
+        .map(|doc| doc.lines.get(idx).cloned().unwrap_or_default());
     drop(state);
 
-    let idx = line_number as usize - 1;
-    match text_document {
-        Some((_, lines)) => Ok(lines[idx].clone()),
-        None => {
-            let mut file = std::fs::File::open(filename)?;
-            let mut text = String::new();
-            file.read_to_string(&mut text)?;
-            let lines: Vec<&str> = text.split('\n').collect();
-            let line = lines[idx].to_owned();
-            Ok(line)
+    match line {
+        Some(line) => Ok(line),
+        None => Ok(read_lines(filename)?
+            .into_iter()
+            .nth(idx)
+            .unwrap_or_default()),
+    }
+}
+
+// returns the lines of the file on disk.
+fn read_lines(filename: &str) -> Result<Vec<String>> {
+    let mut file = std::fs::File::open(filename)?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok(text.split('\n').map(String::from).collect())
+}
+
+impl<C: RPCClient, S: RPCClient> DocumentLines for Context<C, S> {
+    fn line(&self, filename: &str, line: u32) -> String {
+        get_line(self, filename, line + 1).unwrap_or_default()
+    }
+}
+
+/// Lines of the documents a batch of positions is converted in. Files that are not open in vim
+/// are read once, however many positions are in them.
+pub struct FileLines<'a, C: RPCClient, S: RPCClient> {
+    ctx: &'a Context<C, S>,
+    // lines of the files read so far, None for the ones that could not be read.
+    files: RefCell<HashMap<String, Option<Vec<String>>>>,
+}
+
+impl<'a, C: RPCClient, S: RPCClient> FileLines<'a, C, S> {
+    pub fn new(ctx: &'a Context<C, S>) -> Self {
+        Self {
+            ctx,
+            files: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl<C: RPCClient, S: RPCClient> DocumentLines for FileLines<'_, C, S> {
+    fn line(&self, filename: &str, line: u32) -> String {
+        let idx = line as usize;
+        if let Some(doc) = self.ctx.state.read().text_documents.get(filename) {
+            return doc.lines.get(idx).cloned().unwrap_or_default();
         }
+
+        self.files
+            .borrow_mut()
+            .entry(filename.into())
+            .or_insert_with(|| read_lines(filename).ok())
+            .as_ref()
+            .and_then(|lines| lines.get(idx).cloned())
+            .unwrap_or_default()
     }
 }
 
@@ -480,13 +595,17 @@ pub fn resolve_code_lens_action<C: RPCClient, S: RPCClient>(
     match code_lens {
         None => {}
         Some(code_lens) => {
-            let response = crate::lsp::code_lens::resolve(ctx, code_lens)?;
+            let response = if ctx.config.features.code_lens_resolve {
+                crate::lsp::code_lens::resolve(ctx, code_lens)?
+            } else {
+                code_lens.clone()
+            };
             if code_lens.command.is_none() {
                 return Ok(());
             }
 
             log::error!("{:?}", code_lens);
-            crate::lsp::extensions::run_command(ctx, response.command.as_ref().unwrap())?;
+            crate::lsp::extensions::run_command(ctx, response.command.as_ref().unwrap(), None)?;
         }
     }
 
@@ -505,18 +624,18 @@ pub fn resolve_code_action<C: RPCClient, S: RPCClient>(
     let action = code_actions.get(params.selection);
     match action {
         None => {}
-        Some(action) => match action {
+        Some((id, action)) => match action {
             CodeActionOrCommand::CodeAction(action) => {
                 if let Some(edit) = &action.edit {
                     apply_workspace_edit(ctx, &edit)?;
                 }
 
                 if let Some(command) = &action.command {
-                    crate::lsp::extensions::run_command(ctx, command)?;
+                    crate::lsp::extensions::run_command(ctx, command, Some(id))?;
                 }
             }
             CodeActionOrCommand::Command(command) => {
-                crate::lsp::extensions::run_command(ctx, command)?;
+                crate::lsp::extensions::run_command(ctx, command, Some(id))?;
             }
         },
     }
@@ -527,6 +646,10 @@ pub fn resolve_code_action<C: RPCClient, S: RPCClient>(
 }
 
 pub fn rename<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    if !ctx.config.features.rename {
+        return Ok(());
+    }
+
     let params: RenameParams = serde_json::from_value(params.into()).unwrap();
     let response = crate::lsp::text_document::rename(ctx, params)?;
     if response.is_none() {
@@ -539,25 +662,57 @@ pub fn rename<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -
 
 pub fn did_open<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
     let req: TextDocumentContent = serde_json::from_value(params.clone().into())?;
-    crate::lsp::text_document::did_open(ctx, req)?;
+    if ctx.config.features.did_open {
+        crate::lsp::text_document::did_open(ctx, req)?;
+    }
     code_lens(ctx, params)?;
     Ok(())
 }
 
 pub fn did_save<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
     let req: TextDocumentContent = serde_json::from_value(params.clone().into())?;
-    crate::lsp::text_document::did_save(ctx, req)?;
+    if ctx.config.features.did_save {
+        crate::lsp::text_document::did_save(ctx, req)?;
+    }
     code_lens(ctx, params)?;
     Ok(())
 }
 
+// replies with the changes the servers want made to the buffer before it is written, which vim
+// waits for.
+pub fn will_save<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    let params: TextDocumentContent = serde_json::from_value(params.into())?;
+    let mut changes = vec![];
+    if ctx.config.features.will_save {
+        // the edits are for the buffer as it is now, so the servers must have caught up with it.
+        if ctx.config.features.did_change {
+            crate::lsp::text_document::did_change(ctx, params.clone())?;
+        }
+
+        let edits = crate::lsp::text_document::will_save(ctx, &params.filename)?;
+        changes = buf_changes(ctx, &params.filename, &edits);
+    }
+
+    ctx.vim
+        .reply_success(&ctx.message_id, serde_json::to_value(&changes)?)?;
+    Ok(())
+}
+
 pub fn did_close<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    if !ctx.config.features.did_close {
+        return Ok(());
+    }
+
     let params: TextDocumentContent = serde_json::from_value(params.into())?;
     crate::lsp::text_document::did_close(ctx, params)?;
     Ok(())
 }
 
 pub fn did_change<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    if !ctx.config.features.did_change {
+        return Ok(());
+    }
+
     let params: TextDocumentContent = serde_json::from_value(params.into())?;
     crate::lsp::text_document::did_change(ctx, params.clone())?;
     Ok(())
@@ -567,6 +722,10 @@ pub fn implementation<C: RPCClient, S: RPCClient>(
     ctx: &Context<C, S>,
     params: Params,
 ) -> Result<()> {
+    if !ctx.config.features.implementation {
+        return Ok(());
+    }
+
     let params: CursorPosition = serde_json::from_value(params.into())?;
     let response = crate::lsp::text_document::implementation(ctx, params)?;
     if response.is_none() {
@@ -575,14 +734,22 @@ pub fn implementation<C: RPCClient, S: RPCClient>(
 
     match response.unwrap() {
         lsp_types::GotoDefinitionResponse::Scalar(l) => {
-            crate::vim::jump_to_location(ctx, l.into())?
+            crate::vim::jump_to_location(ctx, Location::from_lsp(l, ctx))?
         }
         lsp_types::GotoDefinitionResponse::Array(ll) => {
-            let locations = ll.into_iter().map(|l| l.into()).collect();
+            let lines = FileLines::new(ctx);
+            let locations = ll
+                .into_iter()
+                .map(|l| Location::from_lsp(l, &lines))
+                .collect();
             crate::vim::show_locations(ctx, locations)?
         }
         lsp_types::GotoDefinitionResponse::Link(ll) => {
-            let locations = ll.into_iter().map(|l| l.into()).collect();
+            let lines = FileLines::new(ctx);
+            let locations = ll
+                .into_iter()
+                .map(|l| Location::from_lsp_link(l, &lines))
+                .collect();
             crate::vim::show_locations(ctx, locations)?
         }
     }
@@ -591,6 +758,10 @@ pub fn implementation<C: RPCClient, S: RPCClient>(
 }
 
 pub fn hover<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    if !ctx.config.features.hover {
+        return Ok(());
+    }
+
     let params: CursorPosition = serde_json::from_value(params.into())?;
     let response = crate::lsp::text_document::hover(ctx, params)?;
     if response.is_none() {
@@ -602,6 +773,10 @@ pub fn hover<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) ->
 }
 
 pub fn references<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    if !ctx.config.features.references {
+        return Ok(());
+    }
+
     let params: CursorPosition = serde_json::from_value(params.into())?;
     let response = crate::lsp::text_document::references(ctx, params)?;
     if response.is_none() {
@@ -611,10 +786,15 @@ pub fn references<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Param
     let response = response.unwrap();
     match response.len() {
         1 => {
-            crate::vim::jump_to_location(ctx, response.first().cloned().unwrap().into())?;
+            let location = Location::from_lsp(response.first().cloned().unwrap(), ctx);
+            crate::vim::jump_to_location(ctx, location)?;
         }
         _ => {
-            let locations = response.into_iter().map(|l| l.into()).collect();
+            let lines = FileLines::new(ctx);
+            let locations = response
+                .into_iter()
+                .map(|l| Location::from_lsp(l, &lines))
+                .collect();
             crate::vim::show_locations(ctx, locations)?;
         }
     }
@@ -704,6 +884,10 @@ pub fn code_lens_for_position<C: RPCClient, S: RPCClient>(
 }
 
 pub fn code_lens<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    if !ctx.features()?.code_lenses {
+        return Ok(());
+    }
+
     let params: TextDocumentIdentifier = serde_json::from_value(params.into())?;
     let response: Vec<lsp_types::CodeLens> = crate::lsp::text_document::code_lens(ctx, params)?;
     if response.is_empty() {
@@ -754,12 +938,18 @@ pub fn completion<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Param
         return Ok(());
     }
 
-    let list = match response.unwrap() {
-        lsp_types::CompletionResponse::Array(vec) => vec.into_iter().map(|i| i.into()).collect(),
-        lsp_types::CompletionResponse::List(list) => {
-            list.items.into_iter().map(|i| i.into()).collect()
-        }
+    let items = match response.unwrap() {
+        lsp_types::CompletionResponse::Array(vec) => vec,
+        lsp_types::CompletionResponse::List(list) => list.items,
     };
+    let list = items
+        .into_iter()
+        .enumerate()
+        .map(|(index, i)| CompletionItem {
+            user_data: Some(index.to_string()),
+            ..i.into()
+        })
+        .collect();
 
     let list = CompletionList { words: list };
     ctx.vim
@@ -776,23 +966,8 @@ pub fn resolve_completion<C: RPCClient, S: RPCClient>(
         return Ok(());
     }
 
-    let state = ctx.state.read();
-    let caps = state.server_capabilities.get(&params.language_id).cloned();
-    drop(state);
-
-    if caps.is_none() {
-        log::debug!("skipping completionItem/resolve, no server capabilities found");
-        return Ok(());
-    }
-
-    let opts: Option<lsp_types::CompletionOptions> = caps.unwrap().completion_provider;
-    if opts.is_none() {
-        log::debug!("skipping completionItem/resolve, server is not completion provider");
-        return Ok(());
-    }
-
-    if !opts.unwrap().resolve_provider.unwrap_or_default() {
-        log::debug!("skipping completionItem/resolve, server is not resolve provider");
+    if !ctx.supports(lsp_types::request::ResolveCompletionItem::METHOD) {
+        log::debug!("skipping completionItem/resolve, no server is resolve provider");
         return Ok(());
     }
 
@@ -805,6 +980,10 @@ pub fn resolve_completion<C: RPCClient, S: RPCClient>(
 }
 
 pub fn definition<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Params) -> Result<()> {
+    if !ctx.config.features.definition {
+        return Ok(());
+    }
+
     let params: CursorPosition = serde_json::from_value(params.into())?;
     let response = crate::lsp::text_document::definition(ctx, params)?;
     if response.is_none() {
@@ -813,17 +992,279 @@ pub fn definition<C: RPCClient, S: RPCClient>(ctx: &Context<C, S>, params: Param
 
     match response.unwrap() {
         lsp_types::GotoDefinitionResponse::Scalar(l) => {
-            crate::vim::jump_to_location(ctx, l.into())?
+            crate::vim::jump_to_location(ctx, Location::from_lsp(l, ctx))?
         }
         lsp_types::GotoDefinitionResponse::Array(ll) => {
-            let locations = ll.into_iter().map(|l| l.into()).collect();
+            let lines = FileLines::new(ctx);
+            let locations = ll
+                .into_iter()
+                .map(|l| Location::from_lsp(l, &lines))
+                .collect();
             crate::vim::show_locations(ctx, locations)?
         }
         lsp_types::GotoDefinitionResponse::Link(ll) => {
-            let locations = ll.into_iter().map(|l| l.into()).collect();
+            let lines = FileLines::new(ctx);
+            let locations = ll
+                .into_iter()
+                .map(|l| Location::from_lsp_link(l, &lines))
+                .collect();
             crate::vim::show_locations(ctx, locations)?
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FeatureSet;
+    use crate::rpc::{FakeClient, OnCall, ServerID};
+    use crate::state::State;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    fn server(name: &str) -> ServerID {
+        ServerID::new("rust", name, "/")
+    }
+
+    fn params(value: Value) -> Params {
+        serde_json::from_value(value).unwrap()
+    }
+
+    // returns a context for a server that has the given features enabled in the config.
+    fn context(server: FakeClient, features: FeatureSet) -> Context<FakeClient, FakeClient> {
+        let mut ctx = Context::fake(
+            FakeClient::default(),
+            vec![(self::server("a"), server)],
+            State::default(),
+            "/a.rs",
+        );
+        ctx.state
+            .write()
+            .text_documents
+            .open("/a.rs", "rust", "fn main() {}\n");
+        let server_config = config::ServerConfig {
+            name: "a".into(),
+            features,
+            ..Default::default()
+        };
+        ctx.config
+            .servers
+            .insert("rust".into(), vec![server_config]);
+        ctx
+    }
+
+    fn position() -> Value {
+        json!({"filename": "/a.rs", "language_id": "rust", "line": 1, "column": 1})
+    }
+
+    #[test]
+    fn test_completion_feature() {
+        let method = lsp_types::request::Completion::METHOD;
+        let server = FakeClient::with_response(method, json!([{"label": "main"}]));
+        let ctx = context(server.clone(), FeatureSet::default());
+        completion(&ctx, params(position())).unwrap();
+        assert_eq!(server.called(method).len(), 1);
+        let replies = ctx.vim.replies.lock();
+        let words = replies[0].1.as_ref().unwrap()["words"].clone();
+        assert_eq!(
+            words,
+            json!([{"word": "main", "menu": "", "user_data": "0"}])
+        );
+        drop(replies);
+
+        let server = FakeClient::with_response(method, json!([{"label": "main"}]));
+        let features = FeatureSet {
+            completion: false,
+            ..Default::default()
+        };
+        let ctx = context(server.clone(), features);
+        completion(&ctx, params(position())).unwrap();
+        let item = CompletionItemWithContext {
+            completion_item: CompletionItem::default(),
+            position: Position { line: 1, column: 1 },
+        };
+        resolve_completion(&ctx, item).unwrap();
+        assert!(server.calls.lock().is_empty());
+        assert!(ctx.vim.replies.lock().is_empty());
+    }
+
+    #[test]
+    fn test_code_action_feature() {
+        let method = lsp_types::request::CodeActionRequest::METHOD;
+        let selection = json!({
+            "filename": "/a.rs",
+            "language_id": "rust",
+            "range": {"start": {"line": 1, "column": 1}, "end": {"line": 1, "column": 1}},
+        });
+        let server = FakeClient::with_response(method, json!([]));
+        let ctx = context(server.clone(), FeatureSet::default());
+        code_action(&ctx, params(selection.clone())).unwrap();
+        assert_eq!(server.called(method).len(), 1);
+
+        let features = FeatureSet {
+            code_actions: false,
+            ..Default::default()
+        };
+        let server = FakeClient::with_response(method, json!([]));
+        let ctx = context(server.clone(), features);
+        code_action(&ctx, params(selection)).unwrap();
+        assert!(server.calls.lock().is_empty());
+    }
+
+    #[test]
+    fn test_code_lens_feature() {
+        let method = lsp_types::request::CodeLensRequest::METHOD;
+        let document = json!({"filename": "/a.rs", "language_id": "rust"});
+        let server = FakeClient::with_response(method, json!([]));
+        let ctx = context(server.clone(), FeatureSet::default());
+        code_lens(&ctx, params(document.clone())).unwrap();
+        assert_eq!(server.called(method).len(), 1);
+
+        let features = FeatureSet {
+            code_lenses: false,
+            ..Default::default()
+        };
+        let server = FakeClient::with_response(method, json!([]));
+        let ctx = context(server.clone(), features);
+        code_lens(&ctx, params(document)).unwrap();
+        assert!(server.calls.lock().is_empty());
+    }
+
+    #[test]
+    fn test_resolve_code_action_on_origin() {
+        let method = lsp_types::request::ExecuteCommand::METHOD;
+        let a = FakeClient::with_response(method, Value::Null);
+        let b = FakeClient::with_response(method, Value::Null);
+        let ctx = Context::fake(
+            FakeClient::default(),
+            vec![(server("a"), a.clone()), (server("b"), b.clone())],
+            State::default(),
+            "/a.rs",
+        );
+        let command = |name: &str| {
+            CodeActionOrCommand::Command(lsp_types::Command::new(name.into(), name.into(), None))
+        };
+        ctx.state.write().code_actions = vec![
+            (server("a"), command("first")),
+            (server("b"), command("second")),
+        ];
+
+        let selection = json!({
+            "selection": 1,
+            "filename": "/a.rs",
+            "language_id": "rust",
+            "line": 1,
+            "column": 1,
+        });
+        resolve_code_action(&ctx, params(selection)).unwrap();
+        assert!(a.called(method).is_empty());
+        assert_eq!(b.called(method).len(), 1);
+        assert_eq!(b.called(method)[0]["command"], "second");
+        assert!(ctx.state.read().code_actions.is_empty());
+    }
+
+    #[test]
+    fn test_file_lines_untracked() {
+        let file = std::env::temp_dir().join(format!("vlc-lines-{}.rs", std::process::id()));
+        let filename = file.to_str().unwrap().to_owned();
+        std::fs::write(&file, "aé中\n🦀b\n").unwrap();
+        let ctx = Context::fake(
+            FakeClient::default(),
+            vec![(server("a"), FakeClient::default())],
+            State::default(),
+            "/a.rs",
+        );
+
+        let lines = FileLines::new(&ctx);
+        let from_lsp = |line, character| {
+            let position =
+                Position::from_lsp(lsp_types::Position::new(line, character), &filename, &lines);
+            (position.line, position.column)
+        };
+        assert_eq!(from_lsp(0, 3), (1, 7));
+        assert_eq!(from_lsp(1, 2), (2, 5));
+        let position = Position { line: 2, column: 6 };
+        assert_eq!(
+            position.to_lsp(&filename, &lines),
+            lsp_types::Position::new(1, 3)
+        );
+
+        // the file is only read once for every position converted with the same lines.
+        std::fs::write(&file, "abc\n").unwrap();
+        assert_eq!(from_lsp(0, 3), (1, 7));
+        assert_eq!(from_lsp(1, 2), (2, 5));
+        let position = Position::from_lsp(
+            lsp_types::Position::new(0, 3),
+            &filename,
+            &FileLines::new(&ctx),
+        );
+        assert_eq!(position.column, 4);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_file_lines_tracked() {
+        let mut state = State::default();
+        state.text_documents.open("/a.rs", "rust", "🦀b\n");
+        let ctx: Context<FakeClient, FakeClient> =
+            Context::fake(FakeClient::default(), vec![], state, "/a.rs");
+        let lines = FileLines::new(&ctx);
+        assert_eq!(lines.line("/a.rs", 0), "🦀b");
+        assert_eq!(lines.line("/a.rs", 5), "");
+        assert_eq!(lines.line("/does/not/exist.rs", 0), "");
+    }
+
+    #[test]
+    fn test_will_save_replies_with_edits_for_the_current_version() {
+        let method = lsp_types::request::WillSaveWaitUntil::METHOD;
+        let edit = json!([{
+            "range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 7}},
+            "newText": "start",
+        }]);
+        let content = json!({"filename": "/a.rs", "language_id": "rust", "text": "fn main() {}\n"});
+        let caps: lsp_types::ServerCapabilities =
+            serde_json::from_value(json!({"textDocumentSync": {"willSaveWaitUntil": true}}))
+                .unwrap();
+
+        let ctx = context(
+            FakeClient::with_response(method, edit.clone()),
+            FeatureSet::default(),
+        );
+        ctx.state
+            .write()
+            .server_capabilities
+            .insert(server("a"), caps.clone());
+        will_save(&ctx, params(content.clone())).unwrap();
+        let replies = ctx.vim.replies.lock();
+        assert_eq!(
+            replies[0].1.as_ref().unwrap(),
+            &json!([{
+                "start": {"line": 1, "column": 4},
+                "end": {"line": 1, "column": 8},
+                "lines": ["start"],
+            }])
+        );
+        drop(replies);
+
+        // the document changes while the server works out the edits.
+        let mut ctx = context(
+            FakeClient::with_response(method, edit),
+            FeatureSet::default(),
+        );
+        ctx.state
+            .write()
+            .server_capabilities
+            .insert(server("a"), caps);
+        let state = ctx.state.clone();
+        ctx.servers[0].1.on_call = Some(OnCall(Arc::new(move |_| {
+            state
+                .write()
+                .text_documents
+                .change("/a.rs", "rust", "fn start() {}\n");
+        })));
+        will_save(&ctx, params(content)).unwrap();
+        assert_eq!(ctx.vim.replies.lock()[0].1.as_ref().unwrap(), &json!([]));
+    }
+}
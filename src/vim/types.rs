@@ -146,7 +146,6 @@ pub struct CompletionList {
 pub struct CompletionItemWithContext {
     pub completion_item: CompletionItem,
     pub position: Position,
-    pub language_id: String,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -179,6 +178,9 @@ pub struct CompletionItem {
     // when non-zero this match will be added even when it is an empty string
     #[serde(skip_serializing_if = "Option::is_none")]
     pub empty: Option<u8>,
+    // index of the completion item this one was made from, so that it can be resolved later.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_data: Option<String>,
 }
 
 impl Into<lsp_types::CompletionItem> for CompletionItem {
@@ -333,30 +335,36 @@ pub struct Location {
     pub position: Position,
 }
 
-impl From<lsp_types::LocationLink> for Location {
-    fn from(f: lsp_types::LocationLink) -> Self {
+impl Location {
+    /// converts a location from LSP.
+    pub fn from_lsp(location: lsp_types::Location, lines: &impl DocumentLines) -> Self {
         Location {
-            filename: f.target_uri.to_string(),
-            position: Position {
-                line: f.target_range.start.line + 1,
-                column: f.target_range.start.character + 1,
-            },
+            position: Position::from_lsp(location.range.start, &file_path(&location.uri), lines),
+            filename: location.uri.to_string(),
         }
     }
-}
 
-impl From<lsp_types::Location> for Location {
-    fn from(f: lsp_types::Location) -> Self {
+    /// converts the target of a location link from LSP.
+    pub fn from_lsp_link(link: lsp_types::LocationLink, lines: &impl DocumentLines) -> Self {
         Location {
-            filename: f.uri.to_string(),
-            position: Position {
-                line: f.range.start.line + 1,
-                column: f.range.start.character + 1,
-            },
+            position: Position::from_lsp(
+                link.target_range.start,
+                &file_path(&link.target_uri),
+                lines,
+            ),
+            filename: link.target_uri.to_string(),
         }
     }
 }
 
+// returns the path of the file the uri points to, which is what documents are tracked by.
+fn file_path(uri: &lsp_types::Url) -> String {
+    match uri.to_file_path() {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => uri.path().to_owned(),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum LogLevel {
     Error = 1,
@@ -386,18 +394,21 @@ pub struct CursorPosition {
     pub position: Position,
 }
 
-impl Into<lsp_types::ReferenceParams> for CursorPosition {
-    fn into(self) -> lsp_types::ReferenceParams {
-        lsp_types::ReferenceParams {
-            text_document_position: lsp_types::TextDocumentPositionParams {
-                text_document: lsp_types::TextDocumentIdentifier {
-                    uri: lsp_types::Url::from_file_path(self.filename).unwrap(),
-                },
-                position: lsp_types::Position {
-                    line: self.position.line,
-                    character: self.position.column,
-                },
+impl CursorPosition {
+    /// converts the position to the text document position LSP requests take.
+    pub fn to_lsp(&self, lines: &impl DocumentLines) -> lsp_types::TextDocumentPositionParams {
+        lsp_types::TextDocumentPositionParams {
+            text_document: lsp_types::TextDocumentIdentifier {
+                uri: lsp_types::Url::from_file_path(&self.filename).unwrap(),
             },
+            position: self.position.to_lsp(&self.filename, lines),
+        }
+    }
+
+    /// returns the params to find the references to the symbol at the position.
+    pub fn to_reference_params(&self, lines: &impl DocumentLines) -> lsp_types::ReferenceParams {
+        lsp_types::ReferenceParams {
+            text_document_position: self.to_lsp(lines),
             work_done_progress_params: lsp_types::WorkDoneProgressParams::default(),
             context: lsp_types::ReferenceContext {
                 include_declaration: false,
@@ -407,40 +418,18 @@ impl Into<lsp_types::ReferenceParams> for CursorPosition {
     }
 }
 
-impl Into<lsp_types::TextDocumentPositionParams> for CursorPosition {
-    fn into(self) -> lsp_types::TextDocumentPositionParams {
-        lsp_types::TextDocumentPositionParams {
-            text_document: lsp_types::TextDocumentIdentifier {
-                uri: lsp_types::Url::from_file_path(self.filename).unwrap(),
-            },
-            position: lsp_types::Position {
-                line: self.position.line,
-                character: self.position.column,
-            },
-        }
-    }
-}
-
 #[derive(Debug, Deserialize)]
 pub struct Range {
     pub start: Position,
     pub end: Position,
 }
 
-impl From<lsp_types::Position> for Position {
-    fn from(f: lsp_types::Position) -> Self {
-        Position {
-            line: f.line + 1,
-            column: f.character + 1,
-        }
-    }
-}
-
-impl Into<lsp_types::Range> for Range {
-    fn into(self) -> lsp_types::Range {
+impl Range {
+    /// converts the range in the file to LSP.
+    pub fn to_lsp(&self, filename: &str, lines: &impl DocumentLines) -> lsp_types::Range {
         lsp_types::Range {
-            start: self.start.into(),
-            end: self.end.into(),
+            start: self.start.to_lsp(filename, lines),
+            end: self.end.to_lsp(filename, lines),
         }
     }
 }
@@ -449,17 +438,71 @@ impl Into<lsp_types::Range> for Range {
 pub struct Position {
     /// line position in a buffer, one-based
     pub line: u32,
-    /// column position in a buffer, one-based
+    /// column position in a buffer, one-based and counted in bytes
     pub column: u32,
 }
 
-impl Into<lsp_types::Position> for Position {
-    fn into(self) -> lsp_types::Position {
+impl Position {
+    /// converts the position in the file to LSP.
+    pub fn to_lsp(&self, filename: &str, lines: &impl DocumentLines) -> lsp_types::Position {
+        let line = self.line.saturating_sub(1);
+        let text = lines.line(filename, line);
         lsp_types::Position {
-            line: self.line,
-            character: self.column,
+            line,
+            character: utf16_offset(&text, self.column.saturating_sub(1)),
         }
     }
+
+    /// converts a position in the file from LSP.
+    pub fn from_lsp(
+        position: lsp_types::Position,
+        filename: &str,
+        lines: &impl DocumentLines,
+    ) -> Self {
+        let text = lines.line(filename, position.line);
+        Position {
+            line: position.line + 1,
+            column: byte_offset(&text, position.character) + 1,
+        }
+    }
+}
+
+/// Gives the text of the lines positions are in. Vim counts columns in bytes while LSP counts them
+/// in UTF-16 code units, so converting between them takes the text of the line.
+pub trait DocumentLines {
+    /// returns the text of the zero-based line of the file, or an empty string if it can't be
+    /// read.
+    fn line(&self, filename: &str, line: u32) -> String;
+}
+
+// returns the number of UTF-16 code units in the given number of bytes at the start of the line.
+// Bytes past the end of the line count as a unit each, so that positions after it are kept as
+// they are.
+fn utf16_offset(text: &str, bytes: u32) -> u32 {
+    let bytes = bytes as usize;
+    if bytes > text.len() {
+        return (text.encode_utf16().count() + bytes - text.len()) as u32;
+    }
+
+    let mut end = bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].encode_utf16().count() as u32
+}
+
+// returns the number of bytes in the given number of UTF-16 code units at the start of the line,
+// the opposite of utf16_offset.
+fn byte_offset(text: &str, units: u32) -> u32 {
+    let mut counted = 0;
+    for (idx, c) in text.char_indices() {
+        if counted >= units {
+            return idx as u32;
+        }
+        counted += c.len_utf16() as u32;
+    }
+
+    text.len() as u32 + units.saturating_sub(counted)
 }
 
 #[derive(Debug, Deserialize)]
@@ -471,3 +514,99 @@ pub struct SelectionRange {
     /// start position
     pub range: Range,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a single document, made of the lines given.
+    struct Lines(Vec<&'static str>);
+
+    impl DocumentLines for Lines {
+        fn line(&self, _: &str, line: u32) -> String {
+            self.0
+                .get(line as usize)
+                .cloned()
+                .unwrap_or_default()
+                .into()
+        }
+    }
+
+    #[test]
+    fn test_utf16_offset() {
+        // a is 1 byte, é 2 bytes, 中 3 bytes and 🦀 4 bytes, 🦀 being the only one that takes two
+        // UTF-16 code units.
+        let text = "aé中🦀b";
+        let cases = [(0, 0), (1, 1), (3, 2), (6, 3), (10, 5), (11, 6)];
+        for (bytes, units) in cases.iter() {
+            assert_eq!(utf16_offset(text, *bytes), *units, "{} bytes", bytes);
+            assert_eq!(byte_offset(text, *units), *bytes, "{} units", units);
+        }
+    }
+
+    #[test]
+    fn test_offset_past_the_end() {
+        assert_eq!(utf16_offset("", 0), 0);
+        assert_eq!(byte_offset("", 0), 0);
+        assert_eq!(utf16_offset("ab", 5), 5);
+        assert_eq!(byte_offset("ab", 5), 5);
+        assert_eq!(utf16_offset("🦀", 6), 4);
+        assert_eq!(byte_offset("🦀", 4), 6);
+    }
+
+    #[test]
+    fn test_offset_inside_a_character() {
+        // a byte inside a character counts as the start of it.
+        assert_eq!(utf16_offset("aé", 2), 1);
+        assert_eq!(utf16_offset("中b", 1), 0);
+        assert_eq!(utf16_offset("中b", 2), 0);
+        assert_eq!(utf16_offset("a🦀", 3), 1);
+        // a unit between the two halves of a surrogate pair counts as the end of the character.
+        assert_eq!(byte_offset("🦀b", 1), 4);
+        assert_eq!(byte_offset("a🦀", 2), 5);
+    }
+
+    #[test]
+    fn test_position_round_trip() {
+        let lines = Lines(vec!["let x = 1;", "let é = \"中🦀\";", ""]);
+        let cases = [
+            // line and byte column in vim, character in LSP.
+            ((1, 1), 0),
+            ((1, 5), 4),
+            ((2, 5), 4),
+            ((2, 7), 5),
+            ((2, 11), 9),
+            ((2, 14), 10),
+            ((2, 18), 12),
+            ((2, 20), 14),
+            ((2, 22), 16),
+            ((3, 1), 0),
+            ((3, 4), 3),
+        ];
+
+        for ((line, column), character) in cases.iter() {
+            let position = Position {
+                line: *line,
+                column: *column,
+            };
+            let lsp = position.to_lsp("/a.rs", &lines);
+            assert_eq!(lsp, lsp_types::Position::new(line - 1, *character));
+
+            let back = Position::from_lsp(lsp, "/a.rs", &lines);
+            assert_eq!((back.line, back.column), (*line, *column));
+        }
+    }
+
+    #[test]
+    fn test_position_inside_a_character() {
+        let lines = Lines(vec!["中🦀"]);
+        let position = Position { line: 1, column: 3 };
+        assert_eq!(
+            position.to_lsp("/a.rs", &lines),
+            lsp_types::Position::new(0, 0)
+        );
+
+        let position = Position::from_lsp(lsp_types::Position::new(0, 2), "/a.rs", &lines);
+        assert_eq!((position.line, position.column), (1, 8));
+    }
+}
@@ -0,0 +1,208 @@
+use super::{Backend, Change};
+use ::inotify::{Event, EventMask, WatchDescriptor, WatchMask, Watches};
+use anyhow::Result;
+use crossbeam::channel::Sender;
+use lsp_types::FileChangeType;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Watches every directory under the roots with inotify.
+pub struct Inotify {
+    dirs: Arc<Mutex<Dirs>>,
+}
+
+impl Inotify {
+    pub fn start(changes: Sender<Change>) -> Result<Self> {
+        let mut inotify = ::inotify::Inotify::init()?;
+        let dirs = Arc::new(Mutex::new(Dirs {
+            watches: inotify.watches(),
+            paths: HashMap::new(),
+            roots: vec![],
+            ignored: vec![],
+        }));
+
+        let shared = Arc::downgrade(&dirs);
+        std::thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            loop {
+                let events = match inotify.read_events_blocking(&mut buffer) {
+                    Ok(events) => events,
+                    Err(err) => {
+                        log::error!("could not read file changes: {}", err);
+                        return;
+                    }
+                };
+
+                // the thread stops once the backend is dropped.
+                let dirs = match shared.upgrade() {
+                    Some(dirs) => dirs,
+                    None => return,
+                };
+
+                let mut dirs = dirs.lock();
+                for event in events {
+                    if !dirs.handle(event, &changes) {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { dirs })
+    }
+}
+
+impl Backend for Inotify {
+    fn set_roots(&mut self, roots: &[PathBuf], ignored: &[String]) -> Result<()> {
+        let mut dirs = self.dirs.lock();
+        // the roots are watched from scratch when other directories are to be left out of them.
+        if dirs.ignored != ignored {
+            for root in std::mem::take(&mut dirs.roots) {
+                dirs.forget(&root, &[]);
+            }
+            dirs.ignored = ignored.to_vec();
+        }
+
+        let removed: Vec<PathBuf> = dirs
+            .roots
+            .iter()
+            .filter(|root| !roots.contains(root))
+            .cloned()
+            .collect();
+        for root in removed {
+            dirs.forget(&root, roots);
+        }
+
+        let added: Vec<PathBuf> = roots
+            .iter()
+            .filter(|root| !dirs.roots.contains(root))
+            .cloned()
+            .collect();
+        dirs.roots = roots.to_vec();
+        for root in added {
+            dirs.add(&root, &mut |_| {})?;
+        }
+
+        Ok(())
+    }
+}
+
+// the directories being watched.
+struct Dirs {
+    watches: Watches,
+    paths: HashMap<WatchDescriptor, PathBuf>,
+    roots: Vec<PathBuf>,
+    // names of the directories that are not watched.
+    ignored: Vec<String>,
+}
+
+impl Dirs {
+    // watches the directory and every directory under it, calling f with everything in them.
+    fn add(&mut self, dir: &Path, f: &mut dyn FnMut(&Path)) -> Result<()> {
+        self.add_watch(dir)?;
+        let mut result = Ok(());
+        let ignored = self.ignored.clone();
+        super::walk(dir, &ignored, &mut |path, metadata| {
+            if metadata.is_dir() && result.is_ok() {
+                result = self.add_watch(path);
+            }
+
+            f(path);
+        });
+
+        result
+    }
+
+    fn add_watch(&mut self, dir: &Path) -> Result<()> {
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ONLYDIR;
+        let wd = self.watches.add(dir, mask)?;
+        self.paths.insert(wd, dir.to_owned());
+        Ok(())
+    }
+
+    // stops watching the directory and every directory under it, except for the ones under any
+    // of the roots to keep.
+    fn forget(&mut self, dir: &Path, keep: &[PathBuf]) {
+        let forgotten: Vec<WatchDescriptor> = self
+            .paths
+            .iter()
+            .filter(|(_, path)| path.starts_with(dir))
+            .filter(|(_, path)| !keep.iter().any(|root| path.starts_with(root)))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in forgotten {
+            self.paths.remove(&wd);
+            // the watch is already gone if the directory was removed.
+            let _ = self.watches.remove(wd);
+        }
+    }
+
+    // turns the event into the changes it stands for, returning false once nobody is listening
+    // for them anymore.
+    fn handle(&mut self, event: Event<&OsStr>, changes: &Sender<Change>) -> bool {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            log::warn!("too many file changes at once, some of them were missed");
+            return true;
+        }
+
+        if event.mask.contains(EventMask::IGNORED) {
+            self.paths.remove(&event.wd);
+            return true;
+        }
+
+        let path = match (self.paths.get(&event.wd), event.name) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => return true,
+        };
+
+        let is_dir = event.mask.contains(EventMask::ISDIR);
+        if is_dir && super::is_ignored(&path, &self.ignored) {
+            return true;
+        }
+
+        let mut found = vec![];
+        let typ = if event
+            .mask
+            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+        {
+            if is_dir {
+                // files can be created in a new directory before it is watched, so the ones
+                // already in it are reported as created as well.
+                if let Err(err) = self.add(&path, &mut |path| found.push(path.to_owned())) {
+                    log::error!("could not watch {:?}: {}", path, err);
+                }
+            }
+
+            FileChangeType::Created
+        } else if event
+            .mask
+            .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+        {
+            if is_dir {
+                self.forget(&path, &[]);
+            }
+
+            FileChangeType::Deleted
+        } else if event.mask.contains(EventMask::MODIFY) && !is_dir {
+            FileChangeType::Changed
+        } else {
+            return true;
+        };
+
+        std::iter::once((path, typ))
+            .chain(
+                found
+                    .into_iter()
+                    .map(|path| (path, FileChangeType::Created)),
+            )
+            .all(|change| changes.send(change).is_ok())
+    }
+}
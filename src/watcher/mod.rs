@@ -0,0 +1,492 @@
+// watches the workspace roots of the servers that registered for workspace/didChangeWatchedFiles
+// and sends them batches of the changes to the files they asked for. Nothing is watched, and no
+// thread is started, until a server registers file watchers. Roots are watched with inotify where
+// it is available, and polled otherwise.
+
+#[cfg(target_os = "linux")]
+mod inotify;
+mod poll;
+
+use crate::glob;
+use crate::rpc::ServerID;
+use anyhow::Result;
+use crossbeam::channel::{Receiver, Sender};
+use lsp_types::{FileChangeType, FileEvent, FileSystemWatcher, Url, WatchKind};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// how long to wait for more changes before sending the ones collected so far.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+// how long changes can be held back at most while more keep coming in, e.g. during a checkout.
+const MAX_DELAY: Duration = Duration::from_secs(1);
+// directories that are never watched, whatever the config says. Changes to version control
+// internals are of no interest to language servers and would flood them with events.
+const IGNORED_DIRS: &[&str] = &[".git", ".hg", ".svn"];
+
+// a change to a file under one of the watched roots.
+type Change = (PathBuf, FileChangeType);
+
+// a way of finding out about changes to the files under a set of roots, which sends them on the
+// channel it was started with.
+trait Backend {
+    // watches the given roots, leaving out the directories with the ignored names, and stops
+    // watching the ones that are no longer in the list.
+    fn set_roots(&mut self, roots: &[PathBuf], ignored: &[String]) -> Result<()>;
+}
+
+#[derive(Debug)]
+enum Command {
+    Watch(ServerID, Vec<PathBuf>, Vec<String>, Vec<FileSystemWatcher>),
+    Unwatch(ServerID),
+}
+
+/// Handle to the thread watching files for the language servers.
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    commands: Arc<Mutex<Option<Sender<Command>>>>,
+    events: Sender<(ServerID, Vec<FileEvent>)>,
+}
+
+impl Watcher {
+    /// returns a watcher along with the channel the changes for each server are sent to.
+    pub fn new() -> (Self, Receiver<(ServerID, Vec<FileEvent>)>) {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let watcher = Self {
+            commands: Arc::new(Mutex::new(None)),
+            events: tx,
+        };
+
+        (watcher, rx)
+    }
+
+    /// watches the roots for changes to the files matched by any of the watchers, replacing what
+    /// was being watched for the server before. Directories with the ignored names are left out,
+    /// the latest ones given being used for every server.
+    pub fn watch(
+        &self,
+        id: &ServerID,
+        roots: &[String],
+        ignored: &[String],
+        watchers: Vec<FileSystemWatcher>,
+    ) {
+        if watchers.is_empty() {
+            return self.unwatch(id);
+        }
+
+        let roots = roots.iter().map(PathBuf::from).collect();
+        let command = Command::Watch(id.clone(), roots, ignored.to_vec(), watchers);
+        let mut commands = self.commands.lock();
+        let commands = commands.get_or_insert_with(|| Worker::spawn(self.events.clone()));
+        if let Err(err) = commands.send(command) {
+            log::error!("could not watch files for {}: {}", id, err);
+        }
+    }
+
+    /// stops watching files for the server.
+    pub fn unwatch(&self, id: &ServerID) {
+        if let Some(commands) = self.commands.lock().as_ref() {
+            if let Err(err) = commands.send(Command::Unwatch(id.clone())) {
+                log::error!("could not stop watching files for {}: {}", id, err);
+            }
+        }
+    }
+}
+
+// the files a server is interested in.
+struct Watch {
+    roots: Vec<PathBuf>,
+    watchers: Vec<FileSystemWatcher>,
+}
+
+impl Watch {
+    fn matches(&self, path: &Path, typ: FileChangeType) -> bool {
+        let kind = match typ {
+            FileChangeType::Created => WatchKind::Create,
+            FileChangeType::Changed => WatchKind::Change,
+            FileChangeType::Deleted => WatchKind::Delete,
+        };
+
+        // patterns are either absolute or relative to the workspace root.
+        let absolute = path.to_string_lossy();
+        let relative: Vec<_> = self
+            .roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .map(|path| path.to_string_lossy())
+            .collect();
+        if relative.is_empty() {
+            return false;
+        }
+
+        self.watchers
+            .iter()
+            .filter(|watcher| watcher.kind.unwrap_or_else(WatchKind::all).contains(kind))
+            .any(|watcher| {
+                glob::matches(&watcher.glob_pattern, &absolute)
+                    || relative
+                        .iter()
+                        .any(|path| glob::matches(&watcher.glob_pattern, path))
+            })
+    }
+}
+
+struct Worker {
+    watches: HashMap<ServerID, Watch>,
+    // names of the directories that are not watched.
+    ignored: Vec<String>,
+    backend: Box<dyn Backend>,
+    changes: Sender<Change>,
+    // changes seen since the last batch was sent.
+    pending: Vec<Change>,
+    events: Sender<(ServerID, Vec<FileEvent>)>,
+}
+
+impl Worker {
+    fn spawn(events: Sender<(ServerID, Vec<FileEvent>)>) -> Sender<Command> {
+        let (tx, commands) = crossbeam::channel::unbounded();
+        std::thread::spawn(move || {
+            let (changes_tx, changes) = crossbeam::channel::unbounded();
+            let worker = Worker {
+                watches: HashMap::new(),
+                ignored: vec![],
+                backend: start_backend(changes_tx.clone()),
+                changes: changes_tx,
+                pending: vec![],
+                events,
+            };
+
+            worker.run(commands, changes);
+        });
+
+        tx
+    }
+
+    fn run(mut self, commands: Receiver<Command>, changes: Receiver<Change>) {
+        // when the first and the last change of the batch being collected were seen.
+        let mut batch: Option<(Instant, Instant)> = None;
+        loop {
+            let timer = match batch {
+                Some((first, last)) => {
+                    crossbeam::channel::at((last + DEBOUNCE).min(first + MAX_DELAY))
+                }
+                None => crossbeam::channel::never(),
+            };
+
+            crossbeam::select! {
+                recv(commands) -> command => match command {
+                    Ok(command) => self.handle(command),
+                    // the language client is gone.
+                    Err(_) => return,
+                },
+                recv(changes) -> change => {
+                    if let Ok(change) = change {
+                        let now = Instant::now();
+                        batch = Some((batch.map_or(now, |(first, _)| first), now));
+                        self.pending.push(change);
+                    }
+                },
+                recv(timer) -> _ => {
+                    batch = None;
+                    self.flush();
+                },
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Watch(id, roots, ignored, watchers) => {
+                log::debug!("watching {} patterns for {}", watchers.len(), id);
+                self.watches.insert(id, Watch { roots, watchers });
+                self.ignored = ignored;
+            }
+            Command::Unwatch(id) => {
+                self.watches.remove(&id);
+            }
+        }
+
+        let mut roots: Vec<PathBuf> = self
+            .watches
+            .values()
+            .flat_map(|watch| watch.roots.iter().cloned())
+            .collect();
+        roots.sort();
+        roots.dedup();
+        if let Err(err) = self.backend.set_roots(&roots, &self.ignored) {
+            log::warn!("could not watch files, polling them instead: {}", err);
+            self.backend = Box::new(poll::Poll::start(self.changes.clone()));
+            if let Err(err) = self.backend.set_roots(&roots, &self.ignored) {
+                log::error!("could not watch files: {}", err);
+            }
+        }
+    }
+
+    // sends every server the pending changes to the files it watches.
+    fn flush(&mut self) {
+        let changes = coalesce(std::mem::take(&mut self.pending));
+        for (id, watch) in &self.watches {
+            let events: Vec<FileEvent> = changes
+                .iter()
+                .filter(|(path, typ)| watch.matches(path, *typ))
+                .filter_map(|(path, typ)| {
+                    Some(FileEvent {
+                        uri: Url::from_file_path(path).ok()?,
+                        typ: *typ,
+                    })
+                })
+                .collect();
+            if events.is_empty() {
+                continue;
+            }
+
+            if self.events.send((id.clone(), events)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn start_backend(changes: Sender<Change>) -> Box<dyn Backend> {
+    match inotify::Inotify::start(changes.clone()) {
+        Ok(backend) => Box::new(backend),
+        Err(err) => {
+            log::warn!("inotify is not available, polling files instead: {}", err);
+            Box::new(poll::Poll::start(changes))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_backend(changes: Sender<Change>) -> Box<dyn Backend> {
+    Box::new(poll::Poll::start(changes))
+}
+
+// merges the changes to each file into the one change that sums them up, keeping the order in
+// which files first changed.
+fn coalesce(changes: Vec<Change>) -> Vec<Change> {
+    let mut merged: Vec<Option<Change>> = vec![];
+    let mut index: HashMap<PathBuf, usize> = HashMap::new();
+    for (path, typ) in changes {
+        let i = *index.entry(path.clone()).or_insert_with(|| {
+            merged.push(None);
+            merged.len() - 1
+        });
+
+        let previous = merged[i].as_ref().map(|(_, typ)| *typ);
+        merged[i] = match (previous, typ) {
+            // a file that was created and removed in the same batch never existed as far as the
+            // servers are concerned.
+            (Some(FileChangeType::Created), FileChangeType::Deleted) => None,
+            (Some(FileChangeType::Created), _) => Some((path, FileChangeType::Created)),
+            (Some(FileChangeType::Deleted), FileChangeType::Created) => {
+                Some((path, FileChangeType::Changed))
+            }
+            _ => Some((path, typ)),
+        };
+    }
+
+    merged.into_iter().flatten().collect()
+}
+
+// calls f with every file and directory under the given one that is not ignored, without
+// following symbolic links. Ignored directories are not walked at all.
+fn walk(dir: &Path, ignored: &[String], f: &mut dyn FnMut(&Path, &std::fs::Metadata)) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::debug!("could not read {:?}: {}", dir, err);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_dir() {
+            if is_ignored(&path, ignored) {
+                continue;
+            }
+
+            f(&path, &metadata);
+            walk(&path, ignored, f);
+        } else {
+            f(&path, &metadata);
+        }
+    }
+}
+
+// returns whether the directory is one of the version control ones or has one of the ignored
+// names.
+fn is_ignored(path: &Path, ignored: &[String]) -> bool {
+    match path.file_name() {
+        Some(name) => {
+            IGNORED_DIRS.iter().any(|dir| name == *dir)
+                || ignored.iter().any(|dir| name == dir.as_str())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(path: &str, typ: FileChangeType) -> Change {
+        (PathBuf::from(path), typ)
+    }
+
+    fn watch(pattern: &str, kind: Option<WatchKind>) -> Watch {
+        Watch {
+            roots: vec![PathBuf::from("/root/project")],
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: pattern.into(),
+                kind,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_coalesce_create_delete() {
+        let changes = vec![
+            change("/a", FileChangeType::Created),
+            change("/b", FileChangeType::Changed),
+            change("/a", FileChangeType::Changed),
+            change("/a", FileChangeType::Deleted),
+        ];
+        assert_eq!(
+            coalesce(changes),
+            vec![change("/b", FileChangeType::Changed)]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_delete_create() {
+        let changes = vec![
+            change("/a", FileChangeType::Deleted),
+            change("/a", FileChangeType::Created),
+        ];
+        assert_eq!(
+            coalesce(changes),
+            vec![change("/a", FileChangeType::Changed)]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_keeps_order() {
+        let changes = vec![
+            change("/b", FileChangeType::Created),
+            change("/a", FileChangeType::Changed),
+            change("/b", FileChangeType::Changed),
+            change("/c", FileChangeType::Changed),
+            change("/c", FileChangeType::Deleted),
+        ];
+        assert_eq!(
+            coalesce(changes),
+            vec![
+                change("/b", FileChangeType::Created),
+                change("/a", FileChangeType::Changed),
+                change("/c", FileChangeType::Deleted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matches_kind() {
+        let path = Path::new("/root/project/src/main.rs");
+        let all = watch("**/*.rs", None);
+        assert!(all.matches(path, FileChangeType::Created));
+        assert!(all.matches(path, FileChangeType::Changed));
+        assert!(all.matches(path, FileChangeType::Deleted));
+
+        let created = watch("**/*.rs", Some(WatchKind::Create | WatchKind::Delete));
+        assert!(created.matches(path, FileChangeType::Created));
+        assert!(!created.matches(path, FileChangeType::Changed));
+        assert!(created.matches(path, FileChangeType::Deleted));
+    }
+
+    #[test]
+    fn test_matches_relative_and_absolute() {
+        let relative = watch("src/*.rs", None);
+        assert!(relative.matches(
+            Path::new("/root/project/src/main.rs"),
+            FileChangeType::Changed
+        ));
+        assert!(!relative.matches(
+            Path::new("/root/project/tests/main.rs"),
+            FileChangeType::Changed
+        ));
+
+        let absolute = watch("/root/project/Cargo.toml", None);
+        assert!(absolute.matches(
+            Path::new("/root/project/Cargo.toml"),
+            FileChangeType::Changed
+        ));
+        assert!(!absolute.matches(
+            Path::new("/root/project/sub/Cargo.toml"),
+            FileChangeType::Changed
+        ));
+    }
+
+    #[test]
+    fn test_matches_outside_roots() {
+        let any = watch("**", None);
+        assert!(!any.matches(Path::new("/root/other/main.rs"), FileChangeType::Changed));
+        let absolute = watch("/root/other/*.rs", None);
+        assert!(!absolute.matches(Path::new("/root/other/main.rs"), FileChangeType::Changed));
+    }
+
+    #[test]
+    fn test_walk_skips_ignored_dirs() {
+        let root = std::env::temp_dir().join(format!("vlc-walk-{}", std::process::id()));
+        for dir in &[
+            "src/nested",
+            ".git/objects",
+            "node_modules/dep",
+            "src/target",
+        ] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in &[
+            "src/main.rs",
+            "src/nested/lib.rs",
+            ".git/HEAD",
+            "node_modules/dep/a.js",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+
+        let mut walked = vec![];
+        let ignored = vec!["node_modules".to_string(), "target".to_string()];
+        walk(&root, &ignored, &mut |path, _| {
+            walked.push(path.strip_prefix(&root).unwrap().to_owned())
+        });
+        walked.sort();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let expected: Vec<PathBuf> = ["src", "src/main.rs", "src/nested", "src/nested/lib.rs"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(walked, expected);
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        let ignored = vec!["node_modules".to_string()];
+        assert!(is_ignored(Path::new("/root/project/.git"), &[]));
+        assert!(is_ignored(
+            Path::new("/root/project/node_modules"),
+            &ignored
+        ));
+        assert!(!is_ignored(Path::new("/root/project/node_modules"), &[]));
+        assert!(!is_ignored(Path::new("/root/project/src"), &ignored));
+    }
+}
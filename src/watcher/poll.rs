@@ -0,0 +1,135 @@
+use super::{Backend, Change};
+use anyhow::Result;
+use crossbeam::channel::Sender;
+use lsp_types::FileChangeType;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// how often the roots are scanned for changes.
+const INTERVAL: Duration = Duration::from_secs(2);
+
+/// Finds changes by scanning the roots every few seconds and comparing the modification times of
+/// the files in them, for when they can't be watched with inotify.
+pub struct Poll {
+    // roots to scan, along with the names of the directories left out of them.
+    roots: Arc<Mutex<(Vec<PathBuf>, Vec<String>)>>,
+}
+
+impl Poll {
+    pub fn start(changes: Sender<Change>) -> Self {
+        let roots = Arc::new(Mutex::new((vec![], vec![])));
+        let shared = Arc::downgrade(&roots);
+        std::thread::spawn(move || {
+            let mut snapshots: HashMap<PathBuf, HashMap<PathBuf, SystemTime>> = HashMap::new();
+            // the thread stops once the backend is dropped.
+            while let Some(roots) = shared.upgrade() {
+                let (roots, ignored) = roots.lock().clone();
+                snapshots.retain(|root, _| roots.contains(root));
+                for root in roots {
+                    let current = scan(&root, &ignored);
+                    // the first scan of a root is what the next ones are compared against.
+                    let previous = match snapshots.insert(root.clone(), current) {
+                        Some(previous) => previous,
+                        None => continue,
+                    };
+
+                    for change in diff(&previous, &snapshots[&root]) {
+                        if changes.send(change).is_err() {
+                            return;
+                        }
+                    }
+                }
+
+                std::thread::sleep(INTERVAL);
+            }
+        });
+
+        Self { roots }
+    }
+}
+
+impl Backend for Poll {
+    fn set_roots(&mut self, roots: &[PathBuf], ignored: &[String]) -> Result<()> {
+        *self.roots.lock() = (roots.to_vec(), ignored.to_vec());
+        Ok(())
+    }
+}
+
+// returns the modification time of every file under the root that is not in an ignored directory.
+fn scan(root: &Path, ignored: &[String]) -> HashMap<PathBuf, SystemTime> {
+    let mut files = HashMap::new();
+    super::walk(root, ignored, &mut |path, metadata| {
+        if metadata.is_dir() {
+            return;
+        }
+
+        if let Ok(modified) = metadata.modified() {
+            files.insert(path.to_owned(), modified);
+        }
+    });
+
+    files
+}
+
+fn diff(
+    previous: &HashMap<PathBuf, SystemTime>,
+    current: &HashMap<PathBuf, SystemTime>,
+) -> Vec<Change> {
+    let mut changes = vec![];
+    for (path, modified) in current {
+        match previous.get(path) {
+            None => changes.push((path.clone(), FileChangeType::Created)),
+            Some(before) if before != modified => {
+                changes.push((path.clone(), FileChangeType::Changed))
+            }
+            Some(_) => {}
+        }
+    }
+
+    for path in previous.keys().filter(|path| !current.contains_key(*path)) {
+        changes.push((path.clone(), FileChangeType::Deleted));
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(files: &[(&str, u64)]) -> HashMap<PathBuf, SystemTime> {
+        files
+            .iter()
+            .map(|(path, secs)| {
+                let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(*secs);
+                (PathBuf::from(path), modified)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let previous = snapshot(&[("/a", 1), ("/b", 1), ("/c", 1)]);
+        let current = snapshot(&[("/a", 1), ("/b", 2), ("/d", 1)]);
+        let mut changes = diff(&previous, &current);
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            changes,
+            vec![
+                (PathBuf::from("/b"), FileChangeType::Changed),
+                (PathBuf::from("/c"), FileChangeType::Deleted),
+                (PathBuf::from("/d"), FileChangeType::Created),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_unchanged() {
+        let previous = snapshot(&[("/a", 1), ("/b", 2)]);
+        assert!(diff(&previous, &previous.clone()).is_empty());
+        assert!(diff(&HashMap::new(), &HashMap::new()).is_empty());
+    }
+}